@@ -0,0 +1,305 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use crate::error::Error;
+use crate::rule::{self, Routing};
+use crate::{dial, Result};
+
+/// loopback, link-local (cloud metadata included), private and other special ranges.
+const DENIED_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// what a rule of the acl matches on.
+#[derive(Debug)]
+enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    IpCidr(IpNetwork),
+    DstPort(u16),
+}
+
+#[derive(Debug)]
+struct AclRule {
+    matcher: Matcher,
+    allow: bool,
+}
+
+/// the destinations that nexeld connects to, written as rules whose target is DIRECT to allow or REJECT to deny.
+/// the rules are checked in the order they're written and the first one that matches decides.
+/// the denied ranges are only reachable when an IP-CIDR rule allows them.
+#[derive(Debug)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    final_allow: bool,
+    denied: Vec<IpNetwork>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        let denied = DENIED_RANGES.iter().map(|range| IpNetwork::from_str(range).unwrap()).collect();
+        Acl { rules: Vec::new(), final_allow: true, denied }
+    }
+}
+
+impl Acl {
+    /// parses a rule like `IP-CIDR,10.1.0.0/16,DIRECT` and appends it.
+    pub fn insert(&mut self, item: &str) -> Result<()> {
+        let bad = |reason: &str| Error::Other(format!("bad acl rule {item}: {reason}"));
+        let mut split_iter = item.split(',');
+        let kind = split_iter.next().unwrap_or("_");
+        let content = split_iter.next().unwrap_or("_");
+        let routing = if kind == "MATCH" { content } else { split_iter.next().unwrap_or("_") };
+        let allow = match Routing::try_from(routing).map_err(|e| bad(&e.to_string()))? {
+            Routing::Direct => true,
+            Routing::Reject => false,
+            Routing::Proxy(_) => return Err(bad("acl rules can only be DIRECT or REJECT")),
+        };
+        let matcher = match kind {
+            "DOMAIN" => Matcher::Domain(content.to_string()),
+            "DOMAIN-SUFFIX" => Matcher::DomainSuffix(content.to_string()),
+            "DOMAIN-KEYWORD" => Matcher::DomainKeyword(content.to_string()),
+            "IP-CIDR" | "IP-CIDR6" => Matcher::IpCidr(IpNetwork::from_str(content).map_err(|e| bad(&e.to_string()))?),
+            "DST-PORT" => Matcher::DstPort(content.parse().map_err(|_| bad("invalid port"))?),
+            "MATCH" => {
+                self.final_allow = allow;
+                return Ok(());
+            }
+            _ => return Err(bad("unsupported kind")),
+        };
+        self.rules.push(AclRule { matcher, allow });
+        Ok(())
+    }
+
+    /// loads the `acl` section of the config file, only the denied ranges apply if it's absent.
+    pub fn load(path: &str) -> Result<Acl> {
+        #[derive(Deserialize)]
+        struct Cfg {
+            #[serde(default)]
+            acl: Vec<String>,
+        }
+        let file = std::fs::File::open(path)?;
+        let cfg: Cfg = serde_yml::from_reader(file)
+            .map_err(|e| Error::Other(format!("bad acl config: {e}")))?;
+        let mut acl = Acl::default();
+        for item in cfg.acl {
+            acl.insert(&item)?;
+        }
+        Ok(acl)
+    }
+
+    /// the first rule that matches, an IP rule is never matched without the address.
+    /// returns None and stops at the first IP rule when the address isn't known yet.
+    fn first_match(&self, domain: Option<&str>, port: u16, ip: Option<IpAddr>) -> Option<&AclRule> {
+        for rule in &self.rules {
+            let matched = match (&rule.matcher, domain) {
+                (Matcher::Domain(name), Some(domain)) => domain == name,
+                (Matcher::DomainSuffix(suffix), Some(domain)) => rule::domain_ends_with(domain, suffix),
+                (Matcher::DomainKeyword(keyword), Some(domain)) => domain.contains(keyword.as_str()),
+                (Matcher::DstPort(p), _) => *p == port,
+                (Matcher::IpCidr(cidr), _) => match ip {
+                    Some(ip) => cidr.contains(ip),
+                    None => return None,
+                },
+                _ => false,
+            };
+            if matched {
+                return Some(rule);
+            }
+        }
+        None
+    }
+
+    /// checks the destination before it's resolved, a domain or port that is rejected isn't looked up.
+    fn check_dst(&self, host: &str, port: u16) -> Result<()> {
+        let domain = host.parse::<IpAddr>().is_err().then_some(host);
+        match self.first_match(domain, port, None) {
+            Some(rule) if !rule.allow => Err(Error::Rejected),
+            _ => Ok(()),
+        }
+    }
+
+    /// an IP rule decides by itself, a domain or port rule that allows still keeps out the denied ranges.
+    fn check_ip(&self, domain: Option<&str>, port: u16, ip: IpAddr) -> bool {
+        let ip = embedded_ipv4(ip);
+        let denied = self.denied.iter().any(|range| range.contains(ip));
+        match self.first_match(domain, port, Some(ip)) {
+            Some(AclRule { matcher: Matcher::IpCidr(_), allow }) => *allow,
+            Some(rule) => rule.allow && !denied,
+            None => self.final_allow && !denied,
+        }
+    }
+
+    /// the addresses of host:port that are allowed, they're checked after resolving so that a domain can't be rebound to a denied one.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        self.check_dst(host, port)?;
+        let (domain, addrs) = match host.parse::<IpAddr>() {
+            Ok(ip) => (None, vec![SocketAddr::new(ip, port)]),
+            Err(_) => (Some(host), dial::resolve(host, port).await?),
+        };
+        let addrs: Vec<SocketAddr> = addrs.into_iter().filter(|addr| self.check_ip(domain, port, addr.ip())).collect();
+        if addrs.is_empty() {
+            return Err(Error::Rejected);
+        }
+        Ok(addrs)
+    }
+
+    /// connects to host:port if it's allowed.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        Ok(dial::connect_addrs(self.resolve(host, port).await?).await?)
+    }
+}
+
+/// the IPv4 address that an IPv4-mapped, NAT64 (64:ff9b::/96) or 6to4 (2002::/16) address reaches.
+fn embedded_ipv4(ip: IpAddr) -> IpAddr {
+    let IpAddr::V6(v6) = ip else {
+        return ip;
+    };
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return IpAddr::V4(v4);
+    }
+    let octets = v6.octets();
+    match v6.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])),
+        [0x2002, ..] => IpAddr::V4(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::acl::Acl;
+    use crate::connection::Connection;
+    use crate::error::Error;
+    use crate::protocol::ReplyCmd;
+    use crate::test_util::spawn_with;
+    use crate::upstream::http_connect;
+
+    fn acl_of(items: &[&str]) -> Acl {
+        let mut acl = Acl::default();
+        for item in items {
+            acl.insert(item).unwrap();
+        }
+        acl
+    }
+
+    fn allowed(acl: &Acl, ip: &str) -> bool {
+        acl.check_ip(None, 443, ip.parse().unwrap())
+    }
+
+    #[test]
+    fn deny_special_ranges() {
+        let acl = Acl::default();
+        for ip in ["127.0.0.1", "169.254.169.254", "10.1.2.3", "192.168.1.1", "::1", "fd00:ec2::254", "::ffff:127.0.0.1", "64:ff9b::7f00:1", "2002:a9fe:a9fe::1"] {
+            assert!(!allowed(&acl, ip), "{ip}");
+        }
+        assert!(allowed(&acl, "8.8.8.8"));
+        assert!(allowed(&acl, "2001:4860:4860::8888"));
+        assert!(allowed(&acl, "64:ff9b::808:808"));
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let acl = acl_of(&[
+            "IP-CIDR,10.1.0.0/16,DIRECT",
+            "IP-CIDR,8.8.8.0/24,REJECT",
+            "DOMAIN-SUFFIX,nexel.cc,DIRECT",
+            "DOMAIN-KEYWORD,ads,REJECT",
+            "DST-PORT,25,REJECT",
+            "MATCH,REJECT",
+        ]);
+        assert!(allowed(&acl, "10.1.2.3"));
+        assert!(!acl.check_ip(Some("www.nexel.cc"), 443, "10.2.0.1".parse().unwrap()));
+        assert!(!acl.check_ip(Some("www.nexel.cc"), 443, "8.8.8.8".parse().unwrap()));
+        assert!(acl.check_ip(Some("www.nexel.cc"), 443, "1.1.1.1".parse().unwrap()));
+        assert!(!allowed(&acl, "1.1.1.1"));
+
+        assert!(!acl.check_ip(Some("ads.example.com"), 443, "1.1.1.1".parse().unwrap()));
+        // the suffix rule comes before the port rule
+        assert!(acl.check_ip(Some("www.nexel.cc"), 25, "1.1.1.1".parse().unwrap()));
+        // nothing is rejected before resolving while an IP rule comes first
+        assert!(acl.check_dst("ads.example.com", 443).is_ok());
+        let acl = acl_of(&["DOMAIN-KEYWORD,ads,REJECT", "DST-PORT,25,REJECT"]);
+        assert!(matches!(acl.check_dst("ads.example.com", 443), Err(Error::Rejected)));
+        assert!(matches!(acl.check_dst("www.nexel.cc", 25), Err(Error::Rejected)));
+        assert!(Acl::default().insert("DOMAIN,nexel.cc,PROXY").is_err());
+        assert!(Acl::default().insert("GEOIP,CN,REJECT").is_err());
+    }
+
+    #[test]
+    fn first_match_wins() {
+        // overlapping ranges are decided by the order they're written in, whatever their prefixes
+        let acl = acl_of(&["IP-CIDR,10.0.0.0/8,REJECT", "IP-CIDR,10.1.0.0/16,DIRECT"]);
+        assert!(!allowed(&acl, "10.1.2.3"));
+        let acl = acl_of(&["IP-CIDR,10.1.0.0/16,DIRECT", "IP-CIDR,10.0.0.0/8,REJECT", "MATCH,DIRECT"]);
+        assert!(allowed(&acl, "10.1.2.3"));
+        assert!(!allowed(&acl, "10.2.0.1"));
+
+        let acl = acl_of(&["DOMAIN-KEYWORD,ads,REJECT", "DOMAIN-SUFFIX,example.com,DIRECT"]);
+        assert!(matches!(acl.check_dst("ads.example.com", 443), Err(Error::Rejected)));
+        let acl = acl_of(&["DOMAIN-SUFFIX,example.com,DIRECT", "DOMAIN-KEYWORD,ads,REJECT"]);
+        assert!(acl.check_dst("ads.example.com", 443).is_ok());
+        assert!(acl.check_ip(Some("ads.example.com"), 443, "1.1.1.1".parse().unwrap()));
+
+        // an IP rule before a domain rule is decided once the domain is resolved
+        let acl = acl_of(&["IP-CIDR,1.1.1.0/24,DIRECT", "DOMAIN-SUFFIX,example.com,REJECT"]);
+        assert!(acl.check_dst("www.example.com", 443).is_ok());
+        assert!(acl.check_ip(Some("www.example.com"), 443, "1.1.1.1".parse().unwrap()));
+        assert!(!acl.check_ip(Some("www.example.com"), 443, "8.8.8.8".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn check_resolved_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // an allowed domain that resolves to loopback
+        let acl = acl_of(&["DOMAIN,localhost,DIRECT"]);
+        assert!(matches!(acl.connect("localhost", port).await, Err(Error::Rejected)));
+        assert!(matches!(acl.connect("127.0.0.1", port).await, Err(Error::Rejected)));
+        let acl = acl_of(&["IP-CIDR,127.0.0.0/8,DIRECT", "IP-CIDR6,::1/128,DIRECT"]);
+        assert!(acl.connect("localhost", port).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reply_rules_not_allowed() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        let acl = std::sync::Arc::new(Acl::default());
+        let (port, _) = spawn_with(move |socket| {
+            let mut conn = Connection::new(socket, None);
+            conn.set_acl(acl.clone());
+            async move { conn.run_on_server().await }
+        }).await;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let ret = http_connect(&mut stream, "127.0.0.1", target_port, None).await;
+        assert_eq!(ret.unwrap_err().to_string(), "unexpected response status 403");
+
+        // nexel forwards the socks5 request without the greeting
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let [hi, lo] = target_port.to_be_bytes();
+        stream.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, hi, lo]).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], ReplyCmd::RulesNotAllowed as u8);
+    }
+}
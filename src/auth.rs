@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::{BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::error::Error;
+use crate::Result;
+
+/// version of the handshake that nexel sends to nexeld before the forwarded request.
+pub const VERSION: u8 = 1;
+const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// ver | user len | user | timestamp | nonce, the mac covers all of them.
+fn signed(user: &str, timestamp: u64, nonce: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(2 + user.len() + 8 + NONCE_LEN + MAC_LEN);
+    buf.put_u8(VERSION);
+    buf.put_u8(user.len() as u8);
+    buf.put_slice(user.as_bytes());
+    buf.put_u64(timestamp);
+    buf.put_slice(nonce);
+    buf
+}
+
+fn mac(key: &str, msg: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(msg);
+    mac
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// builds the handshake of the user signed with its pre-shared key.
+pub fn handshake(user: &str, key: &str) -> Result<Vec<u8>> {
+    if user.len() > 255 {
+        return Err(Error::Other("user name was too long".to_string()));
+    }
+    let nonce = uuid::Uuid::new_v4();
+    let mut buf = signed(user, now(), nonce.as_bytes());
+    let tag = mac(key, &buf).finalize().into_bytes();
+    buf.put_slice(&tag);
+    Ok(buf.to_vec())
+}
+
+/// sends the handshake of the user to nexeld.
+pub async fn send<W: AsyncWrite + Unpin + ?Sized>(stream: &mut W, user: &str, key: &str) -> Result<()> {
+    stream.write_all(&handshake(user, key)?).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    name: String,
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AuthCfg {
+    users: Vec<User>,
+    #[serde(default = "default_max_skew")]
+    max_skew: u64,
+}
+
+fn default_max_skew() -> u64 {
+    120
+}
+
+/// verifies the handshakes on nexeld, every user has its own pre-shared key.
+pub struct Authenticator {
+    keys: HashMap<String, String>,
+    max_skew: u64,
+    nonces: Mutex<HashMap<[u8; NONCE_LEN], u64>>, // nonce -> timestamp
+}
+
+impl Authenticator {
+    pub fn new(max_skew: u64) -> Authenticator {
+        Authenticator { keys: HashMap::new(), max_skew, nonces: Mutex::new(HashMap::new()) }
+    }
+
+    /// loads the `auth` section of the config file, none if it's absent.
+    pub fn load(path: &str) -> Result<Option<Authenticator>> {
+        #[derive(Deserialize)]
+        struct Cfg {
+            auth: Option<AuthCfg>,
+        }
+        let file = std::fs::File::open(path)?;
+        let cfg: Cfg = serde_yml::from_reader(file)
+            .map_err(|e| Error::Other(format!("bad auth config: {e}")))?;
+        Ok(cfg.auth.map(|auth| {
+            let mut authenticator = Authenticator::new(auth.max_skew);
+            for user in auth.users {
+                authenticator.insert_user(&user.name, &user.key);
+            }
+            authenticator
+        }))
+    }
+
+    pub fn insert_user(&mut self, name: &str, key: &str) {
+        self.keys.insert(name.to_string(), key.to_string());
+    }
+
+    /// reads a handshake from the stream and returns the user it's signed by.
+    pub async fn verify<R: AsyncRead + Unpin + ?Sized>(&self, stream: &mut R) -> Result<String> {
+        let ver = stream.read_u8().await?;
+        if ver != VERSION {
+            return Err(Error::Unauthenticated);
+        }
+        let user_len = stream.read_u8().await? as usize;
+        let mut user = vec![0u8; user_len];
+        stream.read_exact(&mut user).await?;
+        let user = String::from_utf8(user).map_err(|_| Error::Unauthenticated)?;
+        let timestamp = stream.read_u64().await?;
+        let mut nonce = [0u8; NONCE_LEN];
+        stream.read_exact(&mut nonce).await?;
+        let mut tag = [0u8; MAC_LEN];
+        stream.read_exact(&mut tag).await?;
+
+        let key = self.keys.get(&user).ok_or(Error::Unauthenticated)?;
+        mac(key, &signed(&user, timestamp, &nonce))
+            .verify_slice(&tag)
+            .map_err(|_| Error::Unauthenticated)?;
+        self.check_fresh(timestamp, nonce, now())?;
+        Ok(user)
+    }
+
+    /// a handshake is accepted once and only within the allowed clock skew.
+    fn check_fresh(&self, timestamp: u64, nonce: [u8; NONCE_LEN], now: u64) -> Result<()> {
+        let max_skew = self.max_skew;
+        let mut nonces = self.nonces.lock().unwrap();
+        // nonces out of the window are rejected by their timestamp anyway
+        nonces.retain(|_, seen| seen.abs_diff(now) <= max_skew);
+        if timestamp.abs_diff(now) > max_skew {
+            return Err(Error::Unauthenticated);
+        }
+        if nonces.insert(nonce, timestamp).is_some() {
+            return Err(Error::Unauthenticated);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::auth::{handshake, now, Authenticator};
+    use crate::connection::Connection;
+    use crate::error::Error;
+    use crate::outbound::{Outbounds, ProxyCfg};
+    use crate::test_util::spawn_with;
+    use crate::upstream;
+
+    fn authenticator() -> Authenticator {
+        let mut authenticator = Authenticator::new(120);
+        authenticator.insert_user("alice", "secret");
+        authenticator
+    }
+
+    #[tokio::test]
+    async fn verify_handshake() {
+        let authenticator = authenticator();
+        let frame = handshake("alice", "secret").unwrap();
+        assert_eq!(authenticator.verify(&mut &frame[..]).await.unwrap(), "alice");
+        // replayed
+        assert!(matches!(authenticator.verify(&mut &frame[..]).await, Err(Error::Unauthenticated)));
+
+        let frame = handshake("alice", "guess").unwrap();
+        assert!(matches!(authenticator.verify(&mut &frame[..]).await, Err(Error::Unauthenticated)));
+        let frame = handshake("bob", "secret").unwrap();
+        assert!(matches!(authenticator.verify(&mut &frame[..]).await, Err(Error::Unauthenticated)));
+        // not a handshake at all
+        let frame = b"CONNECT nexel.cc:443 HTTP/1.1\r\n\r\n";
+        assert!(authenticator.verify(&mut &frame[..]).await.is_err());
+    }
+
+    #[test]
+    fn reject_stale() {
+        let authenticator = authenticator();
+        let now = now();
+        assert!(authenticator.check_fresh(now - 121, [1; 16], now).is_err());
+        assert!(authenticator.check_fresh(now + 121, [2; 16], now).is_err());
+        assert!(authenticator.check_fresh(now - 60, [3; 16], now).is_ok());
+        assert!(authenticator.check_fresh(now, [3; 16], now).is_err());
+        // forgotten once it's out of the window, its timestamp rejects it then
+        assert!(authenticator.check_fresh(now - 60, [3; 16], now + 200).is_err());
+        assert!(authenticator.nonces.lock().unwrap().is_empty());
+    }
+
+    async fn spawn_server(authenticator: Arc<Authenticator>) -> u16 {
+        let (port, _) = spawn_with(move |socket| {
+            let mut conn = Connection::new(socket, None);
+            conn.set_authenticator(authenticator.clone());
+            async move { conn.run_on_server().await }
+        }).await;
+        port
+    }
+
+    #[tokio::test]
+    async fn reject_open_proxy_use() {
+        let server = spawn_server(Arc::new(authenticator())).await;
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+
+        // authenticated through the outbound
+        let mut proxy = ProxyCfg::new("127.0.0.1", server, "");
+        proxy.set_credentials("alice", "secret");
+        let mut remote = Outbounds::new().dial(&proxy).await.unwrap();
+        upstream::handshake(&proxy, &mut remote, "127.0.0.1", target_port).await.unwrap();
+
+        // a plain CONNECT gets nothing back
+        let mut stream = TcpStream::connect(("127.0.0.1", server)).await.unwrap();
+        stream.write_all(format!("CONNECT 127.0.0.1:{target_port} HTTP/1.1\r\n\r\n").as_bytes()).await.unwrap();
+        let mut buf = [0u8; 1];
+        let ret = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf)).await;
+        assert!(ret.is_err());
+    }
+}
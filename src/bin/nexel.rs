@@ -1,10 +1,20 @@
-use nexel::connection::{Connection, ProxyCfg};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use nexel::config::Timeouts;
+use nexel::connection::Connection;
+use nexel::outbound::{self, MuxCfg, Outbounds, ProxyCfg};
+use nexel::noise::NoiseCfg;
+use nexel::padding::PaddingCfg;
+use nexel::pool::{self, PoolCfg};
+use nexel::ws::WsCfg;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
 use argh::FromArgs;
 use log::LevelFilter;
 use tokio::io;
-use tokio::net::TcpListener;
-use nexel::rule;
+use tokio::net::{TcpListener, TcpStream};
+use nexel::{redir, rule, sniff};
+#[cfg(target_os = "linux")]
+use nexel::tproxy;
 
 /// nexel manual
 #[derive(FromArgs, Clone)]
@@ -15,16 +25,76 @@ struct Option {
     /// whether to encrypt communication with the proxy server
     #[argh(switch, short = 't')]
     tls: bool,
-    /// specify the cert file path
-    #[argh(option, short = 'c', default = "String::from(\"certificate.crt\")")]
-    cert: String,
-    /// specify server host addr, can be domain or ip
+    /// specify the CA file that the server is verified with, certificate.crt if nothing else is given
+    #[argh(option, short = 'c')]
+    cert: std::option::Option<String>,
+    /// verify the server with the CA bundle of the system as well
+    #[argh(switch)]
+    system_roots: bool,
+    /// pin the base64 SHA-256 of the public key of the server, can be repeated
+    #[argh(option)]
+    pin_sha256: Vec<String>,
+    /// specify the name presented in the tls handshake, defaults to the server host
+    #[argh(option)]
+    sni: std::option::Option<String>,
+    /// specify server host addr, can be domain or ip, it's registered as the PROXY outbound
     #[argh(option, short = 'h')]
-    server_host: String,
+    server_host: std::option::Option<String>,
     /// specify server port
     #[argh(option, short = 'o')]
-    server_port: u16,
-    /// specify rule.yaml file path
+    server_port: std::option::Option<u16>,
+    /// specify the client certificate presented to the server
+    #[argh(option)]
+    client_cert: std::option::Option<String>,
+    /// specify the private key of the client certificate
+    #[argh(option)]
+    client_key: std::option::Option<String>,
+    /// multiplex up to the number of streams over each session to the server, 0 disables it
+    #[argh(option, short = 'm', default = "0")]
+    mux: usize,
+    /// keep the number of idle connections to the server warm, 0 disables it
+    #[argh(option, default = "0")]
+    pool: usize,
+    /// tunnel to the server over a WebSocket on the path
+    #[argh(option, short = 'w')]
+    ws_path: std::option::Option<String>,
+    /// specify the Host header of the WebSocket upgrade, defaults to the server host
+    #[argh(option)]
+    ws_host: std::option::Option<String>,
+    /// carry the tunnels as HTTP/2 CONNECT streams of one tls connection to the server
+    #[argh(switch)]
+    h2: bool,
+    /// carry the tunnels and the UDP associations over QUIC to the server, it takes tls
+    #[argh(switch)]
+    quic: bool,
+    /// pad the records of the tunnels to the server, so that their sizes don't give the requests away
+    #[argh(switch)]
+    padding: bool,
+    /// reach the server over noise with its base64 public key instead of tls
+    #[argh(option)]
+    noise_key: std::option::Option<String>,
+    /// specify the base64 noise private key of the client, a random one is taken without it
+    #[argh(option)]
+    noise_private_key: std::option::Option<String>,
+    /// specify the user that authenticates to the server
+    #[argh(option, short = 'u')]
+    user: std::option::Option<String>,
+    /// specify the pre-shared key of the user
+    #[argh(option, short = 's')]
+    secret: std::option::Option<String>,
+    /// listen on the port for connections redirected by iptables or nftables REDIRECT, linux only
+    #[argh(option)]
+    redir_port: std::option::Option<u16>,
+    /// listen on the port for TCP connections and UDP datagrams handed over by TPROXY, linux only
+    #[argh(option)]
+    tproxy_port: std::option::Option<u16>,
+    /// route the requests that carry an ip only by the domain of their TLS SNI or HTTP Host
+    #[argh(switch)]
+    sniff: bool,
+    /// specify how many milliseconds the first bytes of the client are waited for when sniffing
+    #[argh(option, default = "sniff::WAIT.as_millis() as u64")]
+    sniff_wait: u64,
+    /// specify rule.yaml file path, its proxies and proxy-groups are loaded as well
     #[argh(option, short = 'r', default = "String::from(\"rule.yaml\")")]
     rule_path: String,
     /// specify GeoLite2-Country.mmdb file path
@@ -37,6 +107,9 @@ struct Option {
 async fn main() -> io::Result<()> {
     let op: Option = argh::from_env();
 
+    // initial logger
+    env_logger::Builder::new().filter(None, LevelFilter::Info).init();
+
     // rule file loading
     match rule::initial(&op.rule_path, &op.mmdb_path) {
         Ok(_) => {}
@@ -45,19 +118,110 @@ async fn main() -> io::Result<()> {
         }
     };
 
-    // initial logger
-    env_logger::Builder::new().filter(None, LevelFilter::Info).init();
+    // timeouts loading
+    let timeouts = Timeouts::load(&op.rule_path).unwrap_or_else(|e| {
+        log::error!("timeouts initial failed: {}", e);
+        Timeouts::default()
+    });
+
+    // outbounds loading
+    let mut outbounds = match Outbounds::load(&op.rule_path) {
+        Ok(outbounds) => outbounds,
+        Err(e) => {
+            log::error!("outbounds initial failed: {}", e);
+            Outbounds::new()
+        }
+    };
+    outbounds.set_timeouts(timeouts);
+    // the PROXY outbound of the command line needs both, it may come from rule.yaml instead
+    if op.server_host.is_some() != op.server_port.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "-h and -o have to be given together"));
+    }
+    if let (Some(host), Some(port)) = (&op.server_host, op.server_port) {
+        let cert = match &op.cert {
+            Some(cert) => cert,
+            None if op.system_roots || !op.pin_sha256.is_empty() => "",
+            None => "certificate.crt",
+        };
+        let mut proxy = ProxyCfg::new(host, port, if op.tls { cert } else { "" });
+        proxy.set_tls(op.tls);
+        proxy.set_system_roots(op.system_roots);
+        for pin in &op.pin_sha256 {
+            proxy.add_pin(pin);
+        }
+        if let Some(sni) = &op.sni {
+            proxy.set_sni(sni);
+        }
+        if let (Some(user), Some(secret)) = (&op.user, &op.secret) {
+            proxy.set_credentials(user, secret);
+        }
+        if op.mux > 0 {
+            proxy.set_mux(MuxCfg::new(MuxCfg::default().sessions(), op.mux));
+        }
+        if op.pool > 0 {
+            let default = PoolCfg::default();
+            proxy.set_pool(PoolCfg::new(op.pool, default.max_idle_age(), default.probe_interval()));
+        }
+        if let Some(path) = &op.ws_path {
+            let mut ws = WsCfg::new(path);
+            if let Some(host) = &op.ws_host {
+                ws.set_host(host);
+            }
+            proxy.set_ws(ws);
+        }
+        proxy.set_h2(op.h2);
+        proxy.set_quic(op.quic);
+        if let Some(key) = &op.noise_key {
+            let mut noise = NoiseCfg::new(key);
+            if let Some(private_key) = &op.noise_private_key {
+                noise.set_private_key(private_key);
+            }
+            proxy.set_noise(noise);
+        }
+        if op.padding {
+            proxy.set_padding(PaddingCfg::default());
+        }
+        if let (Some(cert), Some(key)) = (&op.client_cert, &op.client_key) {
+            proxy.set_client_identity(cert, key);
+        }
+        if let Err(e) = outbounds.insert_proxy(proxy) {
+            log::error!("outbounds initial failed: {}", e);
+        }
+    }
+    for name in rule::targets() {
+        if !outbounds.contains(&name) {
+            log::warn!("rules refer to unknown outbound {name}");
+        }
+    }
+    let outbounds = Arc::new(outbounds);
+    outbound::spawn_health_check(outbounds.clone());
+    pool::spawn_pool_fill(outbounds.clone());
 
     // listen port
     let port = op.port;
     let local_addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
     let listener = TcpListener::bind(local_addr).await?;
     log::info!("listening port: {port}");
+    let sniff = op.sniff.then(|| Duration::from_millis(op.sniff_wait));
+    if let Some(port) = op.redir_port {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port)).await?;
+        log::info!("listening redir port: {port}");
+        tokio::spawn(listen_transparent(listener, redir::original_dst, outbounds.clone(), timeouts, sniff));
+    }
+    if let Some(port) = op.tproxy_port {
+        listen_tproxy(port, outbounds.clone(), timeouts, sniff)?;
+    }
     loop {
-        let op = op.clone();
+        let outbounds = outbounds.clone();
         let (socket, _) = listener.accept().await?;
         tokio::spawn(async move {
-            let mut conn = Connection::new(socket, Some(ProxyCfg::new(&op.server_host, op.server_port, if op.tls {&op.cert}else{""})));
+            let local_addr = socket.local_addr();
+            let mut conn = Connection::new(socket, Some(outbounds));
+            conn.set_timeouts(timeouts);
+            conn.set_sniff(sniff);
+            if let Ok(addr) = local_addr {
+                conn.set_local_addr(addr);
+            }
             match conn.run().await {
                 Ok(_) => {}
                 Err(e) => {
@@ -67,3 +231,46 @@ async fn main() -> io::Result<()> {
         });
     }
 }
+
+#[cfg(target_os = "linux")]
+fn listen_tproxy(port: u16, outbounds: Arc<Outbounds>, timeouts: Timeouts, sniff: std::option::Option<Duration>) -> io::Result<()> {
+    let local_addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port).into();
+    let listener = tproxy::tcp_listener(local_addr)?;
+    let socket = tproxy::udp_socket(local_addr)?;
+    log::info!("listening tproxy port: {port}");
+    tokio::spawn(listen_transparent(listener, move |socket: &TcpStream| tproxy::original_dst(socket, port), outbounds.clone(), timeouts, sniff));
+    tokio::spawn(tproxy::serve_udp(socket, outbounds, timeouts.idle()));
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen_tproxy(_port: u16, _outbounds: Arc<Outbounds>, _timeouts: Timeouts, _sniff: std::option::Option<Duration>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "tproxy is only served on linux"))
+}
+
+/// serves the connections that the firewall redirected, their destinations are recovered from the sockets.
+async fn listen_transparent<F>(listener: TcpListener, original_dst: F, outbounds: Arc<Outbounds>, timeouts: Timeouts, sniff: std::option::Option<Duration>) -> io::Result<()>
+where
+    F: Fn(&TcpStream) -> io::Result<SocketAddr> + Copy + Send + 'static,
+{
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let outbounds = outbounds.clone();
+        tokio::spawn(async move {
+            let dst = match original_dst(&socket) {
+                Ok(dst) => dst,
+                Err(e) => {
+                    log::error!("original destination of {} was lost: {}", peer, e);
+                    return;
+                }
+            };
+            let mut conn = Connection::new(socket, Some(outbounds));
+            conn.set_timeouts(timeouts);
+            conn.set_sniff(sniff);
+            log::info!("[TRANSPARENT] conn_id = {}, peer = {}, dst = {}", conn.id(), peer, dst);
+            if let Err(e) = conn.run_transparent(dst).await {
+                log::error!("connection id {} handler run failed: {}", conn.id(), e);
+            }
+        });
+    }
+}
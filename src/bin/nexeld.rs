@@ -1,10 +1,23 @@
+use nexel::acl::Acl;
+use nexel::auth::Authenticator;
+use nexel::config::{self, Timeouts};
 use nexel::connection::Connection;
-use nexel::{tls};
+use nexel::error::Error;
+use nexel::fallback::{self, Rewind};
+use nexel::padding::{self, PadStream, Preface};
+use nexel::trojan::{self, Trojan};
+use nexel::udp::Addr;
+use nexel::tls::{Certs, Inbound, TlsCfg};
+use nexel::{http2, noise, quic, shadowsocks, tls, ws};
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
 use argh::FromArgs;
-use log::{error, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use tokio::io;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
 /// nexeld manual
 #[derive(FromArgs)]
@@ -21,11 +34,84 @@ struct Option {
     /// specify the private key file path
     #[argh(option, short ='k', default = "String::from(\"private.key\")")]
     private_key: String,
+    /// specify the config file path, its timeouts, auth and acl are loaded
+    #[argh(option, short = 'f')]
+    config: std::option::Option<String>,
+    /// specify the CA bundle that the client certificates are verified with
+    #[argh(option, short = 'a')]
+    client_ca: std::option::Option<String>,
+    /// let clients without a certificate in, the ones presented are still verified
+    #[argh(switch)]
+    client_auth_optional: bool,
+    /// tunnel over WebSocket upgrades to the path, other HTTP requests are answered with 404
+    #[argh(option, short = 'w')]
+    ws_path: std::option::Option<String>,
+    /// offer h2 to the clients, each HTTP/2 CONNECT stream carries a tunnel
+    #[argh(switch)]
+    h2: bool,
+    /// listen for QUIC on the same udp port with the tls cert, each stream carries a tunnel
+    #[argh(switch)]
+    quic: bool,
+    /// also accept shadowsocks 2022 on the port
+    #[argh(option)]
+    ss_port: std::option::Option<u16>,
+    /// specify the shadowsocks method
+    #[argh(option, default = "String::from(\"2022-blake3-aes-256-gcm\")")]
+    ss_cipher: String,
+    /// specify the base64 pre-shared key of shadowsocks
+    #[argh(option)]
+    ss_password: std::option::Option<String>,
+    /// specify the address of a local web server, the connections that aren't requests of a client are proxied to it
+    #[argh(option)]
+    fallback: std::option::Option<String>,
+    /// serve over noise with the base64 private key instead of tls
+    #[argh(option)]
+    noise_key: std::option::Option<String>,
+    /// specify the base64 public key of a client let in over noise, can be repeated, it's required without auth
+    #[argh(option)]
+    noise_client: Vec<String>,
+    #[argh(subcommand)]
+    command: std::option::Option<Command>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    GenCert(GenCert),
+    GenKey(GenKey),
+}
+
+/// generate a CA and a certificate of nexeld signed by it, then print the client config
+#[derive(FromArgs)]
+#[argh(subcommand, name = "gen-cert")]
+struct GenCert {
+    /// specify a domain or ip of the server, can be repeated
+    #[argh(option)]
+    san: Vec<String>,
+    /// specify the directory that the files are written into
+    #[argh(option, short = 'o', default = "String::from(\".\")")]
+    out: String,
+    /// specify the number of days that the certificates are valid
+    #[argh(option, default = "3650")]
+    days: u32,
+    /// overwrite the files that exist
+    #[argh(switch)]
+    force: bool,
+}
+
+/// generate a noise keypair, then print the config that it goes into
+#[derive(FromArgs)]
+#[argh(subcommand, name = "gen-key")]
+struct GenKey {}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let op: Option = argh::from_env();
+    match &op.command {
+        Some(Command::GenCert(gen)) => return gen_cert(gen, op.port),
+        Some(Command::GenKey(_)) => return gen_key(op.port),
+        None => {}
+    }
 
     let local_addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), op.port);
     let listener = TcpListener::bind(local_addr).await?;
@@ -33,42 +119,405 @@ async fn main() -> io::Result<()> {
     // initial logger
     env_logger::Builder::new().filter(None, LevelFilter::Info).init();
 
+    // timeouts loading
+    let timeouts = match &op.config {
+        Some(path) => Timeouts::load(path).unwrap_or_else(|e| {
+            error!("timeouts initial failed: {}", e);
+            Timeouts::default()
+        }),
+        None => Timeouts::default(),
+    };
+
+    // users loading, a config that fails to load isn't taken for one without auth
+    let authenticator = match &op.config {
+        Some(path) => Authenticator::load(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("auth initial failed: {e}")))?,
+        None => None,
+    };
+    if authenticator.is_none() {
+        warn!("no auth was configured, anyone can use this server as a proxy");
+    }
+
+    // acl loading, the private ranges are denied without it
+    let acl = match &op.config {
+        Some(path) => Acl::load(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("acl initial failed: {e}")))?,
+        None => Acl::default(),
+    };
+
+    // trojan users loading
+    let trojan = match &op.config {
+        Some(path) => Trojan::load(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("trojan initial failed: {e}")))?,
+        None => None,
+    };
+    if trojan.is_some() && !op.tls {
+        warn!("trojan clients expect tls, enable it with -t");
+    }
+
+    // more certificates and the routes of tls connections loading
+    let tls_cfg = match &op.config {
+        Some(path) => TlsCfg::load(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("tls initial failed: {e}")))?,
+        None => TlsCfg::default(),
+    };
+    let server = Server {
+        timeouts,
+        authenticator: authenticator.map(Arc::new),
+        acl: Arc::new(acl),
+        ws_path: op.ws_path.map(Arc::from),
+        trojan: trojan.map(Arc::new),
+        fallback: op.fallback.map(Arc::from),
+        tls_cfg: Arc::new(tls_cfg),
+    };
+
+    if let Some(port) = op.ss_port {
+        let key = op.ss_cipher.parse()
+            .and_then(|method| shadowsocks::Key::new(method, op.ss_password.as_deref().unwrap_or("")))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port)).await?;
+        tokio::spawn(listen_shadowsocks(listener, Arc::new(shadowsocks::Inbound::new(key)), server.clone()));
+    }
+
+    if op.tls && op.noise_key.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "noise takes the place of tls, --noise-key can't be given with -t"));
+    }
     if op.tls {
-        listen_tls(listener, &op.cert, &op.private_key).await
+        let client_auth = op.client_ca.as_ref().map(|ca| tls::ClientAuth::new(ca, !op.client_auth_optional));
+        let mut alpn: Vec<&[u8]> = if op.h2 { vec![http2::ALPN, b"http/1.1"] } else { vec![] };
+        for protocol in server.tls_cfg.alpn() {
+            if !alpn.contains(&protocol) {
+                alpn.push(protocol);
+            }
+        }
+        let certs = std::iter::once((op.cert.as_str(), op.private_key.as_str())).chain(server.tls_cfg.certs());
+        let certs = Arc::new(Certs::load(certs)?);
+        // renewed certificates are taken by the new handshakes, the tunnels are kept
+        certs.clone().watch(Duration::from_secs(10));
+        let tls_acceptor = tls::acceptor(certs.clone(), client_auth.as_ref(), &alpn)?;
+        if op.quic {
+            let endpoint = quic::server_endpoint(local_addr.into(), certs, client_auth.as_ref())
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            tokio::spawn(listen_quic(endpoint, server.clone()));
+        }
+        listen_tls(listener, tls_acceptor, server).await
     } else {
-        listen(listener).await
+        if op.quic {
+            warn!("QUIC takes the tls cert, it's disabled without -t");
+        }
+        if let Some(key) = &op.noise_key {
+            // any key passes the handshake without the clients, so auth is what's left to keep it from being an open proxy
+            if op.noise_client.is_empty() && server.authenticator.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "noise lets anyone in without --noise-client, give one or configure auth"));
+            }
+            let mut inbound = noise::Inbound::new(noise::Keypair::from_private(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?);
+            for client in &op.noise_client {
+                inbound.allow(noise::decode_key(client).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?);
+            }
+            return listen_noise(listener, Arc::new(inbound), server).await;
+        }
+        listen(listener, server).await
     }
 }
 
-async fn listen_tls(listener: TcpListener, cert: &String, private_key: &String) -> io::Result<()> {
-    let tls_acceptor = tls::acceptor(cert, private_key)?;
+/// the settings shared by every connection.
+#[derive(Clone)]
+struct Server {
+    timeouts: Timeouts,
+    authenticator: std::option::Option<Arc<Authenticator>>,
+    acl: Arc<Acl>,
+    ws_path: std::option::Option<Arc<str>>,
+    trojan: std::option::Option<Arc<Trojan>>,
+    fallback: std::option::Option<Arc<str>>,
+    tls_cfg: Arc<TlsCfg>,
+}
+
+impl Server {
+    async fn serve<RW: AsyncRead + AsyncWrite + Unpin>(&self, mut socket: RW, client_subject: std::option::Option<String>) {
+        let Some(trojan) = &self.trojan else {
+            return self.serve_nexel(socket, client_subject).await;
+        };
+        // a trojan request starts with hex, which none of the nexel ones does
+        match config::within(self.timeouts.handshake(), Error::HandshakeTimeout, trojan.accept(&mut socket)).await {
+            Ok(trojan::Accepted::Connect { user, dst }) => self.run_trojan(socket, client_subject, user, dst).await,
+            Ok(trojan::Accepted::Other(read)) if trojan::looks_like(&read) => self.fall_back(socket, &read).await,
+            Ok(trojan::Accepted::Other(read)) => self.serve_nexel(Rewind::new(read, socket), client_subject).await,
+            Err(e) => {
+                error!("trojan handshake has an error: {}", e);
+            }
+        }
+    }
+
+    /// serves a connection that the tls routes hand to trojan, anything else goes to the fallback.
+    async fn serve_trojan<RW: AsyncRead + AsyncWrite + Unpin>(&self, mut socket: RW, client_subject: std::option::Option<String>) {
+        let Some(trojan) = &self.trojan else {
+            return self.fall_back(socket, &[]).await;
+        };
+        match config::within(self.timeouts.handshake(), Error::HandshakeTimeout, trojan.accept(&mut socket)).await {
+            Ok(trojan::Accepted::Connect { user, dst }) => self.run_trojan(socket, client_subject, user, dst).await,
+            Ok(trojan::Accepted::Other(read)) => self.fall_back(socket, &read).await,
+            Err(e) => {
+                error!("trojan handshake has an error: {}", e);
+            }
+        }
+    }
+
+    async fn run_trojan<RW>(&self, socket: RW, client_subject: std::option::Option<String>, user: String, dst: Addr)
+    where
+        RW: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut conn = self.connection(socket, client_subject);
+        conn.set_user(user);
+        if let Err(e) = conn.run_to(&dst.host(), dst.port()).await {
+            error!("Connection handler run failed: {}", e);
+        }
+    }
+
+    /// proxies the connection to the fallback so that it looks like a web server, or drains it without one.
+    async fn fall_back<RW: AsyncRead + AsyncWrite + Unpin>(&self, mut socket: RW, read: &[u8]) {
+        let Some(backend) = &self.fallback else {
+            return fallback::silence(&mut socket, &self.timeouts).await;
+        };
+        if let Err(e) = fallback::forward(socket, read, backend, &self.timeouts).await {
+            error!("fallback has an error: {}", e);
+        }
+    }
+
+    async fn serve_nexel<RW: AsyncRead + AsyncWrite + Unpin>(&self, socket: RW, client_subject: std::option::Option<String>) {
+        let Some(path) = &self.ws_path else {
+            return self.serve_tunnel(socket, client_subject, self.fallback.clone()).await;
+        };
+        match config::within(self.timeouts.handshake(), Error::HandshakeTimeout, ws::accept(socket, path)).await {
+            Ok(ws::Accepted::Tunnel(socket)) => self.serve_tunnel(socket, client_subject, None).await,
+            Ok(ws::Accepted::Other(socket, head)) if self.fallback.is_some() => self.fall_back(socket, &head).await,
+            Ok(ws::Accepted::Other(mut socket, _)) => {
+                let _ = ws::not_found(&mut socket).await;
+            }
+            Err(e) => {
+                error!("WebSocket handshake has an error: {}", e);
+            }
+        }
+    }
+
+    /// a tunnel is padded when it starts with the preface of one, otherwise the bytes read are read again by the connection.
+    /// anything that isn't a request of a client is handed to the fallback rather than answered.
+    async fn serve_tunnel<RW>(&self, mut socket: RW, client_subject: std::option::Option<String>, fallback: std::option::Option<Arc<str>>)
+    where
+        RW: AsyncRead + AsyncWrite + Unpin,
+    {
+        match config::within(self.timeouts.handshake(), Error::HandshakeTimeout, padding::accept(&mut socket)).await {
+            Ok(Preface::Padded(cfg)) => self.run(PadStream::server(socket, cfg), client_subject).await,
+            Ok(Preface::Other(read)) => {
+                let mut conn = self.connection(Rewind::new(read, socket), client_subject);
+                if let Some(backend) = fallback {
+                    conn.set_fallback(backend);
+                }
+                if let Err(e) = conn.run_on_server().await {
+                    error!("Connection handler run failed: {}", e);
+                }
+            }
+            Err(e) => {
+                error!("Tunnel preface has an error: {}", e);
+            }
+        }
+    }
+
+    /// every stream is served as a connection of its own, the peer authenticates in each one.
+    async fn serve_h2<RW: AsyncRead + AsyncWrite + Unpin>(&self, socket: RW, client_subject: std::option::Option<String>) {
+        let served = http2::serve(socket, |stream| {
+            let server = self.clone();
+            let client_subject = client_subject.clone();
+            tokio::spawn(async move { server.run(stream, client_subject).await });
+        });
+        if let Err(e) = served.await {
+            error!("HTTP/2 connection has an error: {}", e);
+        }
+    }
+
+    /// every stream is served as a connection of its own, the UDP associations tunnel their datagrams in the QUIC connection.
+    async fn serve_quic(&self, conn: quinn::Connection) {
+        let client_subject = quic::client_subject(&conn);
+        let served = quic::serve(conn, |stream, datagrams| {
+            let server = self.clone();
+            let client_subject = client_subject.clone();
+            tokio::spawn(async move {
+                let id = stream.id();
+                let mut conn = server.connection(stream, client_subject);
+                conn.set_datagrams(datagrams, id);
+                if let Err(e) = conn.run_on_server().await {
+                    error!("Connection handler run failed: {}", e);
+                }
+            });
+        });
+        if let Err(e) = served.await {
+            error!("QUIC connection has an error: {}", e);
+        }
+    }
+
+    async fn run<RW: AsyncRead + AsyncWrite + Unpin>(&self, socket: RW, client_subject: std::option::Option<String>) {
+        let mut conn = self.connection(socket, client_subject);
+        if let Err(e) = conn.run_on_server().await {
+            error!("Connection handler run failed: {}", e);
+        }
+    }
+
+    fn connection<RW: AsyncRead + AsyncWrite + Unpin>(&self, socket: RW, client_subject: std::option::Option<String>) -> Connection<RW> {
+        let mut conn = Connection::new(socket, None);
+        conn.set_timeouts(self.timeouts);
+        conn.set_acl(self.acl.clone());
+        if let Some(subject) = client_subject {
+            conn.set_client_subject(subject);
+        }
+        if let Some(authenticator) = &self.authenticator {
+            conn.set_authenticator(authenticator.clone());
+        }
+        conn
+    }
+}
+
+async fn listen_tls(listener: TcpListener, tls_acceptor: TlsAcceptor, server: Server) -> io::Result<()> {
     loop {
         let (socket, _) = listener.accept().await?;
-        match tokio::time::timeout(tokio::time::Duration::from_secs(10), tls_acceptor.accept(socket)).await {
-            Ok(Ok(socket)) => {
-                tokio::spawn(async move {
-                    if let Err(e) = Connection::new(socket, None).run_on_server().await {
+        let tls_acceptor = tls_acceptor.clone();
+        let server = server.clone();
+        // a slow handshake must not hold up the accept loop
+        tokio::spawn(async move {
+            let accept = async { Ok(tls_acceptor.accept(socket).await?) };
+            match config::within(server.timeouts.tls_handshake(), Error::TlsHandshakeTimeout, accept).await {
+                Ok(socket) => {
+                    let tls_conn = socket.get_ref().1;
+                    let subject = tls::client_subject(tls_conn);
+                    let h2 = tls_conn.alpn_protocol() == Some(http2::ALPN);
+                    match server.tls_cfg.route(tls_conn.server_name(), tls_conn.alpn_protocol()) {
+                        Some(Inbound::Trojan) => server.serve_trojan(socket, subject).await,
+                        Some(Inbound::Fallback) => server.fall_back(socket, &[]).await,
+                        Some(Inbound::Tunnel) if !h2 => server.serve_nexel(socket, subject).await,
+                        _ if h2 => server.serve_h2(socket, subject).await,
+                        _ => server.serve(socket, subject).await,
+                    }
+                }
+                Err(e) => {
+                    error!("TLS handshake has an error: {}", e);
+                }
+            }
+        });
+    }
+}
+
+async fn listen_quic(endpoint: quinn::Endpoint, server: Server) {
+    while let Some(incoming) = endpoint.accept().await {
+        let server = server.clone();
+        tokio::spawn(async move {
+            let accept = async { Ok(incoming.await.map_err(io::Error::from)?) };
+            match config::within(server.timeouts.tls_handshake(), Error::TlsHandshakeTimeout, accept).await {
+                Ok(conn) => server.serve_quic(conn).await,
+                Err(e) => {
+                    error!("QUIC handshake has an error: {}", e);
+                }
+            }
+        });
+    }
+}
+
+async fn listen_shadowsocks(listener: TcpListener, inbound: Arc<shadowsocks::Inbound>, server: Server) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let inbound = inbound.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let mut socket = socket;
+            let accept = inbound.accept(&mut socket);
+            match config::within(server.timeouts.handshake(), Error::HandshakeTimeout, accept).await {
+                Ok((stream, dst)) => {
+                    // the pre-shared key authenticates the peer
+                    let mut conn = Connection::new(stream, None);
+                    conn.set_timeouts(server.timeouts);
+                    conn.set_acl(server.acl.clone());
+                    if let Err(e) = conn.run_to(&dst.host(), dst.port()).await {
                         error!("Connection handler run failed: {}", e);
                     }
-                });
-            },
-            Ok(Err(e)) => {
-                error!("TLS handshake has an error: {}", e);
-            },
-            Err(e) => {
-                error!("TLS handshake time out, error: {}", e);
+                }
+                Err(e) => {
+                    error!("shadowsocks handshake has an error: {}", e);
+                    fallback::silence(&mut socket, &server.timeouts).await;
+                }
             }
-        }
+        });
     }
 }
 
-async fn listen(listener: TcpListener) -> io::Result<()> {
+async fn listen_noise(listener: TcpListener, inbound: Arc<noise::Inbound>, server: Server) -> io::Result<()> {
     loop {
         let (socket, _) = listener.accept().await?;
+        let inbound = inbound.clone();
+        let server = server.clone();
         tokio::spawn(async move {
-           if let Err(e) = Connection::new(socket, None).run_on_server().await {
-               error!("Connection handler run failed: {}", e);
-           }
+            let mut socket = socket;
+            let accept = inbound.accept(&mut socket);
+            match config::within(server.timeouts.tls_handshake(), Error::NoiseHandshakeTimeout, accept).await {
+                Ok((stream, client)) => {
+                    info!("[NOISE] client = {}", client);
+                    server.serve_nexel(stream, None).await;
+                }
+                Err(e) => {
+                    error!("noise handshake has an error: {}", e);
+                    fallback::silence(&mut socket, &server.timeouts).await;
+                }
+            }
+        });
+    }
+}
+
+async fn listen(listener: TcpListener, server: Server) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            server.serve(socket, None).await;
         });
     }
 }
+
+fn gen_cert(gen: &GenCert, port: u16) -> io::Result<()> {
+    let generated = tls::generate(std::path::Path::new(&gen.out), &gen.san, gen.days, gen.force)?;
+    let host = &gen.san[0];
+    println!("wrote {}, {} and {}, keep ca.key offline", generated.ca.display(), generated.cert.display(), generated.key.display());
+    println!("pin-sha256: sha256//{}", generated.pin);
+    println!();
+    println!("# nexeld");
+    println!("./nexeld -p {port} -t -c {} -k {}", generated.cert.display(), generated.key.display());
+    println!();
+    println!("# nexel, copy ca.crt to the client");
+    println!("./nexel -t -h {host} -o {port} -c ca.crt --pin-sha256 sha256//{}", generated.pin);
+    println!();
+    println!("# or in the proxies of rule.yaml");
+    println!("proxies:");
+    println!("  - name: nexeld");
+    println!("    server: {host}");
+    println!("    port: {port}");
+    println!("    tls: true");
+    println!("    cert: ca.crt");
+    println!("    pin-sha256: [sha256//{}]", generated.pin);
+    Ok(())
+}
+
+fn gen_key(port: u16) -> io::Result<()> {
+    let keypair = noise::Keypair::generate();
+    println!("private-key: {}", keypair.private_key());
+    println!("public-key: {}", keypair.public_key());
+    println!();
+    println!("# nexeld, keep the private key to the server");
+    println!("./nexeld -p {port} --noise-key {}", keypair.private_key());
+    println!();
+    println!("# nexel");
+    println!("./nexel -h server_host -o {port} --noise-key {}", keypair.public_key());
+    println!();
+    println!("# or in the proxies of rule.yaml");
+    println!("proxies:");
+    println!("  - name: nexeld");
+    println!("    server: server_host");
+    println!("    port: {port}");
+    println!("    noise:");
+    println!("      server-key: {}", keypair.public_key());
+    Ok(())
+}
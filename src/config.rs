@@ -0,0 +1,104 @@
+use std::time::Duration;
+use serde::Deserialize;
+use crate::error::Error;
+use crate::Result;
+
+/// timeouts of every phase of a connection, in seconds, 0 disables the limit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Timeouts {
+    handshake: u64,
+    connect: u64,
+    tls_handshake: u64,
+    idle: u64,
+    lifetime: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: 120,
+            connect: 120,
+            tls_handshake: 10,
+            idle: 300,
+            lifetime: 0,
+        }
+    }
+}
+
+fn secs(n: u64) -> Option<Duration> {
+    if n == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(n))
+    }
+}
+
+impl Timeouts {
+    /// loads the `timeouts` section of the config file.
+    pub fn load(path: &str) -> Result<Timeouts> {
+        #[derive(Deserialize)]
+        struct TimeoutsCfg {
+            #[serde(default)]
+            timeouts: Timeouts,
+        }
+        let file = std::fs::File::open(path)?;
+        let cfg: TimeoutsCfg = serde_yml::from_reader(file)
+            .map_err(|e| Error::Other(format!("bad timeouts config: {e}")))?;
+        Ok(cfg.timeouts)
+    }
+
+    /// receiving the request of the client.
+    pub fn handshake(&self) -> Option<Duration> {
+        secs(self.handshake)
+    }
+
+    /// establishing a tcp connection to the destination or the proxy server.
+    pub fn connect(&self) -> Option<Duration> {
+        secs(self.connect)
+    }
+
+    /// the tls handshake, or the noise one that takes its place.
+    pub fn tls_handshake(&self) -> Option<Duration> {
+        secs(self.tls_handshake)
+    }
+
+    /// no byte was relayed in either direction.
+    pub fn idle(&self) -> Option<Duration> {
+        secs(self.idle)
+    }
+
+    /// the whole life of a connection.
+    pub fn lifetime(&self) -> Option<Duration> {
+        secs(self.lifetime)
+    }
+}
+
+/// runs the future under the limit, the error is returned when it's exceeded.
+pub async fn within<F, T>(limit: Option<Duration>, err: Error, future: F) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.map_err(|_| err)?,
+        None => future.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::config::Timeouts;
+
+    #[test]
+    fn load_timeouts() {
+        let path = std::env::temp_dir().join(format!("nexel-timeouts-{}.yaml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "timeouts:\n  connect: 5\n  idle: 0\n  lifetime: 3600\nrules: []\n").unwrap();
+        let timeouts = Timeouts::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(timeouts.connect(), Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.handshake(), Some(Duration::from_secs(120)));
+        assert_eq!(timeouts.idle(), None);
+        assert_eq!(timeouts.lifetime(), Some(Duration::from_secs(3600)));
+    }
+}
@@ -1,55 +1,62 @@
 use crate::error::Error;
-use crate::protocol::{Reply, ReqCmd, ReqFrame, Request};
-use crate::{protocol, rule, tls, Result};
+use crate::outbound::{Outbounds, Protocol, ProxyCfg, ProxyStream, Target};
+use crate::protocol::{AType, Reply, ReqCmd, ReqFrame, Request};
+use crate::quic::Datagrams;
+use crate::{config, dial, fallback, mux, protocol, rule, sniff, udp, upstream, Result};
+use crate::fallback::Recorder;
+use crate::mux::Session;
+use crate::acl::Acl;
+use crate::auth::Authenticator;
+use crate::config::Timeouts;
 use bytes::BytesMut;
-use std::net::{SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use log::{error, info};
 use tokio::io;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
-use tokio::net::{ToSocketAddrs, TcpStream};
-use tokio::time::timeout;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{sleep_until, Instant};
 use crate::rule::Routing;
 
 pub struct Connection<RW> {
     stream: BufWriter<RW>,
     id: String,
-    proxy_cfg: Option<ProxyCfg>,
-}
-
-#[derive(Clone)]
-pub struct ProxyCfg {
-    proxy_srv_host: String,
-    proxy_srv_port: u16,
-    cert_path: String,
-}
-
-impl ProxyCfg {
-    pub fn new(h: &str, p: u16, cert: &str) -> ProxyCfg {
-        ProxyCfg{
-            proxy_srv_host: h.to_string(),
-            proxy_srv_port: p,
-            cert_path: cert.to_string(),
-        }
-    }
-    pub fn host(&self) -> &str {
-        &self.proxy_srv_host
-    }
-    pub fn addr(&self) -> String {
-        format!("{}:{}", self.proxy_srv_host, self.proxy_srv_port)
-    }
-
-    pub fn cert(&self) -> &str {
-        &self.cert_path
-    }
+    outbounds: Option<Arc<Outbounds>>,
+    timeouts: Timeouts,
+    created: Instant,
+    authenticator: Option<Arc<Authenticator>>,
+    user: Option<String>,
+    client_subject: Option<String>,
+    acl: Option<Arc<Acl>>,
+    datagrams: Option<(Arc<Datagrams>, u64)>,
+    local_addr: Option<SocketAddr>,
+    fallback: Option<Arc<str>>,
+    // the client was replied to before the dial, or it doesn't speak socks and nothing is replied to it
+    replied: bool,
+    sniff: Option<Duration>, // how long the first bytes are waited for, none when the domains aren't sniffed
+    sniffed: BytesMut, // the first bytes of the client, they go to the destination first
 }
 
 impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
-    pub fn new(socket: RW, proxy_cfg: Option<ProxyCfg>) -> Connection<RW> {
+    pub fn new(socket: RW, outbounds: Option<Arc<Outbounds>>) -> Connection<RW> {
         Connection {
             stream: BufWriter::new(socket),
             id: uuid::Uuid::new_v4().to_string(),
-            proxy_cfg,
+            outbounds,
+            timeouts: Timeouts::default(),
+            created: Instant::now(),
+            authenticator: None,
+            user: None,
+            client_subject: None,
+            acl: None,
+            datagrams: None,
+            local_addr: None,
+            fallback: None,
+            replied: false,
+            sniff: None,
+            sniffed: BytesMut::new(),
         }
     }
 
@@ -57,11 +64,68 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
         &self.id
     }
 
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// peers have to pass the handshake of the authenticator before their requests are served.
+    pub fn set_authenticator(&mut self, authenticator: Arc<Authenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// destinations that are connected directly have to be allowed by the acl.
+    pub fn set_acl(&mut self, acl: Arc<Acl>) {
+        self.acl = Some(acl);
+    }
+
+    /// the QUIC connection that the stream belongs to, the datagrams of a UDP association are tunneled in it
+    /// with the stream id.
+    pub fn set_datagrams(&mut self, datagrams: Arc<Datagrams>, id: u64) {
+        self.datagrams = Some((datagrams, id));
+    }
+
+    /// the address that the client connected to, the UDP relay of an association is bound on its ip.
+    pub fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.local_addr = Some(addr);
+    }
+
+    /// the backend that a peer failing the handshake is handed to, instead of being answered.
+    pub fn set_fallback(&mut self, backend: Arc<str>) {
+        self.fallback = Some(backend);
+    }
+
+    /// the domains of the requests that carry an ip only are sniffed from the first bytes of the client,
+    /// the TLS ClientHello or the HTTP request, and routed by the domain rules. the client has `wait` to send them.
+    pub fn set_sniff(&mut self, wait: Option<Duration>) {
+        self.sniff = wait;
+    }
+
+    /// the subject of the tls client certificate that the peer presented.
+    pub fn set_client_subject(&mut self, subject: String) {
+        info!("[MTLS] conn_id = {}, subject = {}", self.id, subject);
+        self.client_subject = Some(subject);
+    }
+
+    pub fn client_subject(&self) -> Option<&str> {
+        self.client_subject.as_deref()
+    }
+
+    /// the user that an inbound protocol authenticated the peer as by itself, such as trojan.
+    pub fn set_user(&mut self, user: String) {
+        info!("[AUTH] conn_id = {}, user = {}", self.id, user);
+        self.user = Some(user);
+    }
+
+    /// the user that the peer was authenticated as.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         let mut authorized = false;
         loop {
             let mut reply = Reply::new();
-            match protocol::recv_and_parse_req(self.stream.get_mut(), authorized).await {
+            match protocol::recv_and_parse_req(self.stream.get_mut(), authorized, self.timeouts.handshake()).await {
                 Ok(Some(req_frame)) => {
                     match req_frame {
                         ReqFrame::Auth(_) => {
@@ -73,6 +137,11 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
                             self.process(&mut reply, &req).await?;
                             break;
                         }
+                        ReqFrame::Mux => {
+                            let err = Error::VnUnsupported(mux::PREFACE[0]);
+                            self.reply(reply.error(&err).await?).await?;
+                            return Err(err);
+                        }
                     }
                 }
                 Ok(None) => break,
@@ -86,15 +155,64 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
     }
 
     pub async fn run_on_server(&mut self) -> Result<()> {
-        let mut reply = Reply::new();
-        match protocol::recv_and_parse_req(self.stream.get_mut(), true).await {
-            Ok(req) => {
-                if let Some(ReqFrame::Req(req)) = req {
-                    self.process(&mut reply, &req).await?;
-                    reply.set_ver(req.ver);
+        // what the peer sent is kept until its request is parsed, the fallback gets it along with the connection
+        let mut recorder = Recorder::new(self.stream.get_mut());
+        if let Some(authenticator) = self.authenticator.clone() {
+            let verify = authenticator.verify(&mut recorder);
+            match config::within(self.timeouts.handshake(), Error::HandshakeTimeout, verify).await {
+                Ok(user) => {
+                    info!("[AUTH] conn_id = {}, user = {}", self.id, user);
+                    self.user = Some(user);
                 }
-                Ok(())
+                Err(err) => {
+                    let read = recorder.into_recorded();
+                    if !self.fall_back(&read).await {
+                        fallback::silence(self.stream.get_mut(), &self.timeouts).await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let frame = protocol::recv_and_parse_req(&mut recorder, true, self.timeouts.handshake()).await;
+        let read = recorder.into_recorded();
+        match frame {
+            Ok(Some(ReqFrame::Req(req))) => self.process(&mut Reply::new(), &req).await,
+            Ok(Some(ReqFrame::Mux)) => self.serve_mux().await,
+            Ok(_) => Ok(()),
+            Err(err) => {
+                if !self.fall_back(&read).await {
+                    self.reply(Reply::new().error(&err).await?).await?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// serves a connection that the firewall redirected here, the destination is the one that it had before.
+    /// it goes through the rules and the proxies as a socks request would, but nothing is replied to the client.
+    pub async fn run_transparent(&mut self, dst: SocketAddr) -> Result<()> {
+        self.replied = true;
+        self.process(&mut Reply::new(), &Request::connect(dst)).await
+    }
+
+    /// serves a tunnel whose destination the inbound protocol carried by itself, such as shadowsocks.
+    /// the destination is connected directly and nothing is replied to the peer.
+    pub async fn run_to(&mut self, host: &str, port: u16) -> Result<()> {
+        info!("[CONNECT-Request] conn_id = {}, dst = {}:{}", self.id, host, port);
+        let mut remote = self.timeout_connect(host, port).await?;
+        info!("[CONNECT-Reply] conn_id = {}, kind = Direct", self.id);
+        self.relay(&mut remote).await
+    }
+
+    /// serves a forwarded request, the other frames are returned.
+    async fn serve_request(&mut self) -> Result<Option<ReqFrame>> {
+        let mut reply = Reply::new();
+        match protocol::recv_and_parse_req(self.stream.get_mut(), true, self.timeouts.handshake()).await {
+            Ok(Some(ReqFrame::Req(req))) => {
+                self.process(&mut reply, &req).await?;
+                Ok(None)
             }
+            Ok(frame) => Ok(frame),
             Err(err) => {
                 self.reply(reply.error(&err).await?).await?;
                 Err(err)
@@ -102,19 +220,57 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
         }
     }
 
+    /// serves every stream that nexel opens in the session as a connection of its own.
+    async fn serve_mux(&mut self) -> Result<()> {
+        self.reply(&mux::PREFACE).await?;
+        info!("[MUX] conn_id = {}, session started", self.id);
+        let (timeouts, acl, user, client_subject) = (self.timeouts, self.acl.clone(), self.user.clone(), self.client_subject.clone());
+        let (_session, mut incoming, driver) = Session::server(self.stream.get_mut());
+        let accept = async {
+            while let Some(stream) = incoming.recv().await {
+                // the peer was authenticated with the session
+                let mut conn = Connection::new(stream, None);
+                conn.timeouts = timeouts;
+                conn.acl = acl.clone();
+                conn.user = user.clone();
+                conn.client_subject = client_subject.clone();
+                tokio::spawn(async move {
+                    match conn.serve_request().await {
+                        Ok(Some(_)) => error!("[MUX] conn_id = {}, a stream can only carry a request", conn.id()),
+                        Err(e) => error!("[MUX] conn_id = {}, stream handler run failed: {}", conn.id(), e),
+                        Ok(None) => {}
+                    }
+                });
+            }
+        };
+        tokio::select! {
+            ret = driver => Ok(ret?),
+            _ = accept => Ok(()),
+        }
+    }
+
     async fn process(&mut self, reply: &mut Reply, req: &Request) -> Result<()> {
         reply.set_ver(req.ver);
-        match self.process_request(&req).await {
-            Ok((mut remote, direct)) => {
-                if direct {
-                    self.reply(reply.successful((req.a_type, req.dst_addr, req.dst_domain.clone()), req.dst_port).await?).await?;
-                    info!("[CONNECT-Reply] conn_id = {}, kind = Direct", self.id);
-                    connect_two_way(self.stream.get_mut(), &mut remote).await?;
-                } else if self.proxy_cfg.is_none() {
-                    return Err(Error::Other("Proxy configuration not ".to_string()));
-                } else {
-                    self.proxy(req, remote).await?;
-                }
+        if req.cmd == ReqCmd::Udp {
+            return self.associate(reply).await;
+        }
+        let sniff = self.sniff_wait(req);
+        let sniffed = match sniff {
+            Some(wait) => self.sniff(reply, req, wait).await?,
+            None => None,
+        };
+        match self.process_request(req, sniffed.as_deref()).await {
+            Ok((mut remote, None)) => {
+                self.reply(reply.successful((req.a_type, req.dst_addr, req.dst_domain.clone()), req.dst_port).await?).await?;
+                info!("[CONNECT-Reply] conn_id = {}, kind = Direct", self.id);
+                self.relay(&mut remote).await
+            }
+            Ok((remote, Some(proxy_cfg))) => {
+                self.proxy(reply, req, remote, &proxy_cfg).await
+            }
+            Err(e) if sniff.is_some() => {
+                // the client took the success that came before the dial, it only sees the connection closed
+                error!("[SNIFF] conn_id = {}, kind = failed after the reply, error = {}", self.id, e);
                 Ok(())
             }
             Err(e) => {
@@ -125,42 +281,126 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
         }
     }
 
-    async fn proxy(&mut self, req: &Request, mut remote: TcpStream) -> Result<()> {
-        let mut buffer = BytesMut::from(req.raw());
-        let proxy_cfg = self.proxy_cfg.clone().unwrap();
-        if !proxy_cfg.cert().is_empty() {
-            let mut tls_remote = tls::connect(remote, proxy_cfg.cert(), proxy_cfg.host()).await?;
-            tls_remote.write_buf(&mut buffer).await?;
-            tls_remote.flush().await?;
-            info!("[CONNECT-Proxy] conn_id = {}, kind = Proxy", self.id);
-            connect_two_way(self.stream.get_mut(), &mut tls_remote).await
-        } else {
+    /// how long the first bytes of the client are waited for, none when a sniffed domain couldn't change the routing.
+    /// that's when the request carries a domain already, or no domain rule is written.
+    fn sniff_wait(&self, req: &Request) -> Option<Duration> {
+        if req.cmd != ReqCmd::Connect || req.dst_addr.is_none() || self.outbounds.is_none() || !rule::has_domain_rules() {
+            return None;
+        }
+        self.sniff
+    }
+
+    /// replies to the client before the dial, so that it sends its first bytes, and takes the domain from them.
+    /// the client is closed without a reply when the dial fails afterwards.
+    async fn sniff(&mut self, reply: &mut Reply, req: &Request, wait: Duration) -> Result<Option<String>> {
+        self.reply(reply.successful((req.a_type, req.dst_addr, req.dst_domain.clone()), req.dst_port).await?).await?;
+        self.replied = true;
+        let (read, domain) = sniff::read(self.stream.get_mut(), wait).await;
+        self.sniffed = read;
+        if let Some(domain) = &domain {
+            info!("[SNIFF] conn_id = {}, domain = {}", self.id, domain);
+        }
+        Ok(domain)
+    }
+
+    async fn proxy(&mut self, reply: &mut Reply, req: &Request, mut remote: Box<dyn ProxyStream>, proxy_cfg: &ProxyCfg) -> Result<()> {
+        if proxy_cfg.protocol() == Protocol::Nexel && !self.replied {
+            // nexeld replies to the client by itself
+            let mut buffer = BytesMut::from(req.raw());
             remote.write_buf(&mut buffer).await?;
             remote.flush().await?;
-            info!("[CONNECT-Proxy] conn_id = {}, kind = Proxy", self.id);
-            connect_two_way(self.stream.get_mut(), &mut remote).await
+        } else {
+            let host = match (&req.dst_domain, req.dst_addr) {
+                (Some(domain), _) => domain.clone(),
+                (None, Some(ip)) => ip.to_string(),
+                (None, None) => return Err(Error::AddrTypeUnsupported(req.a_type as u8)),
+            };
+            if let Err(e) = upstream::handshake(proxy_cfg, &mut remote, &host, req.dst_port).await {
+                error!("[CONNECT-Reply] conn_id = {}, kind = failed, outbound = {}, error = {}", self.id, proxy_cfg.name(), e);
+                self.reply(reply.error(&e).await?).await?;
+                return Ok(());
+            }
+            self.reply(reply.successful((req.a_type, req.dst_addr, req.dst_domain.clone()), req.dst_port).await?).await?;
+        }
+        info!("[CONNECT-Proxy] conn_id = {}, kind = Proxy, outbound = {}", self.id, proxy_cfg.name());
+        self.relay(&mut remote).await
+    }
+
+    /// relays the datagrams of a UDP ASSOCIATE until the client closes this connection.
+    /// nexel relays them through a local socket, nexeld through the QUIC connection that the request came in.
+    async fn associate(&mut self, reply: &mut Reply) -> Result<()> {
+        let idle = self.timeouts.idle();
+        if let Some(outbounds) = self.outbounds.clone() {
+            let ip = self.local_addr.map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |addr| addr.ip());
+            let socket = UdpSocket::bind((ip, 0)).await?;
+            let bound = socket.local_addr()?;
+            let a_type = if bound.is_ipv4() { AType::Ipv4 } else { AType::Ipv6 };
+            self.reply(reply.successful((a_type, Some(bound.ip()), None), bound.port()).await?).await?;
+            info!("[UDP-Associate] conn_id = {}, bound = {}", self.id, bound);
+            let (relay, replies) = udp::Relay::new(outbounds, &self.id);
+            udp::serve_local(socket, relay, replies, self.stream.get_mut(), idle).await
+        } else if let Some((datagrams, id)) = self.datagrams.clone() {
+            self.reply(reply.successful((AType::Ipv4, Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), None), 0).await?).await?;
+            info!("[UDP-Associate] conn_id = {}, id = {}", self.id, id);
+            udp::serve_remote(&datagrams, id, self.acl.as_deref(), self.stream.get_mut(), idle).await
+        } else {
+            let err = Error::NotImplemented;
+            error!("[UDP-Associate] conn_id = {}, kind = failed, error = {}", self.id, err);
+            self.reply(reply.error(&err).await?).await?;
+            Ok(())
+        }
+    }
+
+    /// hands the peer over to the fallback along with the bytes read from it, false if there's none.
+    async fn fall_back(&mut self, read: &[u8]) -> bool {
+        let Some(backend) = self.fallback.clone() else {
+            return false;
+        };
+        if let Err(e) = fallback::forward(self.stream.get_mut(), read, &backend, &self.timeouts).await {
+            error!("[FALLBACK] conn_id = {}, error = {}", self.id, e);
         }
+        true
     }
 
-    async fn process_request(&self, req: &Request) -> Result<(TcpStream, bool)> {
+    async fn relay<R: AsyncRead + AsyncWrite + Unpin>(&mut self, remote: &mut R) -> Result<()> {
+        let deadline = self.timeouts.lifetime().map(|lifetime| self.created + lifetime);
+        let idle = self.timeouts.idle();
+        if !self.sniffed.is_empty() {
+            remote.write_all(&self.sniffed).await?;
+            self.sniffed.clear();
+        }
+        connect_two_way(self.stream.get_mut(), remote, idle, deadline).await
+    }
+
+    /// dials the destination or the proxy server that the rules pick, the proxy config is none when it connects directly.
+    /// a sniffed domain picks the routing of an ip when a domain rule matches it, the ip is dialed all the same.
+    async fn process_request(&self, req: &Request, sniffed: Option<&str>) -> Result<(Box<dyn ProxyStream>, Option<ProxyCfg>)> {
         match req.cmd {
             ReqCmd::Connect => {
                 info!("[CONNECT-Request] conn_id = {}, Request = {}", self.id, req);
                 if let Some(ip) = req.dst_addr {
-                    if let Some(proxy) = &self.proxy_cfg {
-                        if rule::ip(ip) == Routing::Proxy {
-                            return Ok((self.timeout_connect(proxy.addr()).await?, false));
+                    if let Some(outbounds) = &self.outbounds {
+                        let routing = match rule::port(req.dst_port).or_else(|| sniffed.and_then(rule::domain_rules)) {
+                            Some(routing) => routing,
+                            None => rule::ip(ip),
+                        };
+                        let dst = sniffed.map_or_else(|| ip.to_string(), str::to_string);
+                        if let Some(proxy) = self.route(outbounds, routing, &dst)? {
+                            return Ok((self.connect_proxy(outbounds, &proxy).await?, Some(proxy)));
                         }
                     }
-                    Ok((self.timeout_connect(SocketAddr::new(ip, req.dst_port)).await?, true))
+                    Ok((Box::new(self.timeout_connect(&ip.to_string(), req.dst_port).await?), None))
                 } else if let Some(domain) = &req.dst_domain {
-                    if let Some(proxy) = &self.proxy_cfg {
-                        if rule::domain(domain.as_str()).await? == Routing::Proxy {
-                            return Ok((self.timeout_connect(proxy.addr()).await?, false));
+                    if let Some(outbounds) = &self.outbounds {
+                        let routing = match rule::port(req.dst_port) {
+                            Some(routing) => routing,
+                            None => rule::domain(domain.as_str()).await?,
+                        };
+                        if let Some(proxy) = self.route(outbounds, routing, domain)? {
+                            return Ok((self.connect_proxy(outbounds, &proxy).await?, Some(proxy)));
                         }
                     }
-                    let addr = format!("{}:{}", domain, req.dst_port);
-                    Ok((self.timeout_connect(addr).await?, true))
+                    Ok((Box::new(self.timeout_connect(domain, req.dst_port).await?), None))
                 } else {
                     Err(Error::AddrTypeUnsupported(req.ver as u8))
                 }
@@ -169,50 +409,118 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
                 Err(Error::NotImplemented)
             }
             ReqCmd::Udp => {
+                // served by associate
                 Err(Error::NotImplemented)
             }
         }
     }
+
+    fn route(&self, outbounds: &Outbounds, routing: Routing, dst: &str) -> Result<Option<ProxyCfg>> {
+        match outbounds.resolve(routing.name(), dst)? {
+            Target::Direct => Ok(None),
+            Target::Reject => {
+                info!("[CONNECT-Reject] conn_id = {}, routing = {}", self.id, routing.name());
+                Err(Error::Rejected)
+            }
+            Target::Proxy(proxy) => Ok(Some(*proxy)),
+        }
+    }
+
+    async fn connect_proxy(&self, outbounds: &Outbounds, proxy: &ProxyCfg) -> Result<Box<dyn ProxyStream>> {
+        outbounds.connect(proxy).await.inspect_err(|_| outbounds.report_failure(proxy.name()))
+    }
+
     async fn reply(&mut self, buf: &[u8]) -> Result<()> {
-        self.stream.write(buf).await?;
+        if self.replied {
+            return Ok(());
+        }
+        self.stream.write_all(buf).await?;
         self.stream.flush().await?;
         Ok(())
     }
 
-    async fn timeout_connect<A: ToSocketAddrs>(&self, addr: A) -> Result<TcpStream> {
-        match timeout(Duration::from_secs(120), TcpStream::connect(addr)).await {
-            Ok(Ok(ret)) => Ok(ret),
-            Ok(Err(e)) => Err(Error::IoErr(e)),
-            Err(_) => Err(Error::Other(format!("connection timout, id: {}", self.id))),
-        }
+    async fn timeout_connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        config::within(self.timeouts.connect(), Error::ConnectTimeout, async {
+            match &self.acl {
+                Some(acl) => acl.connect(host, port).await.inspect_err(|e| {
+                    if let Error::Rejected = e {
+                        info!("[CONNECT-Reject] conn_id = {}, acl denied {}:{}", self.id, host, port);
+                    }
+                }),
+                None => Ok(dial::connect(host, port).await?),
+            }
+        }).await
     }
 }
 
-async fn connect_two_way<RW1, RW2>(a: &mut RW1, b: &mut RW2) -> Result<()>
+/// relays both directions until both are closed, the idle limit covers reading and writing in either direction.
+async fn connect_two_way<RW1, RW2>(a: &mut RW1, b: &mut RW2, idle: Option<Duration>, deadline: Option<Instant>) -> Result<()>
 where
     RW1: AsyncRead + AsyncWrite + Unpin,
     RW2: AsyncRead + AsyncWrite + Unpin,
 {
     let (mut a_reader, mut a_writer) = io::split(a);
     let (mut b_reader, mut b_writer) = io::split(b);
+    let start = Instant::now();
+    let last_active = AtomicU64::new(0); // milliseconds since start
 
     let copy_a_to_b = async {
-        let _ = io::copy(&mut a_reader, &mut b_writer).await;
+        let _ = copy(&mut a_reader, &mut b_writer, start, &last_active).await;
         let _ = b_writer.shutdown().await;
     };
     let copy_b_to_a = async {
-        let _ = io::copy(&mut b_reader, &mut a_writer).await;
+        let _ = copy(&mut b_reader, &mut a_writer, start, &last_active).await;
         let _ = a_writer.shutdown().await;
     };
-    tokio::join!(copy_a_to_b, copy_b_to_a);
-    Ok(())
+    let watchdog = async {
+        match idle {
+            Some(idle) => loop {
+                let expire = start + Duration::from_millis(last_active.load(Ordering::Relaxed)) + idle;
+                if Instant::now() >= expire {
+                    break;
+                }
+                sleep_until(expire).await;
+            },
+            None => std::future::pending().await,
+        }
+    };
+    let lifetime = async {
+        match deadline {
+            Some(deadline) => sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = async { tokio::join!(copy_a_to_b, copy_b_to_a) } => Ok(()),
+        _ = watchdog => Err(Error::IdleTimeout),
+        _ = lifetime => Err(Error::LifetimeExceeded),
+    }
+}
+
+async fn copy<R, W>(reader: &mut R, writer: &mut W, start: Instant, last_active: &AtomicU64) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 8192];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        total += n as u64;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::error::Error;
     use bytes::{Buf, BytesMut};
-    use serde::de::Unexpected::Bytes;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpStream;
     use tokio::select;
@@ -287,12 +595,12 @@ mod tests {
     async fn test_client_v5() {
         let mut socket = TcpStream::connect("127.0.0.1:3456").await.unwrap();
         let buf: Vec<u8> = vec![0x05, 0x01, 0x00];
-        socket.write(buf.as_slice()).await.unwrap();
+        socket.write_all(buf.as_slice()).await.unwrap();
         let mut read_buf: Vec<u8> = vec![];
         socket.read_buf(&mut read_buf).await.unwrap();
         println!("{:?}", read_buf);
         let buf: Vec<u8> = vec![5, 1, 0, 3, 8, 0x6e, 0x65, 0x78, 0x65, 0x6c, 0x2e, 0x63, 0x63, 0x00, 0x50];
-        socket.write(buf.as_slice()).await.unwrap();
+        socket.write_all(buf.as_slice()).await.unwrap();
         let mut read_buf: Vec<u8> = vec![];
         socket.read_buf(&mut read_buf).await.unwrap();
         println!("{:?}", read_buf);
@@ -315,4 +623,169 @@ mod tests {
         socket.write_buf(&mut buf).await.unwrap();
         socket.flush().await.unwrap();
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn relay_timeouts() {
+        use crate::connection::connect_two_way;
+        use tokio::time::{sleep, Duration, Instant};
+
+        // traffic keeps the relay alive past the idle limit
+        let (mut a, mut client) = tokio::io::duplex(64);
+        let (mut b, mut server) = tokio::io::duplex(64);
+        let start = Instant::now();
+        let talk = async {
+            for _ in 0..3 {
+                sleep(Duration::from_secs(4)).await;
+                client.write_all(b"ping").await.unwrap();
+                let mut buf = [0u8; 4];
+                server.read_exact(&mut buf).await.unwrap();
+            }
+        };
+        let (ret, _) = tokio::join!(connect_two_way(&mut a, &mut b, Some(Duration::from_secs(5)), None), talk);
+        assert!(matches!(ret, Err(Error::IdleTimeout)));
+        assert_eq!(start.elapsed(), Duration::from_secs(17));
+
+        let (mut a, _client) = tokio::io::duplex(64);
+        let (mut b, _server) = tokio::io::duplex(64);
+        let ret = connect_two_way(&mut a, &mut b, None, Some(Instant::now() + Duration::from_secs(60))).await;
+        assert!(matches!(ret, Err(Error::LifetimeExceeded)));
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_bad_requests() {
+        use std::sync::Arc;
+        use tokio::net::TcpListener;
+        use crate::connection::Connection;
+
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            let mut request = [0u8; 16];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"GET / HTTP/1.1\r\n");
+            socket.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        });
+
+        // neither a reply of socks nor a silent close gives nexeld away
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server, None);
+        conn.set_fallback(Arc::from(backend_addr.as_str()));
+        let run = tokio::spawn(async move { conn.run_on_server().await });
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut response = [0u8; 17];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n");
+        drop(client);
+        assert!(matches!(run.await.unwrap(), Err(Error::VnUnsupported(b'G'))));
+    }
+
+    #[tokio::test]
+    async fn transparent_connections() {
+        use std::net::SocketAddr;
+        use crate::connection::Connection;
+        use crate::test_util::spawn_echo;
+
+        let dst = SocketAddr::from(([127, 0, 0, 1], spawn_echo().await));
+
+        // the client gets the bytes of the destination only, no reply of socks comes first
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server, None);
+        let run = tokio::spawn(async move { conn.run_transparent(dst).await });
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut echoed = [0u8; 16];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"GET / HTTP/1.1\r\n");
+        drop(client);
+        run.await.unwrap().unwrap();
+    }
+
+    /// a SOCKS5 CONNECT to the ip of `dst` that is sniffed, the first bytes are sent after the reply.
+    async fn sniffed_connect(dst: std::net::SocketAddr, first: &[u8]) -> (tokio::io::DuplexStream, tokio::task::JoinHandle<crate::Result<()>>) {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use crate::connection::Connection;
+        use crate::outbound::Outbounds;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server, Some(Arc::new(Outbounds::new())));
+        conn.set_sniff(Some(crate::sniff::WAIT));
+        let run = tokio::spawn(async move { conn.run().await });
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let port = dst.port().to_be_bytes();
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).await.unwrap();
+        // the reply comes before the dial, so it's a success whatever the destination turns out to be
+        let mut reply = [0u8; 10];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut reply)).await.unwrap().unwrap();
+        assert_eq!(reply[1], 0);
+        client.write_all(first).await.unwrap();
+        (client, run)
+    }
+
+    #[tokio::test]
+    async fn sniffed_requests() {
+        use std::net::SocketAddr;
+        use crate::test_util::spawn_echo;
+
+        crate::rule::tests::insert("DOMAIN,sniffed.nexel.cc,DIRECT");
+        let dst = SocketAddr::from(([127, 0, 0, 1], spawn_echo().await));
+
+        // the first bytes are sniffed and then relayed as they were
+        let request = b"GET / HTTP/1.1\r\nHost: sniffed.nexel.cc\r\n\r\n";
+        let (mut client, run) = sniffed_connect(dst, request).await;
+        let mut echoed = [0u8; 42];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, request);
+        drop(client);
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn sniffed_rejected() {
+        use std::net::SocketAddr;
+        use std::sync::atomic::Ordering;
+        use crate::test_util::spawn_with;
+
+        crate::rule::tests::insert("DOMAIN-SUFFIX,rejected.nexel.cc,REJECT");
+        let (port, accepted) = spawn_with(|_| async {}).await;
+        let dst = SocketAddr::from(([127, 0, 0, 1], port));
+
+        // the destination isn't dialed, the client that was replied to already is closed without the bytes going anywhere
+        let (mut client, run) = sniffed_connect(dst, b"GET / HTTP/1.1\r\nHost: www.rejected.nexel.cc\r\n\r\n").await;
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        run.await.unwrap().unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn port_rules() {
+        use std::sync::Arc;
+        use std::sync::atomic::Ordering;
+        use crate::connection::Connection;
+        use crate::outbound::Outbounds;
+        use crate::test_util::spawn_with;
+
+        let (port, accepted) = spawn_with(|_| async {}).await;
+        crate::rule::tests::insert(&format!("DST-PORT,{port},REJECT"));
+
+        // the port rule goes before the ip, which would be routed to the PROXY that isn't there
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server, Some(Arc::new(Outbounds::new())));
+        let run = tokio::spawn(async move { conn.run().await });
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let port = port.to_be_bytes();
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 2); // RulesNotAllowed
+        drop(client);
+        run.await.unwrap().unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+    }
 }
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Duration, Instant};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// how long to wait for AAAA records once A records have arrived (RFC 8305 section 3).
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+/// how long an attempt has before the next address is tried in parallel (RFC 8305 section 5).
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+lazy_static! {
+    /// the resolver of resolv.conf and the hosts file, it queries AAAA and A apart so that they can be raced.
+    static ref RESOLVER: Option<TokioAsyncResolver> = TokioAsyncResolver::tokio_from_system_conf()
+        .inspect_err(|e| warn!("the resolver config of the system can't be read, getaddrinfo is used: {e}"))
+        .ok();
+}
+
+/// connects to host:port with Happy Eyeballs v2, the host can be a domain or an ip.
+pub async fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return TcpStream::connect(SocketAddr::new(ip, port)).await;
+    }
+    let (addr, stream) = match &*RESOLVER {
+        Some(resolver) => race(v6_lookup(resolver, host, port), v4_lookup(resolver, host, port), TcpStream::connect).await?,
+        None => race_addrs(lookup_host(host, port).await?).await?,
+    };
+    info!("[Happy-Eyeballs] host = {}:{}, addr = {}", host, port, addr);
+    Ok(stream)
+}
+
+/// resolves the addresses of both families, the IPv6 ones come first.
+pub async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let Some(resolver) = &*RESOLVER else {
+        return lookup_host(host, port).await;
+    };
+    match tokio::join!(v6_lookup(resolver, host, port), v4_lookup(resolver, host, port)) {
+        (Err(e), Err(_)) => Err(e),
+        (v6, v4) => Ok(v6.unwrap_or_default().into_iter().chain(v4.unwrap_or_default()).collect()),
+    }
+}
+
+async fn v6_lookup(resolver: &TokioAsyncResolver, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let lookup = resolver.ipv6_lookup(host).await?;
+    Ok(lookup.iter().map(|aaaa| SocketAddr::new(IpAddr::V6(aaaa.0), port)).collect())
+}
+
+async fn v4_lookup(resolver: &TokioAsyncResolver, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let lookup = resolver.ipv4_lookup(host).await?;
+    Ok(lookup.iter().map(|a| SocketAddr::new(IpAddr::V4(a.0), port)).collect())
+}
+
+/// both families in one getaddrinfo call, for the systems whose resolver config can't be read.
+async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+/// connects to one of the addresses that were resolved already, with Happy Eyeballs v2.
+pub async fn connect_addrs(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let (addr, stream) = race_addrs(addrs).await?;
+    info!("[Happy-Eyeballs] addr = {}", addr);
+    Ok(stream)
+}
+
+/// races the addresses split by family, both are known already so connecting starts at once.
+async fn race_addrs(addrs: Vec<SocketAddr>) -> io::Result<(SocketAddr, TcpStream)> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    race(async { Ok(v6) }, async { Ok(v4) }, TcpStream::connect).await
+}
+
+/// takes the next address, alternating between the families.
+fn next_addr(v6: &mut VecDeque<SocketAddr>, v4: &mut VecDeque<SocketAddr>, prefer_v6: &mut bool) -> Option<SocketAddr> {
+    let addr = if *prefer_v6 {
+        v6.pop_front().or_else(|| v4.pop_front())
+    } else {
+        v4.pop_front().or_else(|| v6.pop_front())
+    };
+    if let Some(addr) = addr {
+        *prefer_v6 = addr.is_ipv4();
+    }
+    addr
+}
+
+/// races staggered connection attempts over the addresses as they are resolved, the losers are cancelled.
+async fn race<L6, L4, C, F, S>(v6_lookup: L6, v4_lookup: L4, connect: C) -> io::Result<(SocketAddr, S)>
+where
+    L6: Future<Output = io::Result<Vec<SocketAddr>>>,
+    L4: Future<Output = io::Result<Vec<SocketAddr>>>,
+    C: Fn(SocketAddr) -> F,
+    F: Future<Output = io::Result<S>> + Send + 'static,
+    S: Send + 'static,
+{
+    tokio::pin!(v6_lookup, v4_lookup);
+    let (mut v6_done, mut v4_done) = (false, false);
+    let (mut v6, mut v4) = (VecDeque::new(), VecDeque::new());
+    let mut prefer_v6 = true;
+    // connecting starts once AAAA arrives, or the resolution delay has passed after A
+    let mut started = false;
+    let mut resolution_deadline: Option<Instant> = None;
+    let mut next_attempt_at = Instant::now();
+    let mut attempts = JoinSet::new(); // dropping it aborts the losers
+    let mut last_err = None;
+
+    loop {
+        let pending = !v6.is_empty() || !v4.is_empty();
+        if started && pending && (attempts.is_empty() || Instant::now() >= next_attempt_at) {
+            if let Some(addr) = next_addr(&mut v6, &mut v4, &mut prefer_v6) {
+                let attempt = connect(addr);
+                attempts.spawn(async move { (addr, attempt.await) });
+                next_attempt_at = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+                continue;
+            }
+        }
+        if attempts.is_empty() && !pending && v6_done && v4_done {
+            return Err(last_err.unwrap_or(io::Error::new(io::ErrorKind::NotFound, "no address was resolved")));
+        }
+
+        tokio::select! {
+            ret = &mut v6_lookup, if !v6_done => {
+                v6_done = true;
+                match ret {
+                    Ok(addrs) => v6.extend(addrs),
+                    Err(e) => last_err = Some(e),
+                }
+                started = true;
+            }
+            ret = &mut v4_lookup, if !v4_done => {
+                v4_done = true;
+                match ret {
+                    Ok(addrs) => v4.extend(addrs),
+                    Err(e) => last_err = Some(e),
+                }
+                if v6_done {
+                    started = true;
+                } else {
+                    resolution_deadline = Some(Instant::now() + RESOLUTION_DELAY);
+                }
+            }
+            _ = sleep_until(resolution_deadline.unwrap_or_else(Instant::now)), if !started && resolution_deadline.is_some() => {
+                started = true;
+            }
+            Some(ret) = attempts.join_next(), if !attempts.is_empty() => {
+                match ret {
+                    Ok((addr, Ok(stream))) => return Ok((addr, stream)),
+                    Ok((_, Err(e))) => last_err = Some(e),
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                }
+                // a failed attempt lets the next one start right away
+                next_attempt_at = Instant::now();
+            }
+            _ = sleep_until(next_attempt_at), if started && pending && !attempts.is_empty() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tokio::time::{sleep, Duration, Instant};
+    use crate::dial::{connect, race};
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broken_ipv6_route() {
+        let v6 = async { Ok(addrs(&["[2001:db8::1]:443", "[2001:db8::2]:443"])) };
+        let v4 = async { Ok(addrs(&["192.0.2.1:443"])) };
+        let tried = Arc::new(Mutex::new(vec![]));
+        let start = Instant::now();
+        let (addr, _) = race(v6, v4, |addr: SocketAddr| {
+            tried.lock().unwrap().push(addr);
+            async move {
+                if addr.is_ipv6() {
+                    // a black hole
+                    sleep(Duration::from_secs(120)).await;
+                }
+                io::Result::Ok(())
+            }
+        }).await.unwrap();
+        assert_eq!(addr, "192.0.2.1:443".parse().unwrap());
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+        assert_eq!(*tried.lock().unwrap(), addrs(&["[2001:db8::1]:443", "192.0.2.1:443"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interleave_and_fail_fast() {
+        let v6 = async { Ok(addrs(&["[2001:db8::1]:443", "[2001:db8::2]:443"])) };
+        let v4 = async { Ok(addrs(&["192.0.2.1:443", "192.0.2.2:443"])) };
+        let tried = Arc::new(Mutex::new(vec![]));
+        let start = Instant::now();
+        let (addr, _) = race(v6, v4, |addr: SocketAddr| {
+            tried.lock().unwrap().push(addr);
+            async move {
+                if addr == "192.0.2.2:443".parse().unwrap() {
+                    Ok(())
+                } else {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                }
+            }
+        }).await.unwrap();
+        assert_eq!(addr, "192.0.2.2:443".parse().unwrap());
+        // refused attempts don't wait for the attempt delay
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert_eq!(*tried.lock().unwrap(), addrs(&["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443", "192.0.2.2:443"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resolution_delay() {
+        // AAAA arrives 30 ms after A, inside the resolution delay, so it is still tried first
+        let v6 = async {
+            sleep(Duration::from_millis(30)).await;
+            Ok(addrs(&["[2001:db8::1]:443"]))
+        };
+        let v4 = async { Ok(addrs(&["192.0.2.1:443"])) };
+        let (addr, _) = race(v6, v4, |_| async { io::Result::Ok(()) }).await.unwrap();
+        assert_eq!(addr, "[2001:db8::1]:443".parse().unwrap());
+
+        // AAAA never arrives
+        let v6 = async {
+            sleep(Duration::from_secs(10)).await;
+            Ok(addrs(&["[2001:db8::1]:443"]))
+        };
+        let v4 = async { Ok(addrs(&["192.0.2.1:443"])) };
+        let start = Instant::now();
+        let (addr, _) = race(v6, v4, |_| async { io::Result::Ok(()) }).await.unwrap();
+        assert_eq!(addr, "192.0.2.1:443".parse().unwrap());
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn all_failed() {
+        let v6 = async { Err(io::Error::from(io::ErrorKind::NotFound)) };
+        let v4 = async { Ok(addrs(&["192.0.2.1:443"])) };
+        let ret = race(v6, v4, |_| async { io::Result::<()>::Err(io::Error::from(io::ErrorKind::ConnectionRefused)) }).await;
+        assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn connect_localhost() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect("localhost", port).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+}
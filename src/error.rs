@@ -12,6 +12,14 @@ pub enum Error {
     AddrTypeUnsupported(u8),
     NotImplemented,
     ServerRefusedAuth,
+    Rejected, // the destination was rejected by rules
+    HandshakeTimeout,
+    Unauthenticated, // the peer failed the handshake of nexeld
+    ConnectTimeout,
+    TlsHandshakeTimeout,
+    NoiseHandshakeTimeout,
+    IdleTimeout,
+    LifetimeExceeded,
     IoErr(io::Error),
     Other(String),
 }
@@ -27,6 +35,14 @@ impl Display for Error {
             Error::AddrTypeUnsupported(_) => write!(f, "the addr type was invalid"),
             Error::NotImplemented => write!(f, "protocol was not implemented"),
             Error::ServerRefusedAuth => write!(f, "server has refused the client auth"),
+            Error::Rejected => write!(f, "the destination was rejected by rules"),
+            Error::HandshakeTimeout => write!(f, "receiving the request timed out"),
+            Error::Unauthenticated => write!(f, "the peer was not authenticated"),
+            Error::ConnectTimeout => write!(f, "connecting to the remote timed out"),
+            Error::TlsHandshakeTimeout => write!(f, "the tls handshake timed out"),
+            Error::NoiseHandshakeTimeout => write!(f, "the noise handshake timed out"),
+            Error::IdleTimeout => write!(f, "the connection was idle for too long"),
+            Error::LifetimeExceeded => write!(f, "the connection exceeded its lifetime"),
             Error::IoErr(e) => write!(f, "{}", e),
             Error::Other(desc) => write!(f, "{desc}"),
         }
@@ -0,0 +1,156 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, Bytes};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use crate::config::{self, Timeouts};
+use crate::error::Error;
+use crate::Result;
+
+/// a stream whose first bytes were read already, they're read again before the rest of it.
+pub struct Rewind<RW> {
+    prefix: Bytes,
+    inner: RW,
+}
+
+impl<RW> Rewind<RW> {
+    pub fn new(prefix: impl Into<Bytes>, inner: RW) -> Rewind<RW> {
+        Rewind { prefix: prefix.into(), inner }
+    }
+
+    pub fn get_ref(&self) -> &RW {
+        &self.inner
+    }
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Rewind<RW> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for Rewind<RW> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// a stream that keeps a copy of the bytes read from it, so that they can be handed to the fallback.
+pub struct Recorder<RW> {
+    inner: RW,
+    recorded: Vec<u8>,
+}
+
+impl<RW> Recorder<RW> {
+    pub fn new(inner: RW) -> Recorder<RW> {
+        Recorder { inner, recorded: Vec::new() }
+    }
+
+    pub fn into_recorded(self) -> Vec<u8> {
+        self.recorded
+    }
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Recorder<RW> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.recorded.extend_from_slice(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for Recorder<RW> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// hands a connection that isn't a tunnel over to the backend, such as a local web server,
+/// so that a probe sees the site instead of a proxy. the bytes read from it already are sent first.
+pub async fn forward<RW>(mut stream: RW, read: &[u8], backend: &str, timeouts: &Timeouts) -> Result<()>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut remote = config::within(timeouts.connect(), Error::ConnectTimeout, async {
+        Ok(TcpStream::connect(backend).await?)
+    }).await?;
+    info!("[FALLBACK] backend = {}, read = {}", backend, read.len());
+    remote.write_all(read).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+    Ok(())
+}
+
+/// reads the connection until the peer gives up or the handshake timeout passes, and replies nothing.
+/// it looks like a server waiting for more data rather than one that closes on a bad request.
+pub async fn silence<R>(stream: &mut R, timeouts: &Timeouts)
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let drain = async { Ok(tokio::io::copy(stream, &mut tokio::io::sink()).await?) };
+    let _ = config::within(timeouts.handshake(), Error::HandshakeTimeout, drain).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::config::Timeouts;
+    use crate::fallback::{forward, Rewind};
+
+    #[tokio::test]
+    async fn rewind_then_forward() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            let mut request = [0u8; 16];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"GET / HTTP/1.1\r\n");
+            socket.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut read = [0u8; 4];
+            socket.read_exact(&mut read).await.unwrap();
+            let mut rewind = Rewind::new(read.to_vec(), &mut socket);
+            let mut again = [0u8; 4];
+            rewind.read_exact(&mut again).await.unwrap();
+            assert_eq!(&again, b"GET ");
+            forward(socket, &read, &backend_addr, &Timeouts::default()).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n");
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+use url::Url;
+use crate::error::Error;
+use crate::outbound::{Outbounds, ProxyCfg};
+use crate::{upstream, Result};
+
+/// measures the latency of a HTTP HEAD request to the url that is tunneled through the proxy.
+pub async fn url_test(outbounds: &Outbounds, proxy: &ProxyCfg, url: &str, limit: Duration) -> Result<Duration> {
+    let url = Url::parse(url).map_err(|e| Error::Other(format!("bad test url: {e}")))?;
+    if url.scheme() != "http" {
+        return Err(Error::Other(format!("test url scheme {} was not supported", url.scheme())));
+    }
+    let host = url.host_str().ok_or(Error::Other("test url has no host".to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let start = Instant::now();
+    let probe = async {
+        let mut remote = outbounds.connect(proxy).await?;
+        upstream::handshake(proxy, &mut remote, host, port).await?;
+
+        let head = format!("HEAD {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+        remote.write_all(head.as_bytes()).await?;
+        remote.flush().await?;
+        upstream::expect_status(&mut remote, &["2", "3"]).await
+    };
+    match timeout(limit, probe).await {
+        Ok(Ok(_)) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(Error::Other(format!("health check of {} time out", proxy.name()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::health::url_test;
+    use crate::outbound::{Health, Outbounds, ProxyCfg};
+    use crate::test_util::{outbounds, proxy_name, spawn_h2_server, spawn_quic_server, spawn_server, spawn_with};
+    use crate::tls::tests::{path, write_pki};
+
+    /// a HTTP stand-in that answers every request with 204.
+    async fn spawn_http() -> u16 {
+        let (port, _) = spawn_with(|mut socket| async move {
+            let mut buf = vec![0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+        }).await;
+        port
+    }
+
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn url_test_through_proxy() {
+        let server = spawn_server().await;
+        let http = spawn_http().await;
+        let url = format!("http://127.0.0.1:{http}/generate_204");
+
+        let outbounds = Outbounds::new();
+        let alive = ProxyCfg::new("127.0.0.1", server, "");
+        assert!(url_test(&outbounds, &alive, &url, Duration::from_secs(5)).await.is_ok());
+
+        let dead = ProxyCfg::new("127.0.0.1", closed_port().await, "");
+        assert!(url_test(&outbounds, &dead, &url, Duration::from_secs(5)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn check_groups() {
+        let server = spawn_server().await;
+        let http = spawn_http().await;
+        let dead = closed_port().await;
+        let outbounds = Arc::new(outbounds(&format!(r#"
+proxies:
+  - name: dead
+    server: 127.0.0.1
+    port: {dead}
+  - name: alive
+    server: 127.0.0.1
+    port: {server}
+proxy-groups:
+  - name: auto
+    type: url-test
+    proxies: [dead, alive]
+    url: http://127.0.0.1:{http}/
+  - name: backup
+    type: fallback
+    proxies: [dead, alive]
+    url: http://127.0.0.1:{http}/
+"#)).unwrap());
+        // nothing is known yet, the first member is used
+        assert_eq!(proxy_name(outbounds.resolve("backup", "nexel.cc").unwrap()), "dead");
+
+        outbounds.check_group("auto").await;
+        assert_eq!(outbounds.health("dead"), Some(Health::Dead));
+        assert!(matches!(outbounds.health("alive"), Some(Health::Alive(_))));
+        assert_eq!(proxy_name(outbounds.resolve("auto", "nexel.cc").unwrap()), "alive");
+        assert_eq!(proxy_name(outbounds.resolve("backup", "nexel.cc").unwrap()), "alive");
+    }
+
+    #[tokio::test]
+    async fn check_quic_and_h2_members() {
+        let dir = std::env::temp_dir().join(format!("nexel-health-{}", uuid::Uuid::new_v4()));
+        write_pki(&dir);
+        let http = spawn_http().await;
+        let dead = closed_port().await;
+        let (quic, _) = spawn_quic_server(&dir);
+        let (h2, _) = spawn_h2_server(&dir).await;
+        let ca = path(&dir, "ca.crt");
+        let outbounds = Arc::new(outbounds(&format!(r#"
+proxies:
+  - {{name: dead, server: 127.0.0.1, port: {dead}}}
+  - {{name: quic, server: 127.0.0.1, port: {quic}, tls: true, cert: '{ca}', sni: localhost, quic: true}}
+  - {{name: h2, server: 127.0.0.1, port: {h2}, tls: true, cert: '{ca}', sni: localhost, h2: true}}
+proxy-groups:
+  - name: backup
+    type: fallback
+    proxies: [dead, quic, h2]
+    url: http://127.0.0.1:{http}/
+"#)).unwrap());
+        outbounds.check_group("backup").await;
+        assert_eq!(outbounds.health("dead"), Some(Health::Dead));
+        assert!(matches!(outbounds.health("quic"), Some(Health::Alive(_))));
+        assert!(matches!(outbounds.health("h2"), Some(Health::Alive(_))));
+        assert_eq!(proxy_name(outbounds.resolve("backup", "nexel.cc").unwrap()), "quic");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::Bytes;
+use h2::client::SendRequest;
+use h2::ext::Protocol;
+use h2::{RecvStream, SendStream};
+use http::{Method, Request, Response, StatusCode};
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::error::Error;
+use crate::Result;
+
+/// the ALPN id that nexel negotiates with nexeld for the HTTP/2 transport.
+pub const ALPN: &[u8] = b"h2";
+/// the `:protocol` of the extended CONNECT requests that carry the tunnels.
+pub const PROTOCOL: &str = "nexel";
+
+impl From<h2::Error> for Error {
+    fn from(value: h2::Error) -> Self {
+        if value.is_io() {
+            Error::IoErr(value.into_io().unwrap())
+        } else {
+            Error::Other(value.to_string())
+        }
+    }
+}
+
+/// a tunnel carried in the data frames of a CONNECT stream.
+pub struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    buf: Bytes, // received but not read yet
+}
+
+impl H2Stream {
+    fn new(send: SendStream<Bytes>, recv: RecvStream) -> H2Stream {
+        H2Stream { send, recv, buf: Bytes::new() }
+    }
+}
+
+fn io_error(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        e.into_io().unwrap()
+    } else {
+        io::Error::other(e)
+    }
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.buf.is_empty() {
+            match ready!(self.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = self.recv.flow_control().release_capacity(data.len());
+                    self.buf = data;
+                }
+                Some(Err(e)) if e.reason() == Some(h2::Reason::NO_ERROR) => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.buf.len().min(buf.remaining());
+        buf.put_slice(&self.buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.send.reserve_capacity(buf.len());
+        match ready!(self.send.poll_capacity(cx)) {
+            Some(Ok(n)) => {
+                let n = n.min(buf.len());
+                self.send.send_data(Bytes::copy_from_slice(&buf[..n]), false).map_err(io_error)?;
+                Poll::Ready(Ok(n))
+            }
+            Some(Err(e)) => Poll::Ready(Err(io_error(e))),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the stream may be reset by the peer already
+        let _ = self.send.send_data(Bytes::new(), true);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// starts a HTTP/2 connection to nexeld, the connection is driven in the background.
+pub async fn handshake<S>(io: S) -> Result<SendRequest<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send, conn) = h2::client::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            error!("[H2] connection failed: {}", e);
+        }
+    });
+    Ok(send)
+}
+
+/// opens a tunnel as an extended CONNECT stream of the connection.
+pub async fn open(send: SendRequest<Bytes>, authority: &str) -> Result<H2Stream> {
+    let mut send = send.ready().await?;
+    let mut req = Request::builder()
+        .method(Method::CONNECT)
+        .uri(format!("https://{authority}/"))
+        .body(())
+        .map_err(|e| Error::Other(e.to_string()))?;
+    req.extensions_mut().insert(Protocol::from_static(PROTOCOL));
+    let (resp, stream) = send.send_request(req, false)?;
+    let resp = resp.await?;
+    if resp.status() != StatusCode::OK {
+        return Err(Error::Other(format!("unexpected response status {}", resp.status().as_u16())));
+    }
+    Ok(H2Stream::new(stream, resp.into_body()))
+}
+
+/// accepts the CONNECT streams of a HTTP/2 connection and hands each one over as a tunnel.
+pub async fn serve<S, F>(io: S, mut handle: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(H2Stream),
+{
+    let mut conn = h2::server::Builder::new().enable_connect_protocol().handshake(io).await?;
+    info!("[H2] connection started");
+    while let Some(accepted) = conn.accept().await {
+        let (req, mut respond) = accepted?;
+        let tunnel = req.method() == Method::CONNECT
+            && req.extensions().get::<Protocol>().is_some_and(|protocol| protocol.as_str() == PROTOCOL);
+        if !tunnel {
+            let resp = Response::builder().status(StatusCode::NOT_FOUND).body(()).unwrap();
+            let _ = respond.send_response(resp, true);
+            continue;
+        }
+        let resp = Response::builder().status(StatusCode::OK).body(()).unwrap();
+        let send = respond.send_response(resp, false)?;
+        handle(H2Stream::new(send, req.into_body()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpStream;
+    use crate::connection::Connection;
+    use crate::http2::{handshake, open, serve};
+    use crate::outbound::Outbounds;
+    use crate::test_util::{assert_echo, h2_proxy, spawn_echo, spawn_h2_server, spawn_with};
+    use crate::tls::tests::write_pki;
+    use crate::upstream::http_connect;
+
+    #[tokio::test]
+    async fn tunnels_share_a_connection() {
+        let echo_port = spawn_echo().await;
+        let (server, accepted) = spawn_with(|socket| serve(socket, |stream| {
+            tokio::spawn(async move { Connection::new(stream, None).run_on_server().await });
+        })).await;
+
+        let socket = tokio::net::TcpStream::connect(("127.0.0.1", server)).await.unwrap();
+        let send = handshake(socket).await.unwrap();
+        let mut streams = Vec::new();
+        for i in 0..3u8 {
+            let mut stream = open(send.clone(), "localhost").await.unwrap();
+            http_connect(&mut stream, "127.0.0.1", echo_port, None).await.unwrap();
+            let data = vec![i; 100_000];
+            stream.write_all(&data).await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+            streams.push(stream);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    /// forwards to the port, the connections relayed so far are frozen by setting their flags:
+    /// they're kept open but nothing passes through them from then on.
+    async fn spawn_relay(port: u16) -> (u16, Arc<Mutex<Vec<Arc<AtomicBool>>>>) {
+        async fn pipe(mut r: OwnedReadHalf, mut w: OwnedWriteHalf, frozen: Arc<AtomicBool>) {
+            let mut buf = vec![0u8; 16 * 1024];
+            while let Ok(n) = r.read(&mut buf).await {
+                if n == 0 || frozen.load(Ordering::SeqCst) {
+                    break;
+                }
+                if w.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+            std::future::pending::<()>().await;
+        }
+        let flags = Arc::new(Mutex::new(Vec::new()));
+        let conns = flags.clone();
+        let (relay, _) = spawn_with(move |socket| {
+            let frozen = Arc::new(AtomicBool::new(false));
+            conns.lock().unwrap().push(frozen.clone());
+            async move {
+                let upstream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                let (r1, w1) = socket.into_split();
+                let (r2, w2) = upstream.into_split();
+                tokio::join!(pipe(r1, w2, frozen.clone()), pipe(r2, w1, frozen));
+            }
+        }).await;
+        (relay, flags)
+    }
+
+    #[tokio::test]
+    async fn half_dead_connection() {
+        let dir = std::env::temp_dir().join(format!("nexel-h2-{}", uuid::Uuid::new_v4()));
+        write_pki(&dir);
+        let echo_port = spawn_echo().await;
+        let (server, accepted) = spawn_h2_server(&dir).await;
+        let (relay, conns) = spawn_relay(server).await;
+        let mut outbounds = Outbounds::new();
+        outbounds.set_timeouts(serde_yml::from_str("connect: 1").unwrap());
+        let proxy = h2_proxy(&dir, relay);
+        outbounds.insert_proxy(proxy.clone()).unwrap();
+
+        let mut stream = outbounds.connect(&proxy).await.unwrap();
+        http_connect(&mut stream, "127.0.0.1", echo_port, None).await.unwrap();
+        assert_echo(&mut stream).await;
+
+        // the cached connection stops answering, the next tunnel gives up on it in time and redials
+        for frozen in conns.lock().unwrap().iter() {
+            frozen.store(true, Ordering::SeqCst);
+        }
+        let start = Instant::now();
+        let mut stream = outbounds.connect(&proxy).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        http_connect(&mut stream, "127.0.0.1", echo_port, None).await.unwrap();
+        assert_echo(&mut stream).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
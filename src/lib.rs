@@ -3,4 +3,28 @@ pub mod connection;
 pub mod protocol;
 pub mod tls;
 pub mod rule;
+pub mod outbound;
+pub mod health;
+pub mod upstream;
+pub mod dial;
+pub mod config;
+pub mod auth;
+pub mod acl;
+pub mod mux;
+pub mod pool;
+pub mod ws;
+pub mod http2;
+pub mod quic;
+pub mod udp;
+pub mod shadowsocks;
+pub mod trojan;
+pub mod fallback;
+pub mod padding;
+pub mod noise;
+pub mod redir;
+#[cfg(target_os = "linux")]
+pub mod tproxy;
+pub mod sniff;
+#[cfg(test)]
+mod test_util;
 pub type Result<T> = std::result::Result<T, error::Error>;
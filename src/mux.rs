@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::info;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+
+/// sent by nexel in place of a request to turn the connection into a session, nexeld echoes it back.
+pub const PREFACE: [u8; 4] = [0, b'N', b'X', 1];
+
+const VERSION: u8 = 0;
+/// ver | type | flags | stream id | length
+const HEADER_LEN: usize = 12;
+/// how many bytes a stream may receive before the reader credits them back.
+const WINDOW: u32 = 256 * 1024;
+const MAX_FRAME: usize = 16 * 1024;
+/// nexeld resets the streams beyond it.
+const MAX_ACCEPTED_STREAMS: usize = 1024;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+struct Frame {
+    ty: u8,
+    flags: u16,
+    id: u32,
+    length: u32, // the body length of data, the window delta, the ping opaque or the go away code
+    body: Bytes,
+}
+
+impl Frame {
+    fn data(id: u32, flags: u16, body: Bytes) -> Frame {
+        Frame { ty: TYPE_DATA, flags, id, length: body.len() as u32, body }
+    }
+
+    fn control(ty: u8, flags: u16, id: u32, length: u32) -> Frame {
+        Frame { ty, flags, id, length, body: Bytes::new() }
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(VERSION);
+        buf.put_u8(self.ty);
+        buf.put_u16(self.flags);
+        buf.put_u32(self.id);
+        buf.put_u32(self.length);
+        buf.put_slice(&self.body);
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    recv_buf: BytesMut,
+    recv_window: u32,
+    consumed: u32, // read but not credited back yet
+    send_window: u32,
+    read_closed: bool,
+    write_closed: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState { recv_window: WINDOW, send_window: WINDOW, ..Default::default() }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, Arc<Mutex<StreamState>>>>,
+    tx: mpsc::UnboundedSender<Frame>,
+    next_id: AtomicU32,
+    next_ping: AtomicU32,
+    pings: Mutex<HashMap<u32, oneshot::Sender<()>>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.tx.send(frame).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// resets every stream, their buffered data can still be read.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let streams: Vec<_> = self.streams.lock().unwrap().drain().map(|(_, state)| state).collect();
+        for state in streams {
+            let mut state = state.lock().unwrap();
+            state.reset = true;
+            state.wake();
+        }
+        self.pings.lock().unwrap().clear();
+    }
+}
+
+/// a handle of a multiplexed session, the logical streams are opened on it.
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session {{ streams: {}, closed: {} }}", self.num_streams(), self.is_closed())
+    }
+}
+
+impl Session {
+    fn new(first_id: u32) -> (Session, mpsc::UnboundedReceiver<Frame>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let shared = Shared {
+            streams: Mutex::new(HashMap::new()),
+            tx,
+            next_id: AtomicU32::new(first_id),
+            next_ping: AtomicU32::new(0),
+            pings: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        };
+        (Session { shared: Arc::new(shared) }, rx)
+    }
+
+    /// runs the client side of a session in the background, the preface was exchanged already.
+    pub fn client<T>(transport: T) -> Session
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        // the client opens odd streams
+        let (session, rx) = Session::new(1);
+        let shared = session.shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = drive(transport, shared, rx, None).await {
+                info!("[MUX] session closed, error = {}", e);
+            }
+        });
+        session
+    }
+
+    /// the server side of a session, the future drives it and the streams that the client opens come out of the receiver.
+    pub fn server<T>(transport: T) -> (Session, mpsc::UnboundedReceiver<MuxStream>, impl Future<Output = io::Result<()>>)
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let (session, rx) = Session::new(2);
+        let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+        let driver = drive(transport, session.shared.clone(), rx, Some(accept_tx));
+        (session, accept_rx, driver)
+    }
+
+    pub fn open(&self) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::SeqCst);
+        let stream = MuxStream::new(id, self.shared.clone());
+        self.shared.streams.lock().unwrap().insert(id, stream.state.clone());
+        self.shared.send(Frame::data(id, FLAG_SYN, Bytes::new()))?;
+        Ok(stream)
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// the round trip time of a ping, an error if it isn't answered within the limit.
+    pub async fn ping(&self, limit: Duration) -> io::Result<Duration> {
+        let opaque = self.shared.next_ping.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.shared.pings.lock().unwrap().insert(opaque, tx);
+        let start = Instant::now();
+        self.shared.send(Frame::control(TYPE_PING, FLAG_SYN, 0, opaque))?;
+        match tokio::time::timeout(limit, rx).await {
+            Ok(Ok(_)) => Ok(start.elapsed()),
+            Ok(Err(_)) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            Err(_) => {
+                self.shared.pings.lock().unwrap().remove(&opaque);
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            }
+        }
+    }
+
+    /// tells the peer to go away and resets the streams.
+    pub fn close(&self) {
+        let _ = self.shared.send(Frame::control(TYPE_GO_AWAY, 0, 0, 0));
+        self.shared.close();
+    }
+}
+
+/// writes the queued frames and dispatches the received ones until either side goes away.
+async fn drive<T>(transport: T, shared: Arc<Shared>, mut rx: mpsc::UnboundedReceiver<Frame>,
+                  accept: Option<mpsc::UnboundedSender<MuxStream>>) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = tokio::io::split(transport);
+    let write = async {
+        let mut buf = BytesMut::with_capacity(MAX_FRAME + HEADER_LEN);
+        while let Some(frame) = rx.recv().await {
+            let mut go_away = frame.ty == TYPE_GO_AWAY;
+            frame.encode(&mut buf);
+            // batch what's queued into one write
+            while !go_away && buf.len() < MAX_FRAME {
+                let Ok(frame) = rx.try_recv() else { break };
+                go_away = frame.ty == TYPE_GO_AWAY;
+                frame.encode(&mut buf);
+            }
+            writer.write_all(&buf).await?;
+            writer.flush().await?;
+            buf.clear();
+            if go_away {
+                break;
+            }
+        }
+        Ok(())
+    };
+    let read = async {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            match reader.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let mut cursor = &header[..];
+            let (ver, ty, flags, id, length) = (cursor.get_u8(), cursor.get_u8(), cursor.get_u16(), cursor.get_u32(), cursor.get_u32());
+            if ver != VERSION {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown mux version"));
+            }
+            let body = if ty == TYPE_DATA {
+                if length as usize > MAX_FRAME {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "mux frame was too large"));
+                }
+                let mut body = vec![0u8; length as usize];
+                reader.read_exact(&mut body).await?;
+                Bytes::from(body)
+            } else {
+                Bytes::new()
+            };
+            if !dispatch(&shared, accept.as_ref(), Frame { ty, flags, id, length, body })? {
+                return Ok(());
+            }
+        }
+    };
+    let ret = tokio::select! {
+        ret = write => ret,
+        ret = read => ret,
+    };
+    shared.close();
+    ret
+}
+
+/// handles a received frame, false if the peer went away.
+fn dispatch(shared: &Arc<Shared>, accept: Option<&mpsc::UnboundedSender<MuxStream>>, frame: Frame) -> io::Result<bool> {
+    match frame.ty {
+        TYPE_DATA | TYPE_WINDOW_UPDATE => {
+            let state = if frame.flags & FLAG_SYN != 0 {
+                let Some(accept) = accept else {
+                    shared.send(Frame::control(TYPE_WINDOW_UPDATE, FLAG_RST, frame.id, 0))?;
+                    return Ok(true);
+                };
+                let stream = MuxStream::new(frame.id, shared.clone());
+                {
+                    let mut streams = shared.streams.lock().unwrap();
+                    if streams.contains_key(&frame.id) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "mux stream was opened twice"));
+                    }
+                    if streams.len() >= MAX_ACCEPTED_STREAMS {
+                        drop(streams);
+                        drop(stream);
+                        return Ok(true);
+                    }
+                    streams.insert(frame.id, stream.state.clone());
+                }
+                let state = stream.state.clone();
+                if accept.send(stream).is_err() {
+                    return Ok(false);
+                }
+                state
+            } else {
+                match shared.streams.lock().unwrap().get(&frame.id) {
+                    Some(state) => state.clone(),
+                    None => return Ok(true), // closed already
+                }
+            };
+            let mut state = state.lock().unwrap();
+            if frame.ty == TYPE_DATA {
+                if frame.length > state.recv_window {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "mux window was exceeded"));
+                }
+                state.recv_window -= frame.length;
+                state.recv_buf.extend_from_slice(&frame.body);
+            } else {
+                state.send_window = state.send_window.saturating_add(frame.length);
+            }
+            if frame.flags & FLAG_FIN != 0 {
+                state.read_closed = true;
+            }
+            if frame.flags & FLAG_RST != 0 {
+                state.reset = true;
+            }
+            state.wake();
+        }
+        TYPE_PING => {
+            if frame.flags & FLAG_SYN != 0 {
+                shared.send(Frame::control(TYPE_PING, FLAG_ACK, 0, frame.length))?;
+            } else if let Some(tx) = shared.pings.lock().unwrap().remove(&frame.length) {
+                let _ = tx.send(());
+            }
+        }
+        TYPE_GO_AWAY => return Ok(false),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown mux frame type")),
+    }
+    Ok(true)
+}
+
+/// a logical stream of a session.
+pub struct MuxStream {
+    id: u32,
+    state: Arc<Mutex<StreamState>>,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    fn new(id: u32, shared: Arc<Shared>) -> MuxStream {
+        MuxStream { id, state: Arc::new(Mutex::new(StreamState::new())), shared }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.recv_buf.is_empty() {
+            let n = buf.remaining().min(state.recv_buf.len());
+            buf.put_slice(&state.recv_buf.split_to(n));
+            state.consumed += n as u32;
+            if state.consumed >= WINDOW / 2 && !state.read_closed {
+                let delta = std::mem::take(&mut state.consumed);
+                state.recv_window += delta;
+                let _ = self.shared.send(Frame::control(TYPE_WINDOW_UPDATE, 0, self.id, delta));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if state.reset {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+        }
+        if state.read_closed {
+            return Poll::Ready(Ok(()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.reset || state.write_closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.send_window as usize).min(MAX_FRAME);
+        state.send_window -= n as u32;
+        self.shared.send(Frame::data(self.id, 0, Bytes::copy_from_slice(&buf[..n])))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the session writes the frames as soon as they are queued
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.write_closed && !state.reset {
+            state.write_closed = true;
+            self.shared.send(Frame::data(self.id, FLAG_FIN, Bytes::new()))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap();
+        let closed = state.read_closed && state.write_closed;
+        if !state.reset && !closed {
+            let _ = self.shared.send(Frame::control(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0));
+        }
+        drop(state);
+        self.shared.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// exchanges the preface over a fresh tunnel to nexeld and starts a session on it.
+pub async fn connect<T>(mut transport: T) -> io::Result<Session>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    transport.write_all(&PREFACE).await?;
+    transport.flush().await?;
+    let mut preface = [0u8; PREFACE.len()];
+    transport.read_exact(&mut preface).await?;
+    if preface != PREFACE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "server didn't accept the mux session"));
+    }
+    Ok(Session::client(transport))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::connection::Connection;
+    use crate::error::Error;
+    use crate::mux::{MuxStream, Session, WINDOW};
+    use crate::outbound::{MuxCfg, Outbounds, ProxyCfg};
+    use crate::test_util::{assert_echo, spawn_client, spawn_echo, spawn_with};
+    use crate::upstream::socks5_connect;
+
+    /// a client session and a server that echoes every stream.
+    fn echo_pair() -> Session {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let (_session, mut incoming, driver) = Session::server(server);
+            let accept = async {
+                while let Some(mut stream) = incoming.recv().await {
+                    tokio::spawn(async move {
+                        let (mut r, mut w) = tokio::io::split(&mut stream);
+                        let _ = tokio::io::copy(&mut r, &mut w).await;
+                        let _ = w.shutdown().await;
+                    });
+                }
+            };
+            tokio::select! {
+                _ = driver => {},
+                _ = accept => {},
+            }
+        });
+        Session::client(client)
+    }
+
+    async fn echo(stream: &mut MuxStream, data: &[u8]) -> Vec<u8> {
+        let (mut r, mut w) = tokio::io::split(stream);
+        let write = async {
+            w.write_all(data).await.unwrap();
+            w.shutdown().await.unwrap();
+        };
+        let mut ret = vec![];
+        let read = r.read_to_end(&mut ret);
+        let (_, n) = tokio::join!(write, read);
+        n.unwrap();
+        ret
+    }
+
+    #[tokio::test]
+    async fn streams_share_a_session() {
+        let session = echo_pair();
+        let mut a = session.open().unwrap();
+        let mut b = session.open().unwrap();
+        assert_eq!(session.num_streams(), 2);
+        // more than a window so that the writer waits for the updates
+        let big: Vec<u8> = (0..WINDOW as usize * 4).map(|i| i as u8).collect();
+        let (ra, rb) = tokio::join!(echo(&mut a, &big), echo(&mut b, b"nexel"));
+        assert_eq!(ra, big);
+        assert_eq!(rb, b"nexel");
+        drop((a, b));
+        assert_eq!(session.num_streams(), 0);
+        assert!(session.ping(Duration::from_secs(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn close_resets_streams() {
+        let session = echo_pair();
+        let mut stream = session.open().unwrap();
+        stream.write_all(b"nexel").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        session.close();
+        assert!(session.is_closed());
+        assert!(stream.read(&mut buf).await.is_err());
+        assert!(stream.write_all(b"nexel").await.is_err());
+        assert!(session.open().is_err());
+    }
+
+    #[tokio::test]
+    async fn tunnel_over_sessions() {
+        let echo = spawn_echo().await;
+        // nexeld that counts its connections
+        let (server, accepted) = spawn_with(|socket| async move { Connection::new(socket, None).run_on_server().await }).await;
+
+        let mut outbounds = Outbounds::new();
+        let mut proxy = ProxyCfg::new("127.0.0.1", server, "");
+        proxy.set_mux(MuxCfg::new(1, 2));
+        outbounds.insert_proxy(proxy).unwrap();
+        let client = spawn_client(Some(Arc::new(outbounds))).await;
+
+        // the third stream doesn't fit into the session and gets a connection of its own
+        let mut streams = vec![];
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(("127.0.0.1", client)).await.unwrap();
+            socks5_connect(&mut stream, "127.0.0.1", echo, None).await.unwrap();
+            assert_echo(&mut stream).await;
+            streams.push(stream);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stalled_preface() {
+        // a nexeld that accepts and never answers
+        let (server, _) = spawn_with(|socket| async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(socket);
+        }).await;
+        let mut outbounds = Outbounds::new();
+        outbounds.set_timeouts(serde_yml::from_str("handshake: 1").unwrap());
+        let mut proxy = ProxyCfg::new("127.0.0.1", server, "");
+        proxy.set_mux(MuxCfg::new(1, 2));
+        outbounds.insert_proxy(proxy.clone()).unwrap();
+        let ret = outbounds.connect(&proxy).await;
+        assert!(matches!(ret, Err(Error::HandshakeTimeout)));
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashSet;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use aws_lc_rs::agreement::{PrivateKey, X25519};
+use aws_lc_rs::encoding::{AsBigEndian, Curve25519SeedBin};
+use base64::Engine;
+use bytes::{Buf, BufMut, BytesMut};
+use serde::Deserialize;
+use snow::params::NoiseParams;
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use crate::error::Error;
+use crate::Result;
+
+const PROTOCOL: &str = "Noise_IK_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"nexel";
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// e | s | payload
+const INITIATION_LEN: usize = KEY_LEN + KEY_LEN + TAG_LEN + TAG_LEN;
+/// e | payload
+const RESPONSE_LEN: usize = KEY_LEN + TAG_LEN;
+const MAX_MESSAGE: usize = 0xffff;
+const READ_CHUNK: usize = 16 * 1024;
+
+/// a static X25519 keypair, the keys are written in base64.
+#[derive(Debug)]
+pub struct Keypair {
+    private: PrivateKey,
+    public: [u8; KEY_LEN],
+}
+
+impl Keypair {
+    pub fn generate() -> Keypair {
+        Keypair::new(PrivateKey::generate(&X25519).unwrap())
+    }
+
+    pub fn from_private(key: &str) -> Result<Keypair> {
+        let key = PrivateKey::from_private_key(&X25519, &decode_key(key)?)
+            .map_err(|e| Error::Other(format!("bad noise private key: {e}")))?;
+        Ok(Keypair::new(key))
+    }
+
+    fn new(private: PrivateKey) -> Keypair {
+        let public = private.compute_public_key().unwrap().as_ref().try_into().unwrap();
+        Keypair { private, public }
+    }
+
+    fn seed(&self) -> Curve25519SeedBin<'static> {
+        self.private.as_be_bytes().unwrap()
+    }
+
+    pub fn private_key(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.seed().as_ref())
+    }
+
+    pub fn public_key(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.public)
+    }
+}
+
+/// decodes a base64 key of X25519.
+pub fn decode_key(key: &str) -> Result<[u8; KEY_LEN]> {
+    let key = base64::engine::general_purpose::STANDARD.decode(key.trim())
+        .map_err(|e| Error::Other(format!("bad noise key: {e}")))?;
+    key.try_into().map_err(|_| Error::Other(format!("a noise key is {KEY_LEN} bytes")))
+}
+
+fn params() -> NoiseParams {
+    PROTOCOL.parse().unwrap()
+}
+
+fn failed(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("noise message failed: {e}"))
+}
+
+async fn read_message<R: AsyncRead + Unpin>(stream: &mut R, len: usize) -> Result<Vec<u8>> {
+    if stream.read_u16().await? as usize != len {
+        return Err(Error::Unauthenticated);
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(stream: &mut W, message: &[u8]) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(2 + message.len());
+    buf.put_u16(message.len() as u16);
+    buf.put_slice(message);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// the noise transport of a nexel proxy, it takes the place of tls when no certificates are managed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NoiseCfg {
+    server_key: String, // the public key of nexeld
+    #[serde(default)]
+    private_key: Option<String>, // of the client, a random one is taken without it
+}
+
+impl NoiseCfg {
+    pub fn new(server_key: &str) -> NoiseCfg {
+        NoiseCfg { server_key: server_key.to_string(), private_key: None }
+    }
+
+    pub fn set_private_key(&mut self, key: &str) {
+        self.private_key = Some(key.to_string());
+    }
+
+    pub fn server_key(&self) -> Result<[u8; KEY_LEN]> {
+        decode_key(&self.server_key)
+    }
+
+    pub fn keypair(&self) -> Result<Keypair> {
+        match &self.private_key {
+            Some(key) => Keypair::from_private(key),
+            None => Ok(Keypair::generate()),
+        }
+    }
+}
+
+/// runs the handshake as the initiator, nexeld is verified by its public key.
+/// -> e, es, s, ss
+/// <- e, ee, se
+pub async fn connect<RW>(mut stream: RW, local: &Keypair, server: &[u8; KEY_LEN]) -> Result<NoiseStream<RW>>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    let seed = local.seed();
+    let mut state = Builder::new(params()).local_private_key(seed.as_ref()).remote_public_key(server)
+        .prologue(PROLOGUE).build_initiator().map_err(failed)?;
+    let mut message = [0u8; INITIATION_LEN];
+    state.write_message(&[], &mut message).map_err(failed)?;
+    write_message(&mut stream, &message).await?;
+
+    let response = read_message(&mut stream, RESPONSE_LEN).await?;
+    state.read_message(&response, &mut []).map_err(|_| Error::ServerRefusedAuth)?;
+    Ok(NoiseStream::new(stream, state)?)
+}
+
+/// the noise inbound of nexeld, the clients are known by their public keys.
+pub struct Inbound {
+    keypair: Keypair,
+    clients: HashSet<[u8; KEY_LEN]>, // anyone is let in when it's empty
+}
+
+impl Inbound {
+    pub fn new(keypair: Keypair) -> Inbound {
+        Inbound { keypair, clients: HashSet::new() }
+    }
+
+    pub fn allow(&mut self, client: [u8; KEY_LEN]) {
+        self.clients.insert(client);
+    }
+
+    /// runs the handshake as the responder, returns the stream and the public key of the client.
+    /// a client that fails it gets no response.
+    pub async fn accept<RW>(&self, mut stream: RW) -> Result<(NoiseStream<RW>, String)>
+    where
+        RW: AsyncRead + AsyncWrite + Unpin,
+    {
+        let seed = self.keypair.seed();
+        let mut state = Builder::new(params()).local_private_key(seed.as_ref())
+            .prologue(PROLOGUE).build_responder().map_err(failed)?;
+        let initiation = read_message(&mut stream, INITIATION_LEN).await?;
+        state.read_message(&initiation, &mut []).map_err(|_| Error::Unauthenticated)?;
+        let client: [u8; KEY_LEN] = state.get_remote_static().unwrap().try_into().unwrap();
+        if !self.clients.is_empty() && !self.clients.contains(&client) {
+            return Err(Error::Unauthenticated);
+        }
+
+        let mut message = [0u8; RESPONSE_LEN];
+        state.write_message(&[], &mut message).map_err(failed)?;
+        write_message(&mut stream, &message).await?;
+        Ok((NoiseStream::new(stream, state)?, base64::engine::general_purpose::STANDARD.encode(client)))
+    }
+}
+
+/// a stream of noise transport messages, each one is len(u16) | sealed data.
+pub struct NoiseStream<RW> {
+    inner: RW,
+    transport: TransportState,
+    wbuf: BytesMut,  // sealed but not written yet
+    rbuf: BytesMut,  // read but not opened yet
+    plain: BytesMut, // opened but not read yet
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> NoiseStream<RW> {
+    fn new(inner: RW, handshake: HandshakeState) -> io::Result<NoiseStream<RW>> {
+        let transport = handshake.into_transport_mode().map_err(failed)?;
+        Ok(NoiseStream { inner, transport, wbuf: BytesMut::new(), rbuf: BytesMut::new(), plain: BytesMut::new() })
+    }
+
+    /// opens the next message in rbuf, false if it isn't received in full yet.
+    fn open_next(&mut self) -> io::Result<bool> {
+        if self.rbuf.len() < 2 {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([self.rbuf[0], self.rbuf[1]]) as usize;
+        if self.rbuf.len() < 2 + len {
+            return Ok(false);
+        }
+        self.rbuf.advance(2);
+        let sealed = self.rbuf.split_to(len);
+        let mut plain = [0u8; MAX_MESSAGE];
+        let n = self.transport.read_message(&sealed, &mut plain).map_err(failed)?;
+        self.plain.extend_from_slice(&plain[..n]);
+        Ok(true)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncRead for NoiseStream<RW> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.plain.is_empty() {
+                let n = this.plain.len().min(buf.remaining());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.open_next()? {
+                continue;
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return if this.rbuf.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.rbuf.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncWrite for NoiseStream<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_MESSAGE - TAG_LEN);
+        let mut sealed = [0u8; MAX_MESSAGE];
+        let len = this.transport.write_message(&buf[..n], &mut sealed).map_err(failed)?;
+        this.wbuf.put_u16(len as u16);
+        this.wbuf.put_slice(&sealed[..len]);
+        // sent right away when the inner stream can take it, the rest goes with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::error::Error;
+    use crate::noise::{connect, decode_key, Inbound, Keypair};
+
+    #[tokio::test]
+    async fn handshake_and_transport() {
+        let server = Keypair::generate();
+        let server_key = decode_key(&server.public_key()).unwrap();
+        let client = Keypair::from_private(&Keypair::generate().private_key()).unwrap();
+        let client_key = client.public_key();
+        let mut inbound = Inbound::new(server);
+        inbound.allow(decode_key(&client_key).unwrap());
+
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let accepted = tokio::spawn(async move {
+            let (mut stream, peer) = inbound.accept(far).await.unwrap();
+            let mut request = vec![0u8; 100_000];
+            stream.read_exact(&mut request).await.unwrap();
+            assert!(request.iter().enumerate().all(|(i, b)| *b == i as u8));
+            stream.write_all(b"pong").await.unwrap();
+            stream.flush().await.unwrap();
+            peer
+        });
+        let mut stream = connect(near, &client, &server_key).await.unwrap();
+        let request: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        stream.write_all(&request).await.unwrap();
+        stream.flush().await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+        assert_eq!(accepted.await.unwrap(), client_key);
+    }
+
+    #[tokio::test]
+    async fn unknown_keys() {
+        let server = Keypair::generate();
+        let server_key = decode_key(&server.public_key()).unwrap();
+        let mut inbound = Inbound::new(server);
+        inbound.allow(decode_key(&Keypair::generate().public_key()).unwrap());
+
+        // a client that isn't allowed gets no response
+        let (near, far) = tokio::io::duplex(1024);
+        let accepted = tokio::spawn(async move { inbound.accept(far).await.map(|_| ()) });
+        let connected = connect(near, &Keypair::generate(), &server_key).await;
+        assert!(matches!(accepted.await.unwrap(), Err(Error::Unauthenticated)));
+        assert!(connected.is_err());
+
+        // nor does one that takes another key for the server's
+        let inbound = Inbound::new(Keypair::generate());
+        let (near, far) = tokio::io::duplex(1024);
+        let accepted = tokio::spawn(async move { inbound.accept(far).await.map(|_| ()) });
+        let connected = connect(near, &Keypair::generate(), &server_key).await;
+        assert!(matches!(accepted.await.unwrap(), Err(Error::Unauthenticated)));
+        assert!(connected.is_err());
+    }
+}
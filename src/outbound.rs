@@ -0,0 +1,1048 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bytes::Bytes;
+use h2::client::SendRequest;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use crate::error::Error;
+use crate::config::Timeouts;
+use crate::noise::NoiseCfg;
+use crate::padding::{PadStream, PaddingCfg};
+use crate::pool::{Pool, PoolCfg};
+use crate::quic::{Association, Datagrams, QuicStream};
+use crate::shadowsocks::{Method, SsStream};
+use crate::ws::WsCfg;
+use crate::tls::Trust;
+use crate::{auth, config, dial, health, http2, mux, noise, quic, shadowsocks, tls, udp, upstream, ws, Result};
+
+pub const DIRECT: &str = "DIRECT";
+pub const REJECT: &str = "REJECT";
+pub const PROXY: &str = "PROXY";
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Nexel,
+    Socks5,
+    Http,
+    #[serde(alias = "ss")]
+    Shadowsocks,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProxyCfg {
+    name: String,
+    #[serde(rename = "server")]
+    proxy_srv_host: String,
+    #[serde(rename = "port")]
+    proxy_srv_port: u16,
+    #[serde(default)]
+    protocol: Protocol,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default, rename = "cert")]
+    cert_path: String,
+    #[serde(default)]
+    sni: Option<String>,
+    #[serde(default, rename = "system-roots")]
+    system_roots: bool,
+    #[serde(default)]
+    ca: Vec<String>, // besides the cert
+    #[serde(default, rename = "pin-sha256")]
+    pins: Vec<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default, rename = "dialer-proxy")]
+    via: Option<String>, // the proxy that this one is dialed through
+    #[serde(default, rename = "client-cert")]
+    client_cert: Option<String>,
+    #[serde(default, rename = "client-key")]
+    client_key: Option<String>,
+    #[serde(default)]
+    mux: Option<MuxCfg>,
+    #[serde(default)]
+    pool: Option<PoolCfg>,
+    #[serde(default)]
+    ws: Option<WsCfg>,
+    #[serde(default)]
+    h2: bool,
+    #[serde(default)]
+    quic: bool,
+    #[serde(default)]
+    cipher: Option<Method>, // of shadowsocks, the password is its pre-shared key
+    #[serde(default)]
+    padding: Option<PaddingCfg>,
+    #[serde(default)]
+    noise: Option<NoiseCfg>,
+}
+
+/// the multiplexed sessions kept to a nexeld.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MuxCfg {
+    sessions: usize,
+    max_streams: usize, // per session
+}
+
+impl Default for MuxCfg {
+    fn default() -> Self {
+        MuxCfg { sessions: 2, max_streams: 16 }
+    }
+}
+
+impl MuxCfg {
+    pub fn new(sessions: usize, max_streams: usize) -> MuxCfg {
+        MuxCfg { sessions, max_streams }
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions
+    }
+}
+
+impl ProxyCfg {
+    pub fn new(h: &str, p: u16, cert: &str) -> ProxyCfg {
+        ProxyCfg {
+            name: PROXY.to_string(),
+            proxy_srv_host: h.to_string(),
+            proxy_srv_port: p,
+            protocol: Protocol::Nexel,
+            tls: !cert.is_empty(),
+            cert_path: cert.to_string(),
+            sni: None,
+            system_roots: false,
+            ca: Vec::new(),
+            pins: Vec::new(),
+            username: None,
+            password: None,
+            via: None,
+            client_cert: None,
+            client_key: None,
+            mux: None,
+            pool: None,
+            ws: None,
+            h2: false,
+            quic: false,
+            cipher: None,
+            padding: None,
+            noise: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn host(&self) -> &str {
+        &self.proxy_srv_host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.proxy_srv_port
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.proxy_srv_host, self.proxy_srv_port)
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// the server is verified with the trust, so the cert can be left empty when there's another one.
+    pub fn set_tls(&mut self, tls: bool) {
+        self.tls = tls;
+    }
+
+    pub fn cert(&self) -> &str {
+        &self.cert_path
+    }
+
+    pub fn set_sni(&mut self, sni: &str) {
+        self.sni = Some(sni.to_string());
+    }
+
+    /// the name presented in the TLS handshake, defaults to the dial host.
+    pub fn sni(&self) -> &str {
+        self.sni.as_deref().unwrap_or(&self.proxy_srv_host)
+    }
+
+    pub fn set_system_roots(&mut self, system_roots: bool) {
+        self.system_roots = system_roots;
+    }
+
+    pub fn add_pin(&mut self, pin: &str) {
+        self.pins.push(pin.to_string());
+    }
+
+    /// what the server is verified with, the cert and the CAs, the system roots and the pins.
+    pub fn trust(&self) -> Trust {
+        let mut trust = Trust::new();
+        for ca in std::iter::once(&self.cert_path).filter(|cert| !cert.is_empty()).chain(&self.ca) {
+            trust.add_ca(ca);
+        }
+        trust.set_system_roots(self.system_roots);
+        for pin in &self.pins {
+            trust.add_pin(pin);
+        }
+        trust
+    }
+
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_string());
+    }
+
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        self.username.as_deref().map(|u| (u, self.password.as_deref().unwrap_or("")))
+    }
+
+    pub fn set_client_identity(&mut self, cert: &str, key: &str) {
+        self.client_cert = Some(cert.to_string());
+        self.client_key = Some(key.to_string());
+    }
+
+    /// the certificate and private key paths that the client presents to the tls server.
+    pub fn client_identity(&self) -> Option<(&str, &str)> {
+        self.client_cert.as_deref().zip(self.client_key.as_deref())
+    }
+
+    pub fn set_mux(&mut self, mux: MuxCfg) {
+        self.mux = Some(mux);
+    }
+
+    /// only the nexel protocol is multiplexed, HTTP/2 and QUIC multiplex the tunnels by themselves.
+    pub fn mux(&self) -> Option<MuxCfg> {
+        self.mux.filter(|mux| self.protocol == Protocol::Nexel && !self.h2() && !self.quic() && mux.sessions > 0 && mux.max_streams > 0)
+    }
+
+    pub fn set_pool(&mut self, pool: PoolCfg) {
+        self.pool = Some(pool);
+    }
+
+    /// a multiplexed proxy doesn't need warm connections.
+    pub fn pool(&self) -> Option<PoolCfg> {
+        self.pool.filter(|pool| self.mux().is_none() && !self.h2() && !self.quic() && pool.min_idle() > 0)
+    }
+
+    pub fn set_ws(&mut self, ws: WsCfg) {
+        self.ws = Some(ws);
+    }
+
+    /// only nexeld is reached through a WebSocket.
+    pub fn ws(&self) -> Option<&WsCfg> {
+        self.ws.as_ref().filter(|_| self.protocol == Protocol::Nexel && !self.h2() && !self.quic())
+    }
+
+    pub fn set_h2(&mut self, h2: bool) {
+        self.h2 = h2;
+    }
+
+    /// the tunnels are carried as HTTP/2 streams, which takes tls to negotiate.
+    pub fn h2(&self) -> bool {
+        self.h2 && self.tls && self.protocol == Protocol::Nexel && !self.quic()
+    }
+
+    pub fn set_quic(&mut self, quic: bool) {
+        self.quic = quic;
+    }
+
+    /// the tunnels are carried as QUIC streams, with the certificate of the tls config.
+    /// it's the only transport that carries UDP, and it can't be dialed through other proxies.
+    pub fn quic(&self) -> bool {
+        self.quic && self.tls && self.protocol == Protocol::Nexel && self.via.is_none()
+    }
+
+    pub fn set_padding(&mut self, padding: PaddingCfg) {
+        self.padding = Some(padding);
+    }
+
+    /// only the tunnels of nexeld are padded, HTTP/2 and QUIC frame the tunnels by themselves.
+    pub fn padding(&self) -> Option<PaddingCfg> {
+        self.padding.filter(|_| self.protocol == Protocol::Nexel && !self.h2() && !self.quic())
+    }
+
+    pub fn set_noise(&mut self, noise: NoiseCfg) {
+        self.noise = Some(noise);
+    }
+
+    /// nexeld is reached over noise instead of tls, it's ignored when tls is on.
+    pub fn noise(&self) -> Option<&NoiseCfg> {
+        self.noise.as_ref().filter(|_| self.protocol == Protocol::Nexel && !self.tls)
+    }
+
+    /// the key of a shadowsocks proxy.
+    pub fn shadowsocks_key(&self) -> Result<shadowsocks::Key> {
+        let method = self.cipher.ok_or(Error::Other(format!("proxy {} has no cipher", self.name)))?;
+        shadowsocks::Key::new(method, self.password.as_deref().unwrap_or(""))
+    }
+
+    pub fn via(&self) -> Option<&str> {
+        self.via.as_deref()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupKind {
+    Select,
+    UrlTest,
+    Fallback,
+    LoadBalance,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProxyGroup {
+    name: String,
+    #[serde(rename = "type")]
+    kind: GroupKind,
+    proxies: Vec<String>,
+    #[serde(default = "default_test_url")]
+    url: String,
+    #[serde(default = "default_interval")]
+    interval: u64, // seconds
+    #[serde(default = "default_tolerance")]
+    tolerance: u64, // milliseconds
+    #[serde(default = "default_test_timeout")]
+    timeout: u64, // milliseconds
+}
+
+fn default_test_url() -> String {
+    "http://www.gstatic.com/generate_204".to_string()
+}
+
+fn default_interval() -> u64 {
+    300
+}
+
+fn default_tolerance() -> u64 {
+    50
+}
+
+fn default_test_timeout() -> u64 {
+    5000
+}
+
+impl ProxyGroup {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> GroupKind {
+        self.kind
+    }
+
+    pub fn proxies(&self) -> &[String] {
+        &self.proxies
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+
+    pub fn tolerance(&self) -> Duration {
+        Duration::from_millis(self.tolerance)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+}
+
+/// the last health check result of a proxy, a proxy that was never checked is taken as alive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Health {
+    Alive(Duration),
+    Dead,
+}
+
+pub trait ProxyStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ProxyStream for T {}
+
+/// wraps a fresh connection to the proxy server with the security layer and the transport that it's configured with.
+/// nexeld is authenticated to with the username and password of the proxy as the user and its pre-shared key,
+/// a HTTP/2 connection is authenticated in each of its streams instead.
+pub async fn establish(proxy: &ProxyCfg, remote: Box<dyn ProxyStream>, timeouts: &Timeouts) -> Result<Box<dyn ProxyStream>> {
+    let alpn: &[&[u8]] = if proxy.h2() { &[http2::ALPN] } else { &[] };
+    let mut remote = if proxy.tls() {
+        config::within(timeouts.tls_handshake(), Error::TlsHandshakeTimeout, async {
+            let remote = tls::connect(remote, &proxy.trust(), proxy.sni(), proxy.client_identity(), alpn).await?;
+            if proxy.h2() && remote.get_ref().1.alpn_protocol() != Some(http2::ALPN) {
+                return Err(Error::Other(format!("proxy {} didn't negotiate h2", proxy.name())));
+            }
+            Ok(Box::new(remote) as Box<dyn ProxyStream>)
+        }).await?
+    } else if let Some(noise) = proxy.noise() {
+        config::within(timeouts.tls_handshake(), Error::NoiseHandshakeTimeout, async {
+            let remote = noise::connect(remote, &noise.keypair()?, &noise.server_key()?).await?;
+            Ok(Box::new(remote) as Box<dyn ProxyStream>)
+        }).await?
+    } else {
+        remote
+    };
+    if proxy.h2() {
+        return Ok(remote);
+    }
+    if proxy.protocol() == Protocol::Shadowsocks {
+        return Ok(Box::new(SsStream::client(remote, proxy.shadowsocks_key()?)));
+    }
+    if let Some(ws) = proxy.ws() {
+        remote = config::within(timeouts.connect(), Error::ConnectTimeout, async {
+            Ok(Box::new(ws::connect(remote, proxy.sni(), ws).await?) as Box<dyn ProxyStream>)
+        }).await?;
+    }
+    if let Some(padding) = proxy.padding() {
+        remote = Box::new(PadStream::client(remote, padding));
+    }
+    if let (Protocol::Nexel, Some((user, key))) = (proxy.protocol(), proxy.credentials()) {
+        auth::send(&mut remote, user, key).await?;
+    }
+    Ok(remote)
+}
+
+/// What a rule target finally resolves to.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Direct,
+    Reject,
+    Proxy(Box<ProxyCfg>),
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OutboundCfg {
+    #[serde(default)]
+    proxies: Vec<ProxyCfg>,
+    #[serde(default, rename = "proxy-groups")]
+    proxy_groups: Vec<ProxyGroup>,
+}
+
+#[derive(Debug, Default)]
+pub struct Outbounds {
+    proxies: HashMap<String, ProxyCfg>,
+    groups: HashMap<String, ProxyGroup>,
+    health: Mutex<HashMap<String, Health>>,
+    selected: Mutex<HashMap<String, String>>, // url-test group -> member
+    timeouts: Timeouts,
+    sessions: Mutex<HashMap<String, MuxPool>>, // proxy -> its mux sessions
+    h2_conns: Mutex<HashMap<String, SendRequest<Bytes>>>, // proxy -> its HTTP/2 connection
+    quic_conns: Mutex<HashMap<String, Arc<Datagrams>>>, // proxy -> its QUIC connection
+    pools: HashMap<String, Arc<Pool>>, // proxy -> its warm connections
+}
+
+#[derive(Debug, Default)]
+struct MuxPool {
+    sessions: Vec<mux::Session>,
+    dialing: usize,
+}
+
+/// a session that is being dialed, it counts against the sessions of the pool until it's done or the dial is dropped.
+struct Dialing<'a> {
+    sessions: &'a Mutex<HashMap<String, MuxPool>>,
+    proxy: &'a str,
+}
+
+impl Dialing<'_> {
+    fn done(self, pool: &mut MuxPool) {
+        pool.dialing -= 1;
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Dialing<'_> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.sessions.lock().unwrap().get_mut(self.proxy) {
+            pool.dialing -= 1;
+        }
+    }
+}
+
+impl Outbounds {
+    pub fn new() -> Outbounds {
+        Outbounds::default()
+    }
+
+    /// loads the `proxies` and `proxy-groups` sections of the config file.
+    pub fn load(path: &str) -> Result<Outbounds> {
+        let file = std::fs::File::open(path)?;
+        let cfg: OutboundCfg = serde_yml::from_reader(file)
+            .map_err(|e| Error::Other(format!("bad outbound config: {e}")))?;
+        let mut outbounds = Outbounds::new();
+        for proxy in cfg.proxies {
+            outbounds.insert_proxy(proxy)?;
+        }
+        for group in cfg.proxy_groups {
+            outbounds.insert_group(group)?;
+        }
+        outbounds.check()?;
+        Ok(outbounds)
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn insert_proxy(&mut self, proxy: ProxyCfg) -> Result<()> {
+        self.check_name(&proxy.name)?;
+        if let Some(cfg) = proxy.pool() {
+            self.pools.insert(proxy.name.clone(), Arc::new(Pool::new(cfg)));
+        }
+        self.proxies.insert(proxy.name.clone(), proxy);
+        Ok(())
+    }
+
+    pub fn insert_group(&mut self, group: ProxyGroup) -> Result<()> {
+        self.check_name(&group.name)?;
+        if group.proxies.is_empty() {
+            return Err(Error::Other(format!("proxy group {} has no member", group.name)));
+        }
+        self.groups.insert(group.name.clone(), group);
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<()> {
+        if name == DIRECT || name == REJECT {
+            return Err(Error::Other(format!("outbound name {name} is reserved")));
+        }
+        if self.proxies.contains_key(name) || self.groups.contains_key(name) {
+            return Err(Error::Other(format!("outbound name {name} is duplicated")));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        name == DIRECT || name == REJECT || self.proxies.contains_key(name) || self.groups.contains_key(name)
+    }
+
+    pub fn group(&self, name: &str) -> Option<&ProxyGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn proxy(&self, name: &str) -> Option<&ProxyCfg> {
+        self.proxies.get(name)
+    }
+
+    /// makes sure every group member exists and that groups and chains don't refer to each other in a loop.
+    pub fn check(&self) -> Result<()> {
+        for proxy in self.proxies.values() {
+            let mut hops = HashSet::from([proxy.name()]);
+            let mut next = proxy.via();
+            while let Some(name) = next {
+                let Some(hop) = self.proxies.get(name) else {
+                    return Err(Error::Other(format!("proxy {} is dialed through unknown proxy {name}", proxy.name)));
+                };
+                if !hops.insert(name) {
+                    return Err(Error::Other(format!("proxy {} is dialed through itself", proxy.name)));
+                }
+                next = hop.via();
+            }
+        }
+        for group in self.groups.values() {
+            for member in &group.proxies {
+                if !self.contains(member) {
+                    return Err(Error::Other(format!("proxy group {} refers to unknown outbound {member}", group.name)));
+                }
+            }
+            self.walk(&group.name, &mut HashSet::new())?;
+        }
+        Ok(())
+    }
+
+    fn walk<'a>(&'a self, name: &'a str, visiting: &mut HashSet<&'a str>) -> Result<()> {
+        if let Some(group) = self.groups.get(name) {
+            if !visiting.insert(name) {
+                return Err(Error::Other(format!("proxy group {name} refers to itself")));
+            }
+            for member in &group.proxies {
+                self.walk(member, visiting)?;
+            }
+            visiting.remove(name);
+        }
+        Ok(())
+    }
+
+    /// follows groups down to a concrete outbound, the destination is the key that load-balance groups hash.
+    pub fn resolve(&self, name: &str, dst: &str) -> Result<Target> {
+        match name {
+            DIRECT => Ok(Target::Direct),
+            REJECT => Ok(Target::Reject),
+            _ => {
+                if let Some(proxy) = self.proxies.get(name) {
+                    return Ok(Target::Proxy(Box::new(proxy.clone())));
+                }
+                match self.groups.get(name) {
+                    Some(group) => self.resolve(&self.pick(group, dst), dst),
+                    None => Err(Error::Other(format!("unknown outbound {name}"))),
+                }
+            }
+        }
+    }
+
+    fn pick(&self, group: &ProxyGroup, dst: &str) -> String {
+        let first = group.proxies[0].clone();
+        match group.kind {
+            GroupKind::Select => first,
+            GroupKind::UrlTest => {
+                self.selected.lock().unwrap().get(&group.name).cloned().unwrap_or(first)
+            }
+            GroupKind::Fallback => {
+                group.proxies.iter()
+                    .find(|member| self.is_alive(member, dst))
+                    .cloned()
+                    .unwrap_or(first)
+            }
+            GroupKind::LoadBalance => {
+                // rendezvous hashing, a destination sticks to its member until that member dies
+                group.proxies.iter()
+                    .filter(|member| self.is_alive(member, dst))
+                    .max_by_key(|member| {
+                        let mut hasher = DefaultHasher::new();
+                        (dst, member.as_str()).hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .cloned()
+                    .unwrap_or(first)
+            }
+        }
+    }
+
+    fn is_alive(&self, name: &str, dst: &str) -> bool {
+        match self.resolve(name, dst) {
+            Ok(Target::Proxy(proxy)) => self.health(proxy.name()) != Some(Health::Dead),
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    pub fn pools(&self) -> Vec<(String, Arc<Pool>)> {
+        self.pools.iter().map(|(name, pool)| (name.clone(), pool.clone())).collect()
+    }
+
+    /// opens a tunnel to the proxy server, as a stream of a mux session or a warm connection if it's configured with one.
+    /// a fresh connection is dialed when every session is full or the pool ran dry.
+    pub async fn connect(&self, proxy: &ProxyCfg) -> Result<Box<dyn ProxyStream>> {
+        if proxy.h2() {
+            return self.connect_h2(proxy).await;
+        }
+        if proxy.quic() {
+            return Ok(Box::new(self.connect_quic(proxy).await?.1));
+        }
+        let Some(cfg) = proxy.mux() else {
+            if let Some(pool) = self.pools.get(proxy.name()) {
+                if let Some(stream) = pool.take().await {
+                    return Ok(stream);
+                }
+                info!("[POOL] outbound = {}, missed, {}", proxy.name(), pool.stats());
+            }
+            return self.dial(proxy).await;
+        };
+        let full = {
+            let mut pools = self.sessions.lock().unwrap();
+            let pool = pools.entry(proxy.name().to_string()).or_default();
+            pool.sessions.retain(|session| !session.is_closed());
+            let idlest = pool.sessions.iter()
+                .filter(|session| session.num_streams() < cfg.max_streams)
+                .min_by_key(|session| session.num_streams());
+            if let Some(session) = idlest {
+                return Ok(Box::new(session.open()?));
+            }
+            let full = pool.sessions.len() + pool.dialing >= cfg.sessions;
+            if !full {
+                pool.dialing += 1;
+            }
+            full
+        };
+        if full {
+            return self.dial(proxy).await;
+        }
+        let dialing = Dialing { sessions: &self.sessions, proxy: proxy.name() };
+        let session = match self.dial(proxy).await {
+            Ok(remote) => config::within(self.timeouts.handshake(), Error::HandshakeTimeout, async {
+                Ok(mux::connect(remote).await?)
+            }).await,
+            Err(e) => Err(e),
+        };
+        let mut pools = self.sessions.lock().unwrap();
+        let pool = pools.entry(proxy.name().to_string()).or_default();
+        dialing.done(pool);
+        let session = session?;
+        let stream = session.open()?;
+        info!("[MUX] outbound = {}, sessions = {}", proxy.name(), pool.sessions.len() + 1);
+        pool.sessions.push(session);
+        Ok(Box::new(stream))
+    }
+
+    /// opens a stream of the HTTP/2 connection to the proxy server, the connection is redialed once it's broken.
+    async fn connect_h2(&self, proxy: &ProxyCfg) -> Result<Box<dyn ProxyStream>> {
+        let cached = self.h2_conns.lock().unwrap().get(proxy.name()).cloned();
+        let stream = match cached {
+            Some(send) => match config::within(self.timeouts.connect(), Error::ConnectTimeout, http2::open(send, proxy.sni())).await {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    // a connection that is half dead doesn't hold up the next tunnels
+                    info!("[H2] outbound = {}, connection dropped: {}", proxy.name(), e);
+                    self.h2_conns.lock().unwrap().remove(proxy.name());
+                    None
+                }
+            },
+            None => None,
+        };
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => {
+                let remote = self.dial(proxy).await?;
+                let send = config::within(self.timeouts.connect(), Error::ConnectTimeout, http2::handshake(remote)).await?;
+                info!("[H2] outbound = {}, connection started", proxy.name());
+                self.h2_conns.lock().unwrap().insert(proxy.name().to_string(), send.clone());
+                config::within(self.timeouts.connect(), Error::ConnectTimeout, http2::open(send, proxy.sni())).await?
+            }
+        };
+        if let Some((user, key)) = proxy.credentials() {
+            auth::send(&mut stream, user, key).await?;
+        }
+        Ok(Box::new(stream))
+    }
+
+    /// opens a stream of the QUIC connection to the proxy server, the connection is redialed once it's closed.
+    async fn connect_quic(&self, proxy: &ProxyCfg) -> Result<(Arc<Datagrams>, QuicStream)> {
+        let datagrams = self.quic_conn(proxy).await?;
+        let (send, recv) = config::within(self.timeouts.connect(), Error::ConnectTimeout, async {
+            Ok(datagrams.conn().open_bi().await?)
+        }).await?;
+        let mut stream = QuicStream::new(send, recv);
+        if let Some((user, key)) = proxy.credentials() {
+            auth::send(&mut stream, user, key).await?;
+        }
+        Ok((datagrams, stream))
+    }
+
+    async fn quic_conn(&self, proxy: &ProxyCfg) -> Result<Arc<Datagrams>> {
+        let cached = self.quic_conns.lock().unwrap().get(proxy.name()).cloned();
+        if let Some(datagrams) = cached.filter(|datagrams| datagrams.conn().close_reason().is_none()) {
+            return Ok(datagrams);
+        }
+        let datagrams = Datagrams::new(quic::connect(proxy, &self.timeouts).await?);
+        info!("[QUIC] outbound = {}, connection started", proxy.name());
+        self.quic_conns.lock().unwrap().insert(proxy.name().to_string(), datagrams.clone());
+        Ok(datagrams)
+    }
+
+    /// asks the proxy server for a UDP association, its datagrams are tunneled in the QUIC connection.
+    pub async fn associate(&self, proxy: &ProxyCfg) -> Result<(Association, mpsc::Receiver<(udp::Addr, Bytes)>)> {
+        if !proxy.quic() {
+            return Err(Error::Other(format!("proxy {} doesn't carry datagrams", proxy.name())));
+        }
+        let (datagrams, mut control) = self.connect_quic(proxy).await?;
+        config::within(self.timeouts.connect(), Error::ConnectTimeout, async {
+            control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            let mut reply = [0u8; 10];
+            control.read_exact(&mut reply).await?;
+            match reply[1] {
+                0 => Ok(()),
+                2 => Err(Error::Rejected),
+                _ => Err(Error::Other(format!("proxy {} refused the association", proxy.name()))),
+            }
+        }).await?;
+        Ok(Association::new(datagrams, control))
+    }
+
+    /// connects to the proxy server, through the proxies it's chained with, and sets up its security layer.
+    pub async fn dial(&self, proxy: &ProxyCfg) -> Result<Box<dyn ProxyStream>> {
+        let mut hops = vec![proxy];
+        while let Some(name) = hops[hops.len() - 1].via() {
+            hops.push(self.proxies.get(name).ok_or(Error::Other(format!("unknown proxy {name}")))?);
+        }
+        // the outermost hop is dialed directly, every hop opens a tunnel to the one inside it
+        let first = hops.pop().unwrap();
+        let remote = config::within(self.timeouts.connect(), Error::ConnectTimeout, async {
+            Ok(Box::new(dial::connect(first.host(), first.port()).await?) as Box<dyn ProxyStream>)
+        }).await?;
+        let mut remote = establish(first, remote, &self.timeouts).await?;
+        let mut outer = first;
+        while let Some(hop) = hops.pop() {
+            config::within(self.timeouts.connect(), Error::ConnectTimeout,
+                           upstream::handshake(outer, &mut remote, hop.host(), hop.port())).await?;
+            remote = establish(hop, remote, &self.timeouts).await?;
+            outer = hop;
+        }
+        Ok(remote)
+    }
+
+    pub fn health(&self, proxy: &str) -> Option<Health> {
+        self.health.lock().unwrap().get(proxy).copied()
+    }
+
+    pub fn set_health(&self, proxy: &str, health: Health) {
+        self.health.lock().unwrap().insert(proxy.to_string(), health);
+    }
+
+    /// marks a proxy as dead after a failed dial, so fallback groups move on before the next check.
+    pub fn report_failure(&self, proxy: &str) {
+        if self.health(proxy) != Some(Health::Dead) {
+            warn!("[Outbound] proxy {proxy} was marked as dead");
+        }
+        self.set_health(proxy, Health::Dead);
+    }
+
+    /// picks the fastest member of an url-test group, it only switches when the new one is faster than the tolerance.
+    fn select_fastest(&self, group: &ProxyGroup) {
+        let latency = |member: &str| match self.resolve(member, "") {
+            Ok(Target::Proxy(proxy)) => match self.health(proxy.name()) {
+                Some(Health::Alive(latency)) => Some(latency),
+                _ => None,
+            },
+            _ => None,
+        };
+        let fastest = group.proxies.iter()
+            .filter_map(|member| latency(member).map(|l| (member, l)))
+            .min_by_key(|(_, l)| *l);
+        let Some((fastest, fastest_latency)) = fastest else {
+            return;
+        };
+        let mut selected = self.selected.lock().unwrap();
+        if let Some(current) = selected.get(&group.name) {
+            if let Some(current_latency) = latency(current) {
+                if current_latency <= fastest_latency + group.tolerance() {
+                    return;
+                }
+            }
+        }
+        info!("[Outbound] group {} selects {} ({} ms)", group.name, fastest, fastest_latency.as_millis());
+        selected.insert(group.name.clone(), fastest.clone());
+    }
+
+    /// probes the proxies of one group and updates their health.
+    pub async fn check_group(self: &Arc<Self>, name: &str) {
+        let Some(group) = self.groups.get(name) else {
+            return;
+        };
+        let mut tasks = vec![];
+        for member in &group.proxies {
+            if let Ok(Target::Proxy(proxy)) = self.resolve(member, "") {
+                let (outbounds, url, timeout) = (self.clone(), group.url.clone(), group.timeout());
+                tasks.push(tokio::spawn(async move {
+                    let ret = health::url_test(&outbounds, &proxy, &url, timeout).await;
+                    (proxy, ret)
+                }));
+            }
+        }
+        for task in tasks {
+            if let Ok((proxy, ret)) = task.await {
+                match ret {
+                    Ok(latency) => self.set_health(proxy.name(), Health::Alive(latency)),
+                    Err(e) => {
+                        warn!("[Outbound] health check of {} failed: {}", proxy.name(), e);
+                        self.set_health(proxy.name(), Health::Dead);
+                    }
+                }
+            }
+        }
+        if group.kind == GroupKind::UrlTest {
+            self.select_fastest(group);
+        }
+    }
+}
+
+/// starts a periodic health check for every group that selects its member automatically.
+pub fn spawn_health_check(outbounds: Arc<Outbounds>) {
+    for group in outbounds.groups.values() {
+        if group.kind == GroupKind::Select {
+            continue;
+        }
+        let (outbounds, name, interval) = (outbounds.clone(), group.name.clone(), group.interval());
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                outbounds.check_group(&name).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::outbound::{Health, MuxCfg, Outbounds, ProxyCfg, Target};
+    use crate::test_util::{outbounds, proxy_name, spawn_with};
+
+    #[test]
+    fn resolve_group() {
+        let outbounds = outbounds(r#"
+proxies:
+  - name: hk-1
+    server: hk1.nexel.cc
+    port: 6789
+    tls: true
+    cert: certificate.crt
+  - name: jp-1
+    server: jp1.nexel.cc
+    port: 6789
+proxy-groups:
+  - name: HK-Servers
+    type: select
+    proxies: [hk-1, jp-1]
+  - name: Final
+    type: select
+    proxies: [HK-Servers, DIRECT]
+"#).unwrap();
+        match outbounds.resolve("Final", "").unwrap() {
+            Target::Proxy(proxy) => {
+                assert_eq!(proxy.name(), "hk-1");
+                assert_eq!(proxy.addr(), "hk1.nexel.cc:6789");
+                assert_eq!(proxy.sni(), "hk1.nexel.cc");
+                assert!(proxy.tls());
+            }
+            _ => panic!("expected a proxy"),
+        }
+        assert!(matches!(outbounds.resolve("DIRECT", "").unwrap(), Target::Direct));
+        assert!(outbounds.resolve("US-Servers", "").is_err());
+    }
+
+    #[test]
+    fn reject_bad_groups() {
+        assert!(outbounds(r#"
+proxy-groups:
+  - name: A
+    type: select
+    proxies: [B]
+  - name: B
+    type: select
+    proxies: [A]
+"#).is_err());
+        assert!(outbounds(r#"
+proxy-groups:
+  - name: A
+    type: select
+    proxies: [missing]
+"#).is_err());
+
+        assert!(outbounds(r#"
+proxies:
+  - name: a
+    server: a.nexel.cc
+    port: 6789
+    dialer-proxy: b
+  - name: b
+    server: b.nexel.cc
+    port: 3128
+    protocol: http
+    dialer-proxy: a
+"#).is_err());
+
+        let mut outbounds = Outbounds::new();
+        outbounds.insert_proxy(ProxyCfg::new("nexel.cc", 6789, "")).unwrap();
+        assert!(outbounds.insert_proxy(ProxyCfg::new("nexel.cc", 6789, "")).is_err());
+    }
+
+    #[test]
+    fn url_test_tolerance() {
+        let outbounds = outbounds(r#"
+proxies:
+  - name: a
+    server: a.nexel.cc
+    port: 6789
+  - name: b
+    server: b.nexel.cc
+    port: 6789
+proxy-groups:
+  - name: auto
+    type: url-test
+    proxies: [a, b]
+    tolerance: 50
+"#).unwrap();
+        outbounds.set_health("a", Health::Alive(Duration::from_millis(100)));
+        outbounds.set_health("b", Health::Alive(Duration::from_millis(200)));
+        outbounds.select_fastest(outbounds.group("auto").unwrap());
+        assert_eq!(proxy_name(outbounds.resolve("auto", "").unwrap()), "a");
+
+        // b is faster but not by more than the tolerance
+        outbounds.set_health("b", Health::Alive(Duration::from_millis(80)));
+        outbounds.select_fastest(outbounds.group("auto").unwrap());
+        assert_eq!(proxy_name(outbounds.resolve("auto", "").unwrap()), "a");
+
+        outbounds.set_health("b", Health::Alive(Duration::from_millis(20)));
+        outbounds.select_fastest(outbounds.group("auto").unwrap());
+        assert_eq!(proxy_name(outbounds.resolve("auto", "").unwrap()), "b");
+    }
+
+    #[test]
+    fn load_balance_sticks() {
+        let outbounds = outbounds(r#"
+proxies:
+  - name: a
+    server: a.nexel.cc
+    port: 6789
+  - name: b
+    server: b.nexel.cc
+    port: 6789
+  - name: c
+    server: c.nexel.cc
+    port: 6789
+proxy-groups:
+  - name: balance
+    type: load-balance
+    proxies: [a, b, c]
+"#).unwrap();
+        let dsts: Vec<String> = (0..32).map(|i| format!("host{i}.nexel.cc")).collect();
+        let picked: Vec<String> = dsts.iter()
+            .map(|dst| proxy_name(outbounds.resolve("balance", dst).unwrap()))
+            .collect();
+        // the same destination always goes to the same member
+        for (dst, name) in dsts.iter().zip(&picked) {
+            assert_eq!(&proxy_name(outbounds.resolve("balance", dst).unwrap()), name);
+        }
+        assert!(picked.iter().any(|name| name != &picked[0]));
+
+        // only the destinations of the dead member move
+        outbounds.report_failure("a");
+        for (dst, name) in dsts.iter().zip(&picked) {
+            let now = proxy_name(outbounds.resolve("balance", dst).unwrap());
+            assert_ne!(now, "a");
+            if name != "a" {
+                assert_eq!(&now, name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_mux_dial() {
+        // a nexeld that accepts and never answers the preface
+        let (server, accepted) = spawn_with(|socket| async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(socket);
+        }).await;
+        let outbounds = {
+            let mut outbounds = Outbounds::new();
+            let mut proxy = ProxyCfg::new("127.0.0.1", server, "");
+            proxy.set_mux(MuxCfg::new(1, 2));
+            outbounds.insert_proxy(proxy).unwrap();
+            outbounds
+        };
+        let proxy = outbounds.proxies[super::PROXY].clone();
+
+        // the client hangs up in the middle of the dial, the session it held a place for is given back
+        for _ in 0..2 {
+            assert!(tokio::time::timeout(Duration::from_millis(200), outbounds.connect(&proxy)).await.is_err());
+            assert_eq!(outbounds.sessions.lock().unwrap()[proxy.name()].dialing, 0);
+        }
+        // so each try dials a session again instead of going around the mux
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
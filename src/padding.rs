@@ -0,0 +1,315 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use bytes::{Buf, BufMut, BytesMut};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+use crate::Result;
+
+/// what a padded tunnel starts with, followed by its config. no other request of nexeld starts with 2.
+pub const PREFACE: [u8; 5] = [2, b'P', b'A', b'D', 1];
+const CFG_LEN: usize = 6;
+const HEADER: usize = 4; // data len | padding len
+const MAX_RECORD: usize = 16 * 1024;
+const MIN_RECORD: usize = 64;
+const READ_CHUNK: usize = 16 * 1024;
+
+/// how the records of a padded tunnel are shaped, the client sends it in the preface and nexeld pads the same way.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PaddingCfg {
+    max_padding: u16, // every record gets a random padding up to it
+    record: u16, // records are padded to a multiple of it, 0 disables it
+    dummy: u16, // seconds that the tunnel is idle before a dummy record is sent, 0 disables it
+}
+
+impl Default for PaddingCfg {
+    fn default() -> Self {
+        PaddingCfg { max_padding: 255, record: 0, dummy: 0 }
+    }
+}
+
+impl PaddingCfg {
+    pub fn new(max_padding: u16, record: u16, dummy: u16) -> PaddingCfg {
+        PaddingCfg { max_padding, record, dummy }
+    }
+
+    fn record(&self) -> usize {
+        match self.record as usize {
+            0 => 0,
+            record => record.max(MIN_RECORD),
+        }
+    }
+
+    /// half to one and a half times the configured seconds, so that the dummy records don't come like a clock.
+    fn dummy_interval(&self) -> Duration {
+        let millis = u64::from(self.dummy) * 1000;
+        Duration::from_millis(millis / 2 + u64::from(random_u16()) * millis / u64::from(u16::MAX))
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.max_padding);
+        buf.put_u16(self.record);
+        buf.put_u16(self.dummy);
+    }
+
+    fn decode(mut buf: &[u8]) -> PaddingCfg {
+        PaddingCfg { max_padding: buf.get_u16(), record: buf.get_u16(), dummy: buf.get_u16() }
+    }
+}
+
+/// what a tunnel on nexeld starts with.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Preface {
+    Padded(PaddingCfg),
+    /// not a padded tunnel, with the bytes read from it.
+    Other(Vec<u8>),
+}
+
+/// reads the preface of a padded tunnel, it stops at the first byte that isn't one.
+pub async fn accept<R: AsyncRead + Unpin + ?Sized>(stream: &mut R) -> Result<Preface> {
+    let mut read = vec![stream.read_u8().await?];
+    if read[0] != PREFACE[0] {
+        return Ok(Preface::Other(read));
+    }
+    read.resize(PREFACE.len() + CFG_LEN, 0);
+    stream.read_exact(&mut read[1..]).await?;
+    if read[..PREFACE.len()] != PREFACE {
+        return Ok(Preface::Other(read));
+    }
+    Ok(Preface::Padded(PaddingCfg::decode(&read[PREFACE.len()..])))
+}
+
+fn random_u16() -> u16 {
+    let mut bytes = [0u8; 2];
+    aws_lc_rs::rand::fill(&mut bytes).unwrap();
+    u16::from_be_bytes(bytes)
+}
+
+/// a tunnel whose data is carried in records with random padding, so that the sizes of the packets
+/// don't give the requests inside away. it's meant to run inside tls, the padding is zeros.
+/// data len(u16) | padding len(u16) | data | padding, a record without data is a dummy one.
+pub struct PadStream<RW> {
+    inner: RW,
+    cfg: PaddingCfg,
+    wbuf: BytesMut, // records to be written
+    rbuf: BytesMut, // records read but not opened yet
+    data: BytesMut, // data opened but not read yet
+    dummy: Option<Pin<Box<Sleep>>>,
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> PadStream<RW> {
+    /// the preface is sent with the first record.
+    pub fn client(inner: RW, cfg: PaddingCfg) -> PadStream<RW> {
+        let mut stream = PadStream::new(inner, cfg);
+        stream.wbuf.extend_from_slice(&PREFACE);
+        cfg.encode(&mut stream.wbuf);
+        stream
+    }
+
+    /// pads the way the client asked in the preface.
+    pub fn server(inner: RW, cfg: PaddingCfg) -> PadStream<RW> {
+        PadStream::new(inner, cfg)
+    }
+
+    fn new(inner: RW, cfg: PaddingCfg) -> PadStream<RW> {
+        PadStream {
+            inner,
+            cfg,
+            wbuf: BytesMut::new(),
+            rbuf: BytesMut::new(),
+            data: BytesMut::new(),
+            dummy: (cfg.dummy > 0).then(|| Box::pin(sleep(cfg.dummy_interval()))),
+        }
+    }
+
+    /// seals as much of the data as a record takes, returns how much it took.
+    fn seal(&mut self, data: &[u8]) -> usize {
+        let record = self.cfg.record();
+        let n = data.len().min(if record > 0 { record - HEADER } else { MAX_RECORD - HEADER });
+        let mut padding = random_u16() as usize % (self.cfg.max_padding as usize + 1);
+        if n == 0 {
+            // a dummy record is never empty
+            padding = padding.max(1);
+        }
+        if record > 0 {
+            padding += (record - (HEADER + n + padding) % record) % record;
+        }
+        let padding = padding.min(u16::MAX as usize);
+        self.wbuf.reserve(HEADER + n + padding);
+        self.wbuf.put_u16(n as u16);
+        self.wbuf.put_u16(padding as u16);
+        self.wbuf.extend_from_slice(&data[..n]);
+        self.wbuf.put_bytes(0, padding);
+        n
+    }
+
+    /// opens the next record in rbuf, false if it isn't complete yet.
+    fn open_next(&mut self) -> bool {
+        if self.rbuf.len() < HEADER {
+            return false;
+        }
+        let len = u16::from_be_bytes([self.rbuf[0], self.rbuf[1]]) as usize;
+        let padding = u16::from_be_bytes([self.rbuf[2], self.rbuf[3]]) as usize;
+        if self.rbuf.len() < HEADER + len + padding {
+            return false;
+        }
+        self.rbuf.advance(HEADER);
+        self.data.extend_from_slice(&self.rbuf[..len]);
+        self.rbuf.advance(len + padding);
+        true
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// sends a dummy record when nothing was written for a while, it's polled along with the reads.
+    fn poll_dummy(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(timer) = self.dummy.as_mut() else {
+            return Ok(());
+        };
+        if timer.as_mut().poll(cx).is_pending() {
+            return Ok(());
+        }
+        timer.as_mut().reset(Instant::now() + self.cfg.dummy_interval());
+        // registers the waker for the next one
+        let _ = timer.as_mut().poll(cx);
+        self.seal(&[]);
+        match self.poll_drain(cx) {
+            Poll::Ready(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncRead for PadStream<RW> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_dummy(cx)?;
+        loop {
+            if !this.data.is_empty() {
+                let n = this.data.len().min(buf.remaining());
+                buf.put_slice(&this.data.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.open_next() {
+                continue;
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return if this.rbuf.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.rbuf.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncWrite for PadStream<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = this.seal(buf);
+        if let Some(timer) = this.dummy.as_mut() {
+            timer.as_mut().reset(Instant::now() + this.cfg.dummy_interval());
+        }
+        // sent right away when the inner stream can take it, the rest goes with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::fallback::Rewind;
+    use crate::padding::{accept, PadStream, PaddingCfg, Preface};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let cfg = PaddingCfg::new(100, 512, 0);
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = PadStream::client(client, cfg);
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        client.flush().await.unwrap();
+
+        assert_eq!(accept(&mut server).await.unwrap(), Preface::Padded(cfg));
+        // every record is a multiple of the record size
+        let mut raw = vec![0u8; 64 * 1024];
+        let n = server.read(&mut raw).await.unwrap();
+        assert_eq!(n % 512, 0);
+        let mut server = PadStream::server(Rewind::new(raw[..n].to_vec(), server), cfg);
+        let mut received = vec![0u8; data.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"pong").await.unwrap();
+        server.flush().await.unwrap();
+        let mut pong = [0u8; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+
+        // anything else is handed back with the byte read
+        assert_eq!(accept(&mut &[5u8, 1, 0][..]).await.unwrap(), Preface::Other(vec![5]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dummy_records() {
+        let cfg = PaddingCfg::new(16, 0, 10);
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = PadStream::client(client, cfg);
+        client.write_all(b"hello").await.unwrap();
+        assert_eq!(accept(&mut server).await.unwrap(), Preface::Padded(cfg));
+        let mut raw = [0u8; 1024];
+        let n = server.read(&mut raw).await.unwrap();
+        assert_eq!(&raw[..2], &[0, 5]);
+        assert_eq!(&raw[4..9], b"hello");
+        assert_eq!(n, 9 + u16::from_be_bytes([raw[2], raw[3]]) as usize);
+
+        // the idle client sends a padding-only record while it waits to read
+        let mut buf = [0u8; 1];
+        let idle = tokio::time::timeout(Duration::from_secs(30), client.read(&mut buf));
+        let dummy = async {
+            let n = server.read(&mut raw).await.unwrap();
+            (n, raw[..2] == [0, 0])
+        };
+        let (idle, (n, empty)) = tokio::join!(idle, dummy);
+        assert!(idle.is_err());
+        assert!(n > 4 && empty);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use crate::outbound::{Outbounds, ProxyStream};
+
+/// the idle connections kept to a nexeld, nexeld closes the ones that don't send a request within its handshake timeout,
+/// so the max idle age has to be shorter.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PoolCfg {
+    min_idle: usize,
+    max_idle_age: u64,   // seconds
+    probe_interval: u64, // seconds
+}
+
+impl Default for PoolCfg {
+    fn default() -> Self {
+        PoolCfg { min_idle: 2, max_idle_age: 60, probe_interval: 5 }
+    }
+}
+
+impl PoolCfg {
+    pub fn new(min_idle: usize, max_idle_age: Duration, probe_interval: Duration) -> PoolCfg {
+        PoolCfg { min_idle, max_idle_age: max_idle_age.as_secs(), probe_interval: probe_interval.as_secs() }
+    }
+
+    pub fn min_idle(&self) -> usize {
+        self.min_idle
+    }
+
+    pub fn max_idle_age(&self) -> Duration {
+        Duration::from_secs(self.max_idle_age)
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_secs(self.probe_interval.max(1))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PoolStats {
+    pub idle: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub dialed: u64,
+    pub expired: u64,
+    pub dead: u64,
+}
+
+impl Display for PoolStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "idle = {}, hits = {}, misses = {}, dialed = {}, expired = {}, dead = {}",
+               self.idle, self.hits, self.misses, self.dialed, self.expired, self.dead)
+    }
+}
+
+/// warm connections that only need the request to be written.
+pub struct Pool {
+    cfg: PoolCfg,
+    idle: Mutex<VecDeque<(Instant, Box<dyn ProxyStream>)>>,
+    taken: Notify,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    dialed: AtomicU64,
+    expired: AtomicU64,
+    dead: AtomicU64,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pool {{ {} }}", self.stats())
+    }
+}
+
+impl Pool {
+    pub fn new(cfg: PoolCfg) -> Pool {
+        Pool {
+            cfg,
+            idle: Mutex::new(VecDeque::new()),
+            taken: Notify::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            dialed: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            dead: AtomicU64::new(0),
+        }
+    }
+
+    /// the oldest connection that is still fresh and alive, none if the pool ran dry.
+    pub async fn take(&self) -> Option<Box<dyn ProxyStream>> {
+        loop {
+            let Some((since, mut stream)) = self.idle.lock().unwrap().pop_front() else {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                self.taken.notify_one();
+                return None;
+            };
+            if since.elapsed() >= self.cfg.max_idle_age() {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            } else if is_alive(&mut stream).await {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.taken.notify_one();
+                return Some(stream);
+            } else {
+                self.dead.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn put(&self, stream: Box<dyn ProxyStream>) {
+        self.dialed.fetch_add(1, Ordering::Relaxed);
+        self.idle.lock().unwrap().push_back((Instant::now(), stream));
+    }
+
+    /// drops the connections that are too old or were closed by the peer.
+    async fn evict(&self) {
+        let idle: Vec<_> = self.idle.lock().unwrap().drain(..).collect();
+        let mut kept = VecDeque::new();
+        for (since, mut stream) in idle {
+            if since.elapsed() >= self.cfg.max_idle_age() {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            } else if !is_alive(&mut stream).await {
+                self.dead.fetch_add(1, Ordering::Relaxed);
+            } else {
+                kept.push_back((since, stream));
+            }
+        }
+        // the ones put meanwhile are newer
+        let mut idle = self.idle.lock().unwrap();
+        kept.extend(idle.drain(..));
+        *idle = kept;
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            idle: self.idle.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            dialed: self.dialed.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            dead: self.dead.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// nexeld sends nothing before the request, so a readable connection was closed or broken.
+async fn is_alive<R: AsyncRead + Unpin + ?Sized>(stream: &mut R) -> bool {
+    let mut byte = [0u8; 1];
+    poll_fn(|cx| {
+        let mut buf = ReadBuf::new(&mut byte);
+        Poll::Ready(Pin::new(&mut *stream).poll_read(cx, &mut buf).is_pending())
+    }).await
+}
+
+/// keeps every pool filled up to its min idle, a taken connection is replaced right away.
+pub fn spawn_pool_fill(outbounds: Arc<Outbounds>) {
+    for (name, pool) in outbounds.pools() {
+        let outbounds = outbounds.clone();
+        tokio::spawn(async move {
+            let Some(proxy) = outbounds.proxy(&name).cloned() else { return };
+            loop {
+                pool.evict().await;
+                let mut dialed = false;
+                while pool.stats().idle < pool.cfg.min_idle() {
+                    match outbounds.dial(&proxy).await {
+                        Ok(stream) => {
+                            pool.put(stream);
+                            dialed = true;
+                        }
+                        Err(e) => {
+                            warn!("[POOL] outbound = {}, dial failed: {}", name, e);
+                            break;
+                        }
+                    }
+                }
+                if dialed {
+                    info!("[POOL] outbound = {}, {}", name, pool.stats());
+                }
+                tokio::select! {
+                    _ = pool.taken.notified() => {}
+                    _ = tokio::time::sleep(pool.cfg.probe_interval()) => {}
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::connection::Connection;
+    use crate::outbound::{Outbounds, ProxyCfg};
+    use crate::pool::{is_alive, spawn_pool_fill, Pool, PoolCfg};
+    use crate::test_util::{assert_echo, spawn_echo, spawn_with};
+    use crate::upstream::http_connect;
+
+    #[tokio::test]
+    async fn probe_liveness() {
+        let (mut a, b) = tokio::io::duplex(64);
+        assert!(is_alive(&mut a).await);
+        drop(b);
+        assert!(!is_alive(&mut a).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_expired_and_dead() {
+        let pool = Pool::new(PoolCfg::new(2, Duration::from_secs(60), Duration::from_secs(5)));
+        let (a, _peer) = tokio::io::duplex(64);
+        pool.put(Box::new(a));
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let (b, peer) = tokio::io::duplex(64);
+        pool.put(Box::new(b));
+        drop(peer);
+        assert!(pool.take().await.is_none());
+        let stats = pool.stats();
+        assert_eq!((stats.expired, stats.dead, stats.misses, stats.hits), (1, 1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn warm_connections() {
+        let echo_port = spawn_echo().await;
+        let (server, accepted) = spawn_with(|socket| async move { Connection::new(socket, None).run_on_server().await }).await;
+
+        let mut outbounds = Outbounds::new();
+        let mut proxy = ProxyCfg::new("127.0.0.1", server, "");
+        proxy.set_pool(PoolCfg::new(2, Duration::from_secs(60), Duration::from_secs(5)));
+        outbounds.insert_proxy(proxy.clone()).unwrap();
+        let outbounds = Arc::new(outbounds);
+        spawn_pool_fill(outbounds.clone());
+        let pool = outbounds.pools()[0].1.clone();
+        while pool.stats().idle < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut stream = outbounds.connect(&proxy).await.unwrap();
+        http_connect(&mut stream, "127.0.0.1", echo_port, None).await.unwrap();
+        assert_echo(&mut stream).await;
+        assert_eq!(pool.stats().hits, 1);
+
+        // the taken one is replaced
+        while accepted.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
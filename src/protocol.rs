@@ -1,12 +1,12 @@
 use std::fmt::{Display, Formatter};
 use crate::error::Error;
-use crate::Result;
+use crate::{mux, Result};
 use bytes::{Buf, BytesMut};
 use std::io::{BufRead, Cursor, ErrorKind};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use url::Url;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout_at, Duration, Instant};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Ver {
@@ -37,6 +37,7 @@ impl ReqCmd {
 pub enum ReqFrame {
     Auth(AuthReq),
     Req(Request),
+    Mux, // the connection carries a multiplexed session from now on
 }
 
 #[derive(Debug, PartialEq)]
@@ -64,6 +65,34 @@ impl Display for Request {
 }
 
 impl Request {
+    /// a socks5 CONNECT to the address, for the inbounds that learn the destination by themselves.
+    pub fn connect(dst: SocketAddr) -> Request {
+        let mut raw = vec![5, ReqCmd::Connect as u8, 0];
+        let a_type = match dst.ip() {
+            IpAddr::V4(ip) => {
+                raw.push(AType::Ipv4 as u8);
+                raw.extend_from_slice(&ip.octets());
+                AType::Ipv4
+            }
+            IpAddr::V6(ip) => {
+                raw.push(AType::Ipv6 as u8);
+                raw.extend_from_slice(&ip.octets());
+                AType::Ipv6
+            }
+        };
+        raw.extend_from_slice(&dst.port().to_be_bytes());
+        Request {
+            ver: Ver::V5,
+            cmd: ReqCmd::Connect,
+            rsv: 0,
+            dst_domain: None,
+            dst_addr: Some(dst.ip()),
+            dst_port: dst.port(),
+            a_type,
+            raw,
+        }
+    }
+
     pub fn raw(&self) -> &[u8] {
         &self.raw[..]
     }
@@ -112,6 +141,12 @@ pub struct Reply {
     pub ver: Ver,
 }
 
+impl Default for Reply {
+    fn default() -> Self {
+        Reply::new()
+    }
+}
+
 impl Reply {
     pub fn new() -> Reply {
         Reply { buffer: BufWriter::with_capacity(64, vec![]), ver: Ver::V5 }
@@ -137,7 +172,11 @@ impl Reply {
                 self.buffer.write_u16(0).await?;
             }
             Ver::Http => {
-                let response = "HTTP/1.1 400 Connection Failed\r\n\r\n";
+                let response = match err {
+                    Error::Rejected => "HTTP/1.1 403 Forbidden\r\n\r\n",
+                    Error::ConnectTimeout | Error::TlsHandshakeTimeout | Error::NoiseHandshakeTimeout => "HTTP/1.1 504 Gateway Timeout\r\n\r\n",
+                    _ => "HTTP/1.1 400 Connection Failed\r\n\r\n",
+                };
                 let mut buf = BytesMut::from(response);
                 self.buffer.write_buf(&mut buf).await?;
             }
@@ -185,6 +224,8 @@ impl Reply {
         match err {
             Error::AddrTypeUnsupported(_) => ReplyCmd::CmdTypeUnsupported,
             Error::UnknownCmd(_) => ReplyCmd::CmdTypeUnsupported,
+            Error::Rejected => ReplyCmd::RulesNotAllowed,
+            Error::ConnectTimeout | Error::TlsHandshakeTimeout | Error::NoiseHandshakeTimeout => ReplyCmd::HostUnreachable,
             Error::IoErr(e) => {
                 match e.kind() {
                     ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => ReplyCmd::ConnectionRefused,
@@ -224,31 +265,29 @@ async fn write_addr(writer: &mut BufWriter<Vec<u8>>, addr: (AType, Option<IpAddr
     }
 }
 
-pub async fn recv_and_parse_req<RW>(io: &mut RW, authorized: bool)
+pub async fn recv_and_parse_req<RW>(io: &mut RW, authorized: bool, limit: Option<Duration>)
                                     -> Result<Option<ReqFrame>>
 where
     RW: AsyncRead + AsyncWrite + Unpin,
 {
     let mut buffer = BytesMut::with_capacity(128);
+    let deadline = limit.map(|limit| Instant::now() + limit);
     loop {
         let mut cursor = Cursor::new(&buffer[..]);
         if let Some(req) = pre_check_parsing(&mut cursor, authorized).await? {
             return Ok(Some(req));
         }
 
-        match timeout(Duration::from_secs(120), io.read_buf(&mut buffer)).await {
-            Ok(n) => {
-                if 0 == n? {
-                    return if buffer.is_empty() {
-                        Ok(None)
-                    } else {
-                        Err(Error::IoErr(tokio::io::Error::from(ErrorKind::ConnectionReset)))
-                    };
-                }
-            }
-            Err(_) => {
-                return Err(Error::Other("read from connection timeout".to_string()));
-            }
+        let n = match deadline {
+            Some(deadline) => timeout_at(deadline, io.read_buf(&mut buffer)).await.map_err(|_| Error::HandshakeTimeout)?,
+            None => io.read_buf(&mut buffer).await,
+        };
+        if 0 == n? {
+            return if buffer.is_empty() {
+                Ok(None)
+            } else {
+                Err(Error::IoErr(tokio::io::Error::from(ErrorKind::ConnectionReset)))
+            };
         }
 
     }
@@ -280,6 +319,15 @@ async fn parse_req(src: &mut Cursor<&[u8]>, authorized: bool) -> Result<ReqFrame
                 Ok(ReqFrame::Req(parse_req_v5(src, buf_reader).await?))
             }
         }
+        // the preface of a mux session
+        0 => {
+            let magic = [buf_reader.get_u8(src).await?, buf_reader.get_u8(src).await?, buf_reader.get_u8(src).await?];
+            if magic == mux::PREFACE[1..] {
+                Ok(ReqFrame::Mux)
+            } else {
+                Err(Error::VnUnsupported(n_ver))
+            }
+        }
         // HTTP CONNECT
         b'C' => {
             Ok(ReqFrame::Req(parse_req_http_connect(src, buf_reader).await?))
@@ -345,7 +393,7 @@ async fn parse_req_v5(src: &mut Cursor<&[u8]>, mut buf_reader: BufReader) -> Res
 
 async fn parse_req_http_connect(src: &mut Cursor<&[u8]>, mut buf_reader: BufReader) -> Result<Request> {
     let line = buf_reader.get_line(src).await?;
-    while buf_reader.get_line(src).await?.len() > 0 {}
+    while !buf_reader.get_line(src).await?.is_empty() {}
     let parts: Vec<&str> = line.split(' ').collect();
     if parts.len() != 3 {
         return Err(Error::Other("Bad Request".to_string()));
@@ -440,7 +488,7 @@ impl BufReader {
     }
 
     async fn get_u128(&mut self, src: &mut Cursor<&[u8]>) -> Result<u128> {
-        if src.remaining() < 128 {
+        if src.remaining() < 16 {
             return Err(Error::Incomplete);
         }
         let ret = src.get_u128();
@@ -482,6 +530,7 @@ impl BufReader {
 }
 
 #[cfg(test)]
+#[allow(unused_imports, unused_variables, clippy::assertions_on_constants)]
 mod test {
     use crate::protocol::{parse_req_http_connect, parse_req_v4, recv_and_parse_req, AType, BufReader, ReqCmd, ReqFrame, Request, Ver};
     use std::io::{BufWriter, Cursor};
@@ -690,7 +739,7 @@ mod test {
             half2: vec![0xa8, 1, 1, 0],
             half3: vec![]
         };
-        let ret = recv_and_parse_req(&mut io, true).await.unwrap();
+        let ret = recv_and_parse_req(&mut io, true, None).await.unwrap();
         assert_eq!(ret, Some(ReqFrame::Req(Request {
             ver: Ver::V4,
             cmd: ReqCmd::Connect,
@@ -714,7 +763,7 @@ mod test {
             half2: req2.as_bytes().to_vec(),
             half3: req3.as_bytes().to_vec(),
         };
-        let ret = recv_and_parse_req(&mut io, true).await.unwrap();
+        let ret = recv_and_parse_req(&mut io, true, None).await.unwrap();
         let mut raw: Vec<u8> = vec![];
         raw.put_slice(req1.as_bytes());
         raw.put_slice(req2.as_bytes());
@@ -730,4 +779,14 @@ mod test {
             raw,
         })))
     }
+
+    #[tokio::test]
+    async fn synthesized_connect() {
+        let dst = "[2001:db8::1]:443".parse().unwrap();
+        let req = Request::connect(dst);
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(req.raw()).await.unwrap();
+        let ret = recv_and_parse_req(&mut server, true, None).await.unwrap();
+        assert_eq!(ret, Some(ReqFrame::Req(req)));
+    }
 }
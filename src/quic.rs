@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{error, info};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Endpoint, RecvStream, SendStream, TransportConfig, VarInt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use crate::config::Timeouts;
+use crate::error::Error;
+use crate::outbound::ProxyCfg;
+use crate::tls::{Certs, ClientAuth};
+use crate::udp::{self, Addr};
+use crate::{config, dial, tls, Result};
+
+/// the ALPN id that nexel negotiates with nexeld for the QUIC transport.
+pub const ALPN: &[u8] = b"nexel";
+/// the idle QUIC connections are kept open, the peer closes them after 30 seconds without a packet.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+impl From<quinn::ConnectionError> for Error {
+    fn from(value: quinn::ConnectionError) -> Self {
+        Error::IoErr(value.into())
+    }
+}
+
+/// a tunnel carried in a bidirectional QUIC stream.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> QuicStream {
+        QuicStream { send, recv }
+    }
+
+    /// the stream id, both peers see the same one.
+    pub fn id(&self) -> u64 {
+        VarInt::from(self.send.id()).into_inner()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf).map_err(io::Error::from)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+/// the UDP associations of a QUIC connection, every datagram is prefixed with the id of the stream that controls its association.
+/// id | socks5 addr | payload
+pub struct Datagrams {
+    conn: quinn::Connection,
+    assocs: Mutex<HashMap<u64, mpsc::Sender<(Addr, Bytes)>>>,
+}
+
+impl std::fmt::Debug for Datagrams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Datagrams {{ remote = {}, associations = {} }}", self.conn.remote_address(), self.assocs.lock().unwrap().len())
+    }
+}
+
+impl Datagrams {
+    /// dispatches the received datagrams to their associations until the connection is closed.
+    pub fn new(conn: quinn::Connection) -> Arc<Datagrams> {
+        let datagrams = Arc::new(Datagrams { conn: conn.clone(), assocs: Mutex::new(HashMap::new()) });
+        let weak = Arc::downgrade(&datagrams);
+        tokio::spawn(async move {
+            while let Ok(mut datagram) = conn.read_datagram().await {
+                let Some(datagrams) = weak.upgrade() else { break };
+                if datagram.len() < 8 {
+                    continue;
+                }
+                let id = datagram.get_u64();
+                let Ok(addr) = udp::decode_addr(&mut datagram) else { continue };
+                let assoc = datagrams.assocs.lock().unwrap().get(&id).cloned();
+                if let Some(assoc) = assoc {
+                    // dropped like any datagram when the association falls behind
+                    let _ = assoc.try_send((addr, datagram));
+                }
+            }
+        });
+        datagrams
+    }
+
+    pub fn conn(&self) -> &quinn::Connection {
+        &self.conn
+    }
+
+    pub fn send(&self, id: u64, addr: &Addr, payload: &[u8]) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(8 + 19 + payload.len());
+        buf.put_u64(id);
+        udp::encode_addr(&mut buf, addr)?;
+        buf.put_slice(payload);
+        self.conn.send_datagram(buf.freeze()).map_err(|e| Error::Other(format!("sending datagram failed: {e}")))
+    }
+
+    pub fn register(&self, id: u64) -> mpsc::Receiver<(Addr, Bytes)> {
+        let (tx, rx) = mpsc::channel(udp::QUEUE);
+        self.assocs.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.assocs.lock().unwrap().remove(&id);
+    }
+}
+
+/// the client side of a UDP association, it's released when the control stream is dropped.
+pub struct Association {
+    datagrams: Arc<Datagrams>,
+    id: u64,
+    _control: QuicStream,
+}
+
+impl Association {
+    pub fn new(datagrams: Arc<Datagrams>, control: QuicStream) -> (Association, mpsc::Receiver<(Addr, Bytes)>) {
+        let id = control.id();
+        let rx = datagrams.register(id);
+        (Association { datagrams, id, _control: control }, rx)
+    }
+
+    pub fn send(&self, dst: &Addr, payload: &[u8]) -> Result<()> {
+        self.datagrams.send(self.id, dst, payload)
+    }
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.datagrams.unregister(self.id);
+    }
+}
+
+/// connects to the QUIC endpoint of nexeld, the server is verified the same way as over tls.
+pub async fn connect(proxy: &ProxyCfg, timeouts: &Timeouts) -> Result<quinn::Connection> {
+    config::within(timeouts.connect(), Error::ConnectTimeout, async {
+        let addr = dial::resolve(proxy.host(), proxy.port()).await?
+            .into_iter()
+            .next()
+            .ok_or(Error::Other(format!("{} has no address", proxy.host())))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let crypto = tls::client_config(&proxy.trust(), proxy.client_identity(), &[ALPN])?;
+        let crypto = QuicClientConfig::try_from(crypto).map_err(|e| Error::Other(e.to_string()))?;
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE));
+        let mut cfg = quinn::ClientConfig::new(Arc::new(crypto));
+        cfg.transport_config(Arc::new(transport));
+        let endpoint = Endpoint::client(local)?;
+        let conn = endpoint.connect_with(cfg, addr, proxy.sni())
+            .map_err(|e| Error::Other(e.to_string()))?
+            .await?;
+        Ok(conn)
+    }).await
+}
+
+/// binds the QUIC endpoint of nexeld with the certificates of its tls listener.
+pub fn server_endpoint(addr: SocketAddr, certs: Arc<Certs>, client_auth: Option<&ClientAuth>) -> Result<Endpoint> {
+    let crypto = tls::server_config(certs, client_auth, &[ALPN])?;
+    let crypto = QuicServerConfig::try_from(crypto).map_err(|e| Error::Other(e.to_string()))?;
+    Ok(Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?)
+}
+
+/// accepts the streams of a QUIC connection and hands each one over as a tunnel.
+pub async fn serve<F>(conn: quinn::Connection, mut handle: F) -> Result<()>
+where
+    F: FnMut(QuicStream, Arc<Datagrams>),
+{
+    info!("[QUIC] connection started, remote = {}", conn.remote_address());
+    let datagrams = Datagrams::new(conn.clone());
+    loop {
+        match conn.accept_bi().await {
+            Ok((send, recv)) => handle(QuicStream::new(send, recv), datagrams.clone()),
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(e) => {
+                error!("[QUIC] connection failed: {}", e);
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// the subject of the certificate that the client presented.
+pub fn client_subject(conn: &quinn::Connection) -> Option<String> {
+    let certs = conn.peer_identity()?.downcast::<Vec<pki_types::CertificateDer<'static>>>().ok()?;
+    tls::cert_subject(certs.first()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use crate::connection::Connection;
+    use crate::outbound::Outbounds;
+    use crate::test_util::{quic_proxy, spawn_echo, spawn_quic_server};
+    use crate::tls::tests::write_pki;
+    use crate::udp::{parse_socks5, socks5_packet, Addr};
+    use crate::upstream::http_connect;
+
+    #[tokio::test]
+    async fn tunnels_share_a_connection() {
+        let dir = std::env::temp_dir().join(format!("nexel-quic-{}", uuid::Uuid::new_v4()));
+        write_pki(&dir);
+        let echo_port = spawn_echo().await;
+        let (port, accepted) = spawn_quic_server(&dir);
+        let proxy = quic_proxy(&dir, port);
+        let mut outbounds = Outbounds::new();
+        outbounds.insert_proxy(proxy.clone()).unwrap();
+
+        let mut streams = Vec::new();
+        for i in 0..3u8 {
+            let mut stream = outbounds.connect(&proxy).await.unwrap();
+            http_connect(&mut stream, "127.0.0.1", echo_port, None).await.unwrap();
+            let data = vec![i; 100_000];
+            stream.write_all(&data).await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+            streams.push(stream);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_udp_associate() {
+        let dir = std::env::temp_dir().join(format!("nexel-quic-{}", uuid::Uuid::new_v4()));
+        write_pki(&dir);
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        let (port, _) = spawn_quic_server(&dir);
+        let mut outbounds = Outbounds::new();
+        outbounds.insert_proxy(quic_proxy(&dir, port)).unwrap();
+        let outbounds = Arc::new(outbounds);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let nexel = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let local_addr = socket.local_addr().unwrap();
+            let mut conn = Connection::new(socket, Some(outbounds));
+            conn.set_local_addr(local_addr);
+            conn.run().await
+        });
+
+        let mut control = TcpStream::connect(nexel).await.unwrap();
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0u8; 2];
+        control.read_exact(&mut buf).await.unwrap();
+        control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        let relay = std::net::SocketAddr::from(([reply[4], reply[5], reply[6], reply[7]], u16::from_be_bytes([reply[8], reply[9]])));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&socks5_packet(&Addr::Ip(echo_addr), b"nexel").unwrap(), relay).await.unwrap();
+        let mut buf = [0u8; 1500];
+        let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+        let (src, payload) = parse_socks5(&buf[..n]).unwrap();
+        assert_eq!(src, Addr::Ip(echo_addr));
+        assert_eq!(&payload[..], b"nexel");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use tokio::net::TcpStream;
+
+/// the destination that the connection had before iptables or nftables REDIRECT sent it here,
+/// it's SO_ORIGINAL_DST of netfilter, or IP6T_SO_ORIGINAL_DST for IPv6.
+#[cfg(target_os = "linux")]
+pub fn original_dst(socket: &TcpStream) -> io::Result<SocketAddr> {
+    use std::os::fd::AsRawFd;
+
+    let local = socket.local_addr()?;
+    // an IPv4 peer of a dual stack listener was redirected by the IPv4 table
+    let v6 = matches!(local, SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_none());
+    let (level, name) = if v6 {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    };
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(socket.as_raw_fd(), level, name, &mut storage as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let dst = socket_addr(&storage)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "original destination had an unknown family"))?;
+    // a connection made to the port itself would be dialed back to nexel forever
+    if dst == local {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection wasn't redirected"));
+    }
+    Ok(dst)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_socket: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "redirected connections are only served on linux"))
+}
+
+/// the address in a sockaddr that the kernel filled in.
+#[cfg(target_os = "linux")]
+pub(crate) fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sa = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sa.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sa = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sa.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sa.sin6_port), sa.sin6_flowinfo, sa.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::redir::original_dst;
+
+    #[tokio::test]
+    async fn not_redirected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        // without a redirect rule there's nothing but the port itself
+        assert!(original_dst(&socket).is_err());
+    }
+}
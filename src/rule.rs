@@ -6,23 +6,39 @@ use std::sync::Mutex;
 use serde::Deserialize;
 use serde_yml;
 use crate::error::Error;
+use crate::outbound;
 use std::str::FromStr;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Routing {
     Direct,
-    Proxy,
-    Reject, // actually, haven't used it yet.
+    Reject,
+    Proxy(String), // the name of a proxy or a proxy group
 }
+
+impl Routing {
+    pub fn proxy() -> Routing {
+        Routing::Proxy(outbound::PROXY.to_string())
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Routing::Direct => outbound::DIRECT,
+            Routing::Reject => outbound::REJECT,
+            Routing::Proxy(name) => name,
+        }
+    }
+}
+
 impl TryFrom<&str> for Routing {
     type Error = Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
-            "DIRECT" => Ok(Routing::Direct),
-            "PROXY" => Ok(Routing::Proxy),
-            "REJECT" => Ok(Routing::Reject),
-            _ => Err(Error::Other("unknown routing".to_string()))
+            outbound::DIRECT => Ok(Routing::Direct),
+            outbound::REJECT => Ok(Routing::Reject),
+            "" | "_" => Err(Error::Other("unknown routing".to_string())),
+            name => Ok(Routing::Proxy(name.to_string())),
         }
     }
 }
@@ -31,81 +47,194 @@ struct Rule {
     rules: Vec<String>,
 }
 
-lazy_static! {
-    static ref domain_set: Mutex<HashMap<String, Routing>> = Mutex::new(HashMap::new());
-    static ref domain_suffix_set: Mutex<HashMap<String, Routing>> = Mutex::new(HashMap::new());
-    static ref domain_keyword_set: Mutex<HashMap<String, Routing>> = Mutex::new(HashMap::new());
-    static ref ip_cidr: Mutex<HashMap<ipnetwork::IpNetwork, Routing>> = Mutex::new(HashMap::new());
-    static ref ip_cidr6: Mutex<HashMap<ipnetwork::IpNetwork, Routing>> = Mutex::new(HashMap::new());
-    static ref geo_ip: Mutex<HashMap<String, Routing>> = Mutex::new(HashMap::new());
-
-    static ref maxmindb_reader: Mutex<Option<maxminddb::Reader<Vec<u8>>>> = Mutex::new(None);
-    // {
-    //     let reader = maxminddb::Reader::open_readfile(PathBuf::from("GeoLite2-Country.mmdb")).unwrap();
-    //     Mutex::new(reader)
-    // };
+/// the tables of the client rules in rule.yaml.
+#[derive(Debug)]
+pub struct RuleSet {
+    domain_set: HashMap<String, Routing>,
+    domain_suffix_set: HashMap<String, Routing>,
+    domain_keyword_set: HashMap<String, Routing>,
+    ip_cidr: HashMap<ipnetwork::IpNetwork, Routing>,
+    ip_cidr6: HashMap<ipnetwork::IpNetwork, Routing>,
+    geo_ip: HashMap<String, Routing>,
+    dst_port: HashMap<u16, Routing>,
+    final_routing: Routing,
 }
 
-pub fn initial(rule_yaml: &String, maxmindb_path: &String) -> Result<(), Box<dyn std::error::Error>> {
-    let reader = maxminddb::Reader::open_readfile(PathBuf::from(maxmindb_path))?;
-    *maxmindb_reader.lock().unwrap() = Some(reader);
+impl RuleSet {
+    pub fn new(final_routing: Routing) -> RuleSet {
+        RuleSet {
+            domain_set: HashMap::new(),
+            domain_suffix_set: HashMap::new(),
+            domain_keyword_set: HashMap::new(),
+            ip_cidr: HashMap::new(),
+            ip_cidr6: HashMap::new(),
+            geo_ip: HashMap::new(),
+            dst_port: HashMap::new(),
+            final_routing,
+        }
+    }
 
-    let rule_yaml = std::fs::File::open(rule_yaml)?;
-    let rule: Rule = serde_yml::from_reader(rule_yaml)?;
-    for item in rule.rules {
+    /// parses a rule like `DOMAIN-SUFFIX,google.com,PROXY`, unknown kinds are skipped.
+    pub fn insert(&mut self, item: &str) -> Result<(), Box<dyn std::error::Error>> {
         let mut split_iter = item.split(',');
         let kind = split_iter.next().unwrap_or("_");
         let content = split_iter.next().unwrap_or("_");
         let routing = split_iter.next().unwrap_or("_");
         match kind {
             "DOMAIN" => {
-                domain_set.lock().unwrap().insert(content.to_string().clone(), Routing::try_from(routing)?);
+                self.domain_set.insert(content.to_string(), Routing::try_from(routing)?);
             }
             "DOMAIN-SUFFIX" => {
-                domain_suffix_set.lock().unwrap().insert(content.to_string().clone(), Routing::try_from(routing)?);
+                self.domain_suffix_set.insert(content.to_string(), Routing::try_from(routing)?);
             }
             "DOMAIN-KEYWORD" => {
-                domain_keyword_set.lock().unwrap().insert(content.to_string().clone(), Routing::try_from(routing)?);
+                self.domain_keyword_set.insert(content.to_string(), Routing::try_from(routing)?);
             }
             "IP-CIDR" => {
                 let cidr = ipnetwork::IpNetwork::from_str(content)?;
-                ip_cidr.lock().unwrap().insert(cidr, Routing::try_from(routing)?);
+                self.ip_cidr.insert(cidr, Routing::try_from(routing)?);
             }
             "IP-CIDR6" => {
                 let cidr = ipnetwork::IpNetwork::from_str(content)?;
-                ip_cidr6.lock().unwrap().insert(cidr, Routing::try_from(routing)?);
+                self.ip_cidr6.insert(cidr, Routing::try_from(routing)?);
+            }
+            "GEOIP" => {
+                self.geo_ip.insert(content.to_string(), Routing::try_from(routing)?);
+            }
+            "DST-PORT" => {
+                self.dst_port.insert(content.parse()?, Routing::try_from(routing)?);
             }
-            _ => continue,
+            "MATCH" => {
+                self.final_routing = Routing::try_from(content)?;
+            }
+            _ => {}
         };
+        Ok(())
     }
-    Ok(())
-}
 
-pub async fn domain(domain: &str) -> crate::Result<Routing> {
-    if let Some(routing) = domain_set.lock().unwrap().get(domain) {
-        return Ok(routing.clone());
+    /// every routing that the rules refer to.
+    pub fn routings(&self) -> Vec<Routing> {
+        let mut routings = vec![self.final_routing.clone()];
+        routings.extend(self.domain_set.values().cloned());
+        routings.extend(self.domain_suffix_set.values().cloned());
+        routings.extend(self.domain_keyword_set.values().cloned());
+        routings.extend(self.ip_cidr.values().cloned());
+        routings.extend(self.ip_cidr6.values().cloned());
+        routings.extend(self.geo_ip.values().cloned());
+        routings.extend(self.dst_port.values().cloned());
+        routings
     }
-    for (suffix, routing) in domain_suffix_set.lock().unwrap().iter() {
-        if domain_ends_with(&domain.to_string(), &suffix) {
-            return Ok(routing.clone());
+
+    pub fn match_domain(&self, domain: &str) -> Option<Routing> {
+        if let Some(routing) = self.domain_set.get(domain) {
+            return Some(routing.clone());
         }
+        for (suffix, routing) in self.domain_suffix_set.iter() {
+            if domain_ends_with(domain, suffix) {
+                return Some(routing.clone());
+            }
+        }
+        for (keyword, routing) in self.domain_keyword_set.iter() {
+            if domain.contains(keyword) {
+                return Some(routing.clone());
+            }
+        }
+        None
+    }
+
+    /// without the domain rules, a domain is routed the way its ip is.
+    pub fn has_domain_rules(&self) -> bool {
+        !self.domain_set.is_empty() || !self.domain_suffix_set.is_empty() || !self.domain_keyword_set.is_empty()
     }
-    for (keyword, routing) in domain_keyword_set.lock().unwrap().iter() {
-        if domain.contains(keyword) {
-            return Ok(routing.clone());
+
+    pub fn match_ip(&self, ip: IpAddr) -> Option<Routing> {
+        let cidr_ip_list = match ip {
+            IpAddr::V4(_) => &self.ip_cidr,
+            IpAddr::V6(_) => &self.ip_cidr6,
+        };
+        for (cidr, routing) in cidr_ip_list.iter() {
+            if cidr.contains(ip) {
+                return Some(routing.clone());
+            }
         }
+        None
+    }
+
+    pub fn match_country(&self, iso_code: &str) -> Option<Routing> {
+        self.geo_ip.get(iso_code).cloned()
+    }
+
+    pub fn match_port(&self, port: u16) -> Option<Routing> {
+        self.dst_port.get(&port).cloned()
+    }
+
+    pub fn final_routing(&self) -> Routing {
+        self.final_routing.clone()
+    }
+}
+
+lazy_static! {
+    static ref rule_set: Mutex<RuleSet> = Mutex::new(RuleSet::new(Routing::proxy()));
+
+    static ref maxmindb_reader: Mutex<Option<maxminddb::Reader<Vec<u8>>>> = Mutex::new(None);
+    // {
+    //     let reader = maxminddb::Reader::open_readfile(PathBuf::from("GeoLite2-Country.mmdb")).unwrap();
+    //     Mutex::new(reader)
+    // };
+}
+
+pub fn initial(rule_yaml: &String, maxmindb_path: &String) -> Result<(), Box<dyn std::error::Error>> {
+    let reader = maxminddb::Reader::open_readfile(PathBuf::from(maxmindb_path))?;
+    *maxmindb_reader.lock().unwrap() = Some(reader);
+
+    let rule_yaml = std::fs::File::open(rule_yaml)?;
+    let rule: Rule = serde_yml::from_reader(rule_yaml)?;
+    let mut rules = rule_set.lock().unwrap();
+    for item in rule.rules {
+        rules.insert(&item)?;
+    }
+    Ok(())
+}
+
+/// every outbound name that the loaded rules refer to.
+pub fn targets() -> Vec<String> {
+    let names = rule_set.lock().unwrap().routings();
+    let mut names: Vec<String> = names.iter().map(|r| r.name().to_string()).collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+pub async fn domain(domain: &str) -> crate::Result<Routing> {
+    let matched = rule_set.lock().unwrap().match_domain(domain);
+    if let Some(routing) = matched {
+        return Ok(routing);
     }
 
     let mut addrs_iter = tokio::net::lookup_host(format!("{}:{}", domain, 1234)).await?;
 
     if let Some(routing) = addrs_iter.next().map(|ret| ip(ret.ip())) {
-        return Ok(routing.clone());
+        return Ok(routing);
     }
 
-    Ok(Routing::Proxy)
+    Ok(rule_set.lock().unwrap().final_routing())
+}
+
+/// the routing of the DST-PORT rules, they're checked before the domain and ip ones.
+pub fn port(port: u16) -> Option<Routing> {
+    rule_set.lock().unwrap().match_port(port)
+}
+
+/// the routing of the domain rules alone, the domain isn't resolved for the ip rules.
+pub fn domain_rules(domain: &str) -> Option<Routing> {
+    rule_set.lock().unwrap().match_domain(domain)
+}
+
+pub fn has_domain_rules() -> bool {
+    rule_set.lock().unwrap().has_domain_rules()
 }
 
-fn domain_ends_with(domain: &String, suffix: &String) -> bool {
+pub(crate) fn domain_ends_with(domain: &str, suffix: &str) -> bool {
     let parts = domain.split('.');
     let mut segment = String::new();
     for part in parts.rev() {
@@ -119,53 +248,58 @@ fn domain_ends_with(domain: &String, suffix: &String) -> bool {
 }
 
 pub fn ip(ip: IpAddr) -> Routing {
-    let cidr_ip_list = match ip {
-        IpAddr::V4(_) => ip_cidr.lock().unwrap(),
-        IpAddr::V6(_) => ip_cidr6.lock().unwrap()
-    };
-    for (cidr, routing) in cidr_ip_list.iter() {
-        if cidr.contains(ip) {
-            return routing.clone();
-        }
+    let rules = rule_set.lock().unwrap();
+    if let Some(routing) = rules.match_ip(ip) {
+        return routing;
     }
 
     if let Some(ref mmdb) = *maxmindb_reader.lock().unwrap() {
         if let Ok(country) =
             mmdb.lookup::<maxminddb::geoip2::Country>(ip) {
             if let Some(c) = country.country {
-                if c.iso_code.unwrap_or("_") == "CN" {
+                let iso_code = c.iso_code.unwrap_or("_");
+                if let Some(routing) = rules.match_country(iso_code) {
+                    return routing;
+                }
+                if iso_code == "CN" {
                     return Routing::Direct;
                 }
             }
         }
     }
 
-    Routing::Proxy
+    rules.final_routing()
 }
 
 #[cfg(test)]
-mod tests {
+#[allow(clippy::bool_assert_comparison, clippy::unnecessary_to_owned)]
+pub(crate) mod tests {
     use std::net::{IpAddr, Ipv4Addr};
     use std::str::FromStr;
     use std::path::PathBuf;
     use crate::rule;
     use crate::rule::{domain_ends_with, Routing};
 
+    /// adds a rule to the ones loaded, the tests that route have to take domains of their own.
+    pub(crate) fn insert(item: &str) {
+        rule::rule_set.lock().unwrap().insert(item).unwrap();
+    }
+
     #[tokio::test]
     async fn check_domain() {
         let r = String::from("rule.yaml");
         let g = String::from("GeoLite2-Country.mmdb");
         rule::initial(&r, &g).unwrap();
-        assert_eq!(rule::domain("itunes.apple.com").await.unwrap(), Routing::Proxy);
+        assert_eq!(rule::domain("itunes.apple.com").await.unwrap(), Routing::proxy());
         assert_eq!(rule::domain("www.163.com").await.unwrap(), Routing::Direct);
         assert_eq!(rule::domain("pan.baidu.com").await.unwrap(), Routing::Direct);
-        assert_eq!(rule::domain("clients4.google.com").await.unwrap(), Routing::Proxy);
-        assert_eq!(rule::domain("javbooks.com").await.unwrap(), Routing::Proxy);
+        assert_eq!(rule::domain("clients4.google.com").await.unwrap(), Routing::proxy());
+        assert_eq!(rule::domain("javbooks.com").await.unwrap(), Routing::proxy());
         assert_eq!(rule::domain("localhost").await.unwrap(), Routing::Direct);
-        assert_eq!(rule::domain("www.google.com").await.unwrap(), Routing::Proxy);
+        assert_eq!(rule::domain("www.google.com").await.unwrap(), Routing::proxy());
         assert_eq!(rule::domain("th.bing.com").await.unwrap(), Routing::Direct);
-        assert_eq!(rule::domain("appleid.apple.com").await.unwrap(), Routing::Proxy);
-        assert_eq!(rule::domain("t.me").await.unwrap(), Routing::Proxy);
+        assert_eq!(rule::domain("appleid.apple.com").await.unwrap(), Routing::proxy());
+        assert_eq!(rule::domain("t.me").await.unwrap(), Routing::proxy());
     }
 
     #[test]
@@ -206,6 +340,15 @@ mod tests {
         assert_eq!(domain_ends_with(&domain.to_string(), &suffix.to_string()), false);
     }
 
+    #[test]
+    fn parse_routing() {
+        assert_eq!(Routing::try_from("DIRECT").unwrap(), Routing::Direct);
+        assert_eq!(Routing::try_from("REJECT").unwrap(), Routing::Reject);
+        assert_eq!(Routing::try_from("HK-Servers").unwrap(), Routing::Proxy("HK-Servers".to_string()));
+        assert_eq!(Routing::try_from("PROXY").unwrap().name(), "PROXY");
+        assert!(Routing::try_from("_").is_err());
+    }
+
     #[test]
     fn test_rfind() {
         let domain = "clients4.google.com";
@@ -217,7 +360,7 @@ mod tests {
         let r = String::from("rule.yaml");
         let g = String::from("GeoLite2-Country.mmdb");
         rule::initial(&r, &g).unwrap();
-        assert_eq!(rule::ip("8.220.210.182".parse::<IpAddr>().unwrap()), Routing::Proxy);
+        assert_eq!(rule::ip("8.220.210.182".parse::<IpAddr>().unwrap()), Routing::proxy());
     }
 
     #[test]
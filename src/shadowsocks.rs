@@ -0,0 +1,640 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use aws_lc_rs::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use base64::Engine;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::Instant;
+use crate::error::Error;
+use crate::udp::{self, Addr};
+use crate::Result;
+
+const SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD: usize = 0xffff;
+const MAX_PADDING: usize = 900;
+/// the requests and responses whose timestamp is further off are rejected.
+const MAX_SKEW: u64 = 30;
+/// the salts are remembered for longer than a timestamp stays valid, so a replayed request is always caught.
+const SALT_TTL: Duration = Duration::from_secs(60);
+const READ_CHUNK: usize = 16 * 1024;
+
+const CLIENT_STREAM: u8 = 0;
+const SERVER_STREAM: u8 = 1;
+
+/// the AEAD 2022 methods of SIP022, the pre-shared key is the base64 of a key as long as the cipher's.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+pub enum Method {
+    #[serde(rename = "2022-blake3-aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "2022-blake3-aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "2022-blake3-chacha20-poly1305")]
+    Chacha20Poly1305,
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Method::Aes128Gcm => write!(f, "2022-blake3-aes-128-gcm"),
+            Method::Aes256Gcm => write!(f, "2022-blake3-aes-256-gcm"),
+            Method::Chacha20Poly1305 => write!(f, "2022-blake3-chacha20-poly1305"),
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Method> {
+        match s {
+            "2022-blake3-aes-128-gcm" => Ok(Method::Aes128Gcm),
+            "2022-blake3-aes-256-gcm" => Ok(Method::Aes256Gcm),
+            "2022-blake3-chacha20-poly1305" => Ok(Method::Chacha20Poly1305),
+            _ => Err(Error::Other(format!("unknown shadowsocks method {s}"))),
+        }
+    }
+}
+
+impl Method {
+    /// the length of the key, and of the salt as well.
+    pub fn key_len(&self) -> usize {
+        match self {
+            Method::Aes128Gcm => 16,
+            Method::Aes256Gcm | Method::Chacha20Poly1305 => 32,
+        }
+    }
+
+    fn algorithm(&self) -> &'static aead::Algorithm {
+        match self {
+            Method::Aes128Gcm => &aead::AES_128_GCM,
+            Method::Aes256Gcm => &aead::AES_256_GCM,
+            Method::Chacha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// the method and the decoded pre-shared key.
+#[derive(Clone, Debug)]
+pub struct Key {
+    method: Method,
+    psk: Vec<u8>,
+}
+
+impl Key {
+    pub fn new(method: Method, password: &str) -> Result<Key> {
+        let psk = base64::engine::general_purpose::STANDARD.decode(password)
+            .map_err(|e| Error::Other(format!("bad shadowsocks key: {e}")))?;
+        if psk.len() != method.key_len() {
+            return Err(Error::Other(format!("{method} takes a key of {} bytes", method.key_len())));
+        }
+        Ok(Key { method, psk })
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// the key of a session, derived from the pre-shared key and the salt of the session.
+    fn session(&self, salt: &[u8]) -> Cipher {
+        let material = [&self.psk[..], salt].concat();
+        let subkey = blake3::derive_key(SUBKEY_CONTEXT, &material);
+        let key = UnboundKey::new(self.method.algorithm(), &subkey[..self.method.key_len()]).unwrap();
+        Cipher { key: LessSafeKey::new(key), counter: 0 }
+    }
+
+    fn salt(&self) -> Vec<u8> {
+        let mut salt = vec![0u8; self.method.key_len()];
+        aws_lc_rs::rand::fill(&mut salt).unwrap();
+        salt
+    }
+}
+
+/// the key of one direction of a session, the nonce is a little endian counter of the AEAD operations.
+struct Cipher {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Cipher {
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn seal(&mut self, out: &mut BytesMut, plain: &[u8]) {
+        let mut buf = plain.to_vec();
+        let nonce = self.nonce();
+        self.key.seal_in_place_append_tag(nonce, Aad::empty(), &mut buf).unwrap();
+        out.put_slice(&buf);
+    }
+
+    fn open(&mut self, mut sealed: BytesMut) -> io::Result<BytesMut> {
+        let nonce = self.nonce();
+        let len = self.key.open_in_place(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "shadowsocks chunk failed to decrypt"))?
+            .len();
+        sealed.truncate(len);
+        Ok(sealed)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn check_timestamp(timestamp: u64, now: u64) -> io::Result<()> {
+    if now.abs_diff(timestamp) > MAX_SKEW {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "shadowsocks timestamp was out of range"));
+    }
+    Ok(())
+}
+
+/// the salts of the sessions seen lately.
+#[derive(Default)]
+pub struct ReplayFilter {
+    salts: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl ReplayFilter {
+    /// false if the salt was seen already.
+    pub fn insert(&self, salt: &[u8]) -> bool {
+        let mut salts = self.salts.lock().unwrap();
+        salts.retain(|_, seen| seen.elapsed() < SALT_TTL);
+        if salts.contains_key(salt) {
+            return false;
+        }
+        salts.insert(salt.to_vec(), Instant::now());
+        true
+    }
+}
+
+/// the salt and the headers of a request, the variable length one is the address, the padding and the initial payload.
+fn seal_request(key: &Key, salt: &[u8], timestamp: u64, var: &[u8]) -> (Cipher, BytesMut) {
+    let mut enc = key.session(salt);
+    let mut out = BytesMut::from(salt);
+    let mut fixed = BytesMut::with_capacity(11);
+    fixed.put_u8(CLIENT_STREAM);
+    fixed.put_u64(timestamp);
+    fixed.put_u16(var.len() as u16);
+    enc.seal(&mut out, &fixed);
+    enc.seal(&mut out, var);
+    (enc, out)
+}
+
+/// the salt and the header of a response, which binds it to the salt of the request, and its first payload.
+fn seal_response(key: &Key, salt: &[u8], timestamp: u64, request_salt: &[u8], payload: &[u8]) -> (Cipher, BytesMut) {
+    let mut enc = key.session(salt);
+    let mut out = BytesMut::from(salt);
+    let mut fixed = BytesMut::with_capacity(11 + salt.len());
+    fixed.put_u8(SERVER_STREAM);
+    fixed.put_u64(timestamp);
+    fixed.put_slice(request_salt);
+    fixed.put_u16(payload.len() as u16);
+    enc.seal(&mut out, &fixed);
+    enc.seal(&mut out, payload);
+    (enc, out)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ReadState {
+    Salt,   // the client waits for the salt of the response
+    Header, // and then for its fixed length header
+    Length,
+    Payload(usize),
+}
+
+/// a shadowsocks 2022 TCP stream. the first write of a client is the target address in the socks5 format,
+/// it's sent in the request header with a random padding.
+pub struct SsStream<RW> {
+    inner: RW,
+    key: Key,
+    client: bool,
+    request_salt: Vec<u8>,
+    enc: Option<Cipher>, // set once the header was written
+    wbuf: BytesMut,      // sealed but not written yet
+    dec: Option<Cipher>,
+    state: ReadState,
+    rbuf: BytesMut,
+    plain: BytesMut, // opened but not read yet
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> SsStream<RW> {
+    pub fn client(inner: RW, key: Key) -> SsStream<RW> {
+        let request_salt = key.salt();
+        SsStream {
+            inner,
+            key,
+            client: true,
+            request_salt,
+            enc: None,
+            wbuf: BytesMut::new(),
+            dec: None,
+            state: ReadState::Salt,
+            rbuf: BytesMut::new(),
+            plain: BytesMut::new(),
+        }
+    }
+
+    fn request_header(&mut self, addr: &[u8]) {
+        let mut padding = [0u8; 2];
+        aws_lc_rs::rand::fill(&mut padding).unwrap();
+        let padding = 1 + u16::from_be_bytes(padding) as usize % MAX_PADDING;
+        let mut var = BytesMut::with_capacity(addr.len() + 2 + padding);
+        var.put_slice(addr);
+        var.put_u16(padding as u16);
+        var.resize(var.len() + padding, 0);
+        let (enc, sealed) = seal_request(&self.key, &self.request_salt, now(), &var);
+        self.wbuf.put_slice(&sealed);
+        self.enc = Some(enc);
+    }
+
+    fn response_header(&mut self, payload: &[u8]) {
+        let (enc, sealed) = seal_response(&self.key, &self.key.salt(), now(), &self.request_salt, payload);
+        self.wbuf.put_slice(&sealed);
+        self.enc = Some(enc);
+    }
+
+    /// opens the next piece of the stream, false if it isn't received in full yet.
+    fn open_next(&mut self) -> io::Result<bool> {
+        let salt_len = self.key.method.key_len();
+        let need = match self.state {
+            ReadState::Salt => salt_len,
+            ReadState::Header => 1 + 8 + salt_len + 2 + TAG_LEN,
+            ReadState::Length => 2 + TAG_LEN,
+            ReadState::Payload(len) => len + TAG_LEN,
+        };
+        if self.rbuf.len() < need {
+            return Ok(false);
+        }
+        let data = self.rbuf.split_to(need);
+        if self.state == ReadState::Salt {
+            self.dec = Some(self.key.session(&data));
+            self.state = ReadState::Header;
+            return Ok(true);
+        }
+        let mut data = self.dec.as_mut().unwrap().open(data)?;
+        self.state = match self.state {
+            ReadState::Header => {
+                let kind = data.get_u8();
+                check_timestamp(data.get_u64(), now())?;
+                let request_salt = data.split_to(salt_len);
+                if kind != SERVER_STREAM || request_salt[..] != self.request_salt[..] {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "shadowsocks response didn't match the request"));
+                }
+                ReadState::Payload(data.get_u16() as usize)
+            }
+            ReadState::Length => ReadState::Payload(data.get_u16() as usize),
+            _ => {
+                self.plain = data;
+                ReadState::Length
+            }
+        };
+        Ok(true)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncRead for SsStream<RW> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.plain.is_empty() {
+                let n = this.plain.len().min(buf.remaining());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.open_next()? {
+                continue;
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                let between_chunks = matches!(this.state, ReadState::Length | ReadState::Salt);
+                return if between_chunks && this.rbuf.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.rbuf.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncWrite for SsStream<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = match &mut this.enc {
+            Some(enc) => {
+                let n = buf.len().min(MAX_PAYLOAD);
+                enc.seal(&mut this.wbuf, &(n as u16).to_be_bytes());
+                enc.seal(&mut this.wbuf, &buf[..n]);
+                n
+            }
+            None if this.client => {
+                this.request_header(buf);
+                buf.len()
+            }
+            None => {
+                let n = buf.len().min(MAX_PAYLOAD);
+                this.response_header(&buf[..n]);
+                n
+            }
+        };
+        // sent right away when the inner stream can take it, the rest goes with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// asks the shadowsocks server on the other side of the stream to open a tunnel to host:port.
+pub async fn request<W>(stream: &mut W, host: &str, port: u16) -> Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let addr = match host.parse() {
+        Ok(ip) => Addr::Ip(std::net::SocketAddr::new(ip, port)),
+        Err(_) => Addr::Domain(host.to_string(), port),
+    };
+    let mut buf = BytesMut::new();
+    udp::encode_addr(&mut buf, &addr)?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// the server side of shadowsocks, the salts of the requests are remembered to reject replays.
+pub struct Inbound {
+    key: Key,
+    replay: ReplayFilter,
+}
+
+impl Inbound {
+    pub fn new(key: Key) -> Inbound {
+        Inbound { key, replay: ReplayFilter::default() }
+    }
+
+    /// reads the request header, the initial payload is read from the returned stream.
+    pub async fn accept<RW>(&self, inner: RW) -> Result<(SsStream<RW>, Addr)>
+    where
+        RW: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.accept_at(inner, now()).await
+    }
+
+    async fn accept_at<RW>(&self, mut inner: RW, now: u64) -> Result<(SsStream<RW>, Addr)>
+    where
+        RW: AsyncRead + AsyncWrite + Unpin,
+    {
+        let salt_len = self.key.method.key_len();
+        let mut salt = vec![0u8; salt_len];
+        inner.read_exact(&mut salt).await?;
+        let mut dec = self.key.session(&salt);
+        let mut fixed = BytesMut::zeroed(1 + 8 + 2 + TAG_LEN);
+        inner.read_exact(&mut fixed).await?;
+        let mut fixed = dec.open(fixed)?;
+        if fixed.get_u8() != CLIENT_STREAM {
+            return Err(Error::Other("shadowsocks request had a bad stream type".to_string()));
+        }
+        check_timestamp(fixed.get_u64(), now)?;
+        // only a peer that holds the key gets this far, so a bogus salt can't be planted
+        if !self.replay.insert(&salt) {
+            return Err(Error::Other("shadowsocks request was replayed".to_string()));
+        }
+        let mut var = BytesMut::zeroed(fixed.get_u16() as usize + TAG_LEN);
+        inner.read_exact(&mut var).await?;
+        let mut var = Bytes::from(dec.open(var)?);
+        let addr = udp::decode_addr(&mut var)?;
+        if var.len() < 2 {
+            return Err(Error::Incomplete);
+        }
+        let padding = var.get_u16() as usize;
+        if padding > MAX_PADDING || var.len() < padding {
+            return Err(Error::Other("shadowsocks request had a bad padding".to_string()));
+        }
+        var.advance(padding);
+        let stream = SsStream {
+            inner,
+            key: self.key.clone(),
+            client: false,
+            request_salt: salt,
+            enc: None,
+            wbuf: BytesMut::new(),
+            dec: Some(dec),
+            state: ReadState::Length,
+            rbuf: BytesMut::new(),
+            plain: BytesMut::from(&var[..]),
+        };
+        Ok((stream, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use bytes::{Buf, BytesMut};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::connection::Connection;
+    use crate::outbound::{Outbounds, ProxyCfg};
+    use crate::shadowsocks::{request, seal_request, seal_response, Inbound, Key, Method, SsStream, CLIENT_STREAM, SERVER_STREAM, TAG_LEN};
+    use crate::test_util::{assert_echo, spawn_echo};
+    use crate::udp::Addr;
+    use crate::upstream;
+
+    const KEY_256: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const KEY_128: &str = "AAECAwQFBgcICQoLDA0ODw==";
+
+    /// captured from shadowsocks-rust 1.25.0: its client asks for nexel.cc:443 with `GET / HTTP/1.1\r\n\r\n` as the first
+    /// payload, which goes into the request header without padding, and its server answers `HTTP/1.1 204 No Content\r\n\r\n`.
+    const VECTORS: &[(Method, &str, &str, &str)] = &[
+        (
+            Method::Aes128Gcm,
+            KEY_128,
+            "e91878967a9bb8f55764b172d0a01843aa78fd043323572a4a0ce3809d32cae2da531b2d4afcd519c8b5774207de552c895789bc27325cb57a\
+             5414780da7889f7dc6c8cfc66f2d4f140064f48c775076f2934fb4cb3aee2c06ec5b",
+            "f3a025f40fbda3f0c8c8d0773c6b264fce62512115a70c54607097f9ab135775ca03e0a7ceabcb86bf9a19752eff0399ba468f45a71ed606\
+             60a122aef4e8b9ae1f193ec6c2aa58be5987f983ae8879ed515ca9693705da7d55e48c23c477cf5588d8603b2038",
+        ),
+        (
+            Method::Aes256Gcm,
+            KEY_256,
+            "8107aabbbe5643d8377bb86de932babfe0ce0255ca09e607643f7c00b78d0da1ff6e46203479c2dd2c1da92307024155a68fd3bd0fd27355\
+             311937478340013c862e554ddfeda392fb05568cca8b419a3f39b4e35c8e8eb32b403ee2f3502ac1f6b2aac8555a3128d1ea2a",
+            "d26bf133a886049fe7ba15f47d58ed6be4fdb185dc3a244e24153e530201ccec2969db8cd956e7a8d953bb66bf4929b134194b8a94024bea\
+             86705d04f4e622e8769d1ce36e45f59de30f34a32ffb0dd4e368bec061c70222d0ba7e01e7adf1728600b759ec63dd339131776293d8d4dd\
+             865ef1eb0f35ae3e062e2e823b8b13a72afeea3f02ed",
+        ),
+        (
+            Method::Chacha20Poly1305,
+            KEY_256,
+            "8b5ba6ccec3ad5f5266fd93b1337936d622014b3c5d225a282bc6aa24cb96790f1856bd9c0ec04f20dd17d669bd0407c3ed2ce9c47f3b281\
+             ec7ad5e619315b1891c5acd836990fcb07d8a1493fd7d412178164a833597eed111dcedef782704e2a3602e449ef2f23443d35",
+            "063dcbf78a6564e8efce9ec13ab43e343561d75971944712f0ef56d7cefb9e0a1aba7ae61f54ffda59750cb1dad7576788bb58b003bc9814\
+             675e474e51052e98d52b93d536a2d9fe60be9404864243722c7f9e1d4cf38a057a3b811c5f986c7606fb7d1a60181213e462baa64705f7e8\
+             4908cc4306047e81ffb51ca0632e6f7ee85ebcb40000",
+        ),
+    ];
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_keys() {
+        assert!(Key::new(Method::Aes256Gcm, KEY_256).is_ok());
+        assert!(Key::new(Method::Aes256Gcm, KEY_128).is_err());
+        assert!(Key::new(Method::Aes128Gcm, "not base64").is_err());
+        assert_eq!("2022-blake3-chacha20-poly1305".parse::<Method>().unwrap(), Method::Chacha20Poly1305);
+        assert!("aes-256-gcm".parse::<Method>().is_err());
+    }
+
+    #[tokio::test]
+    async fn reference_vectors() {
+        for &(method, password, req, resp) in VECTORS {
+            let key = Key::new(method, password).unwrap();
+            let (req, resp) = (unhex(req), unhex(resp));
+            let salt_len = method.key_len();
+
+            // the request: its salt, the fixed length header and the address with the payload
+            let (salt, rest) = req.split_at(salt_len);
+            let (fixed, var) = rest.split_at(11 + TAG_LEN);
+            let mut dec = key.session(salt);
+            let mut fixed = dec.open(BytesMut::from(fixed)).unwrap();
+            assert_eq!(fixed.get_u8(), CLIENT_STREAM);
+            let timestamp = fixed.get_u64();
+            assert_eq!(fixed.get_u16() as usize, var.len() - TAG_LEN);
+            let var = dec.open(BytesMut::from(var)).unwrap();
+            assert_eq!(seal_request(&key, salt, timestamp, &var).1, req);
+
+            let inbound = Inbound::new(key.clone());
+            let (mut stream, addr) = inbound.accept_at(Cursor::new(req.clone()), timestamp).await.unwrap();
+            assert_eq!(addr, Addr::Domain("nexel.cc".to_string(), 443));
+            let mut buf = [0u8; 18];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"GET / HTTP/1.1\r\n\r\n");
+
+            // the response: its salt, the fixed length header that carries the salt of the request, and the payload
+            let (resp_salt, rest) = resp.split_at(salt_len);
+            let (fixed, payload) = rest.split_at(11 + salt_len + TAG_LEN);
+            let mut dec = key.session(resp_salt);
+            let mut fixed = dec.open(BytesMut::from(fixed)).unwrap();
+            assert_eq!(fixed.get_u8(), SERVER_STREAM);
+            let timestamp = fixed.get_u64();
+            assert_eq!(&fixed.split_to(salt_len)[..], salt);
+            let payload = dec.open(BytesMut::from(payload)).unwrap();
+            assert_eq!(fixed.get_u16() as usize, payload.len());
+            assert_eq!(&payload[..], b"HTTP/1.1 204 No Content\r\n\r\n");
+            assert_eq!(seal_response(&key, resp_salt, timestamp, salt, &payload).1, resp);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for method in [Method::Aes128Gcm, Method::Aes256Gcm, Method::Chacha20Poly1305] {
+            let password = if method == Method::Aes128Gcm { KEY_128 } else { KEY_256 };
+            let key = Key::new(method, password).unwrap();
+            let (a, b) = tokio::io::duplex(1024);
+            let server = tokio::spawn(async move {
+                let inbound = Inbound::new(key);
+                let (mut stream, addr) = inbound.accept(b).await.unwrap();
+                let mut buf = vec![0u8; 100_000];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.flush().await.unwrap();
+                addr
+            });
+            let mut client = SsStream::client(a, Key::new(method, password).unwrap());
+            request(&mut client, "nexel.cc", 443).await.unwrap();
+            let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            client.write_all(&data).await.unwrap();
+            client.flush().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+            assert_eq!(server.await.unwrap(), Addr::Domain("nexel.cc".to_string(), 443));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_replay_and_wrong_key() {
+        let (a, mut b) = tokio::io::duplex(4096);
+        let mut client = SsStream::client(a, Key::new(Method::Aes256Gcm, KEY_256).unwrap());
+        request(&mut client, "1.2.3.4", 80).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.flush().await.unwrap();
+        drop(client);
+        let mut captured = Vec::new();
+        b.read_to_end(&mut captured).await.unwrap();
+
+        let inbound = Inbound::new(Key::new(Method::Aes256Gcm, KEY_256).unwrap());
+        let (mut stream, addr) = inbound.accept(Cursor::new(captured.clone())).await.unwrap();
+        assert_eq!(addr, Addr::Ip("1.2.3.4:80".parse().unwrap()));
+        let mut buf = [0u8; 18];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(inbound.accept(Cursor::new(captured.clone())).await.is_err());
+
+        let other = Inbound::new(Key::new(Method::Aes256Gcm, &KEY_256.replace('A', "B")).unwrap());
+        assert!(other.accept(Cursor::new(captured)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn outbound_through_inbound() {
+        let echo_port = spawn_echo().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let inbound = Inbound::new(Key::new(Method::Aes128Gcm, KEY_128).unwrap());
+            let (socket, _) = listener.accept().await.unwrap();
+            let (stream, dst) = inbound.accept(socket).await.unwrap();
+            Connection::new(stream, None).run_to(&dst.host(), dst.port()).await
+        });
+
+        let cfg = format!("{{name: SS, server: 127.0.0.1, port: {server}, protocol: ss, cipher: 2022-blake3-aes-128-gcm, password: '{KEY_128}'}}");
+        let proxy: ProxyCfg = serde_yml::from_str(&cfg).unwrap();
+        let outbounds = Outbounds::new();
+        let mut remote = outbounds.dial(&proxy).await.unwrap();
+        upstream::handshake(&proxy, &mut remote, "127.0.0.1", echo_port).await.unwrap();
+        assert_echo(&mut remote).await;
+    }
+}
@@ -0,0 +1,198 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+
+/// how long the client has to send its first bytes by default, a server that talks first isn't waited for longer.
+pub const WAIT: Duration = Duration::from_millis(300);
+/// a ClientHello takes a record, which is 16KiB at most.
+const MAX_SNIFF: usize = 16 * 1024 + 5;
+const METHODS: [&[u8]; 9] = [b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE "];
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Sniffed {
+    Domain(String),
+    /// more bytes could tell.
+    Incomplete,
+    /// neither a ClientHello nor a request of HTTP/1, or it names no domain.
+    Unknown,
+}
+
+/// the domain in the TLS ClientHello or the HTTP request that the bytes begin with.
+pub fn sniff(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(0x16) => client_hello(buf),
+        Some(_) => http_host(buf),
+    }
+}
+
+/// reads the first bytes of the client until the domain is found in them, they're returned to be sent to the destination.
+pub async fn read<R: AsyncRead + Unpin>(stream: &mut R, wait: Duration) -> (BytesMut, Option<String>) {
+    let deadline = Instant::now() + wait;
+    let mut buf = BytesMut::with_capacity(MAX_SNIFF);
+    loop {
+        match sniff(&buf) {
+            Sniffed::Domain(domain) => return (buf, Some(domain)),
+            Sniffed::Unknown => return (buf, None),
+            Sniffed::Incomplete if buf.len() >= MAX_SNIFF => return (buf, None),
+            Sniffed::Incomplete => {}
+        }
+        match tokio::time::timeout_at(deadline, stream.read_buf(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => {}
+            // the relay comes across the end or the error again
+            _ => return (buf, None),
+        }
+    }
+}
+
+fn domain(name: &[u8]) -> Sniffed {
+    match std::str::from_utf8(name) {
+        Ok(name) if !name.is_empty() && name.parse::<IpAddr>().is_err() => Sniffed::Domain(name.to_ascii_lowercase()),
+        _ => Sniffed::Unknown,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()?;
+        self.take(n)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(n)
+    }
+}
+
+/// the server_name extension of the ClientHello in the first record.
+fn client_hello(buf: &[u8]) -> Sniffed {
+    if buf.len() < 5 {
+        return Sniffed::Incomplete;
+    }
+    let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf[1] != 3 || len > MAX_SNIFF - 5 {
+        return Sniffed::Unknown;
+    }
+    if buf.len() < 5 + len {
+        return Sniffed::Incomplete;
+    }
+    server_name(&buf[5..5 + len]).unwrap_or(Sniffed::Unknown)
+}
+
+fn server_name(record: &[u8]) -> Option<Sniffed> {
+    let mut r = Reader(record);
+    // a ClientHello split over records is left unknown
+    if r.u8()? != 1 {
+        return None;
+    }
+    r.take(3)?;
+    r.take(2 + 32)?; // version and random
+    r.vec8()?; // session id
+    r.vec16()?; // cipher suites
+    r.vec8()?; // compression methods
+    let mut extensions = Reader(r.vec16()?);
+    while let Some(kind) = extensions.u16() {
+        let mut data = Reader(extensions.vec16()?);
+        if kind == 0 {
+            let mut names = Reader(data.vec16()?);
+            while let Some(name_type) = names.u8() {
+                let name = names.vec16()?;
+                if name_type == 0 {
+                    return Some(domain(name));
+                }
+            }
+            return None;
+        }
+    }
+    None
+}
+
+/// the Host header of the request, without the port.
+fn http_host(buf: &[u8]) -> Sniffed {
+    let method = METHODS.iter().find(|method| buf.starts_with(method) || method.starts_with(buf));
+    match method {
+        None => return Sniffed::Unknown,
+        Some(method) if buf.len() < method.len() => return Sniffed::Incomplete,
+        _ => {}
+    }
+    let mut lines: Vec<&[u8]> = buf.split(|b| *b == b'\n').skip(1).collect();
+    // the part of a line that hasn't come yet
+    lines.pop();
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Sniffed::Unknown;
+        }
+        if line.len() > 5 && line[..5].eq_ignore_ascii_case(b"host:") {
+            let host = line[5..].trim_ascii();
+            if host.starts_with(b"[") {
+                // an IPv6 literal
+                return Sniffed::Unknown;
+            }
+            let name = host.split(|b| *b == b':').next().unwrap_or_default();
+            return domain(name);
+        }
+    }
+    if buf.len() >= MAX_SNIFF {
+        return Sniffed::Unknown;
+    }
+    Sniffed::Incomplete
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::sniff::{sniff, Sniffed};
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut conn = rustls::ClientConnection::new(Arc::new(config), name.to_string().try_into().unwrap()).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn server_names() {
+        let hello = client_hello("www.Nexel.cc");
+        assert_eq!(sniff(&hello), Sniffed::Domain(String::from("www.nexel.cc")));
+        assert_eq!(sniff(&hello[..hello.len() - 1]), Sniffed::Incomplete);
+        assert_eq!(sniff(&hello[..3]), Sniffed::Incomplete);
+        // rustls sends no server_name for an ip
+        assert_eq!(sniff(&client_hello("10.0.0.1")), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn http_hosts() {
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nhost: nexel.cc:8080\r\n"), Sniffed::Domain(String::from("nexel.cc")));
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\nHost: nexel.cc"), Sniffed::Incomplete);
+        assert_eq!(sniff(b"PO"), Sniffed::Incomplete);
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\nHost: 10.0.0.1\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\n\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH\r\n"), Sniffed::Unknown);
+    }
+}
@@ -0,0 +1,132 @@
+//! servers and configs that the tests of several modules share.
+
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::connection::Connection;
+use crate::{http2, quic};
+use crate::outbound::{Outbounds, ProxyCfg, Target};
+use crate::tls::tests::path;
+use crate::tls::{self, Certs};
+
+/// serves every connection accepted on loopback, returns the port and the number of connections accepted.
+pub async fn spawn_with<F, Fut>(serve: F) -> (u16, Arc<AtomicUsize>)
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(serve(socket));
+        }
+    });
+    (port, accepted)
+}
+
+/// a TCP server that echoes every connection.
+pub async fn spawn_echo() -> u16 {
+    let (port, _) = spawn_with(|mut socket| async move {
+        let (mut r, mut w) = socket.split();
+        let _ = tokio::io::copy(&mut r, &mut w).await;
+    }).await;
+    port
+}
+
+/// a nexeld that forwards without tls.
+pub async fn spawn_server() -> u16 {
+    let (port, _) = spawn_with(|socket| async move { Connection::new(socket, None).run_on_server().await }).await;
+    port
+}
+
+/// a nexel, which is a plain SOCKS/HTTP proxy without outbounds.
+pub async fn spawn_client(outbounds: Option<Arc<Outbounds>>) -> u16 {
+    let (port, _) = spawn_with(move |socket| {
+        let outbounds = outbounds.clone();
+        async move { Connection::new(socket, outbounds).run().await }
+    }).await;
+    port
+}
+
+/// a nexeld that carries the tunnels as HTTP/2 streams over tls, with the certificates of `tls::tests::write_pki`.
+pub async fn spawn_h2_server(dir: &Path) -> (u16, Arc<AtomicUsize>) {
+    let certs = Certs::load([(path(dir, "server.crt").as_str(), path(dir, "server.key").as_str())]).unwrap();
+    let acceptor = tls::acceptor(Arc::new(certs), None, &[http2::ALPN]).unwrap();
+    spawn_with(move |socket| {
+        let acceptor = acceptor.clone();
+        async move {
+            let socket = acceptor.accept(socket).await?;
+            let _ = http2::serve(socket, |stream| {
+                tokio::spawn(async move { Connection::new(stream, None).run_on_server().await });
+            }).await;
+            std::io::Result::Ok(())
+        }
+    }).await
+}
+
+pub fn h2_proxy(dir: &Path, port: u16) -> ProxyCfg {
+    let cfg = format!("{{name: PROXY, server: 127.0.0.1, port: {port}, tls: true, cert: '{}', sni: localhost, h2: true}}", path(dir, "ca.crt"));
+    serde_yml::from_str(&cfg).unwrap()
+}
+
+/// a nexeld that serves QUIC connections, with the certificates of `tls::tests::write_pki`.
+pub fn spawn_quic_server(dir: &Path) -> (u16, Arc<AtomicUsize>) {
+    let certs = Certs::load([(path(dir, "server.crt").as_str(), path(dir, "server.key").as_str())]).unwrap();
+    let endpoint = quic::server_endpoint("127.0.0.1:0".parse().unwrap(), Arc::new(certs), None).unwrap();
+    let port = endpoint.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let conn = incoming.await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(quic::serve(conn, |stream, datagrams| {
+                tokio::spawn(async move {
+                    let id = stream.id();
+                    let mut conn = Connection::new(stream, None);
+                    conn.set_datagrams(datagrams, id);
+                    conn.run_on_server().await
+                });
+            }));
+        }
+    });
+    (port, accepted)
+}
+
+pub fn quic_proxy(dir: &Path, port: u16) -> ProxyCfg {
+    let cfg = format!("{{name: PROXY, server: 127.0.0.1, port: {port}, tls: true, cert: '{}', sni: localhost, quic: true}}", path(dir, "ca.crt"));
+    serde_yml::from_str(&cfg).unwrap()
+}
+
+/// the outbounds of a config file with the `yaml` in it.
+pub fn outbounds(yaml: &str) -> crate::Result<Outbounds> {
+    let path = std::env::temp_dir().join(format!("nexel-outbounds-{}.yaml", uuid::Uuid::new_v4()));
+    std::fs::write(&path, yaml).unwrap();
+    let ret = Outbounds::load(path.to_str().unwrap());
+    std::fs::remove_file(path).unwrap();
+    ret
+}
+
+pub fn proxy_name(target: Target) -> String {
+    match target {
+        Target::Proxy(proxy) => proxy.name().to_string(),
+        _ => panic!("expected a proxy"),
+    }
+}
+
+/// sends a few bytes through a tunnel to an echo server and expects them back.
+pub async fn assert_echo<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+    stream.write_all(b"nexel").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"nexel");
+}
@@ -1,50 +1,766 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, BufReader, ErrorKind};
+use std::io::{self, BufReader, ErrorKind, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
+use log::{error, info};
 use pki_types::{CertificateDer, PrivateKeyDer};
 use rustls_pemfile::{certs, private_key};
-use tokio::net::TcpStream;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use base64::Engine;
+use chrono::Datelike;
+use rcgen::{
+    date_time_ymd, BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::rustls::sign::CertifiedKey;
 use tokio_rustls::{rustls, TlsAcceptor, TlsConnector, TlsStream};
+use x509_parser::extensions::GeneralName;
+use crate::error::Error;
 
 fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
     certs(&mut BufReader::new(File::open(path)?)).collect()
 }
 
 fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
-    Ok(private_key(&mut BufReader::new(File::open(path)?))
-        .unwrap()
-        .ok_or(io::Error::new(
-            ErrorKind::Other,
-            "no private key found".to_string(),
-        ))?)
+    private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or(io::Error::other("no private key found".to_string()))
 }
 
-pub fn acceptor(cert: &String, private_key: &String) -> io::Result<TlsAcceptor> {
-    let certs = load_certs(&PathBuf::from(cert))?;
-    let key = load_key(&PathBuf::from(private_key))?;
-    let config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
-    Ok(TlsAcceptor::from(Arc::new(config)))
+/// how the certificates of the clients are verified.
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+    ca: String,
+    required: bool, // clients without a certificate are let in when it's false
 }
 
-pub async fn connect(stream: TcpStream,cert: &str, server_domain: &str) -> io::Result<TlsStream<TcpStream>> {
-    let mut root_cert_store = rustls::RootCertStore::empty();
-    let mut pem = BufReader::new(File::open(PathBuf::from(cert))?);
-    for cert in certs(&mut pem) {
-        root_cert_store.add(cert?).unwrap();
+impl ClientAuth {
+    pub fn new(ca: &str, required: bool) -> ClientAuth {
+        ClientAuth { ca: ca.to_string(), required }
     }
+}
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth(); // i guess this was previously the default?
-    let connector = TlsConnector::from(Arc::new(config));
+/// the certificates of the server, picked by the server name that the client asks for.
+/// the names of a certificate are the dns names of its subject alternative names, wildcards included,
+/// and the first certificate is presented when none of them matches.
+/// they can be reloaded from their files, the handshakes after it get the new ones.
+#[derive(Debug)]
+pub struct Certs {
+    paths: Vec<(String, String)>,
+    table: RwLock<Arc<CertTable>>,
+}
 
-    let domain = pki_types::ServerName::try_from(server_domain)
-        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid dnsname"))?
-        .to_owned();
+#[derive(Debug)]
+struct CertTable {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl Certs {
+    /// loads the pairs of the cert and key paths.
+    pub fn load<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> io::Result<Certs> {
+        let paths: Vec<_> = pairs.into_iter().map(|(cert, key)| (cert.to_string(), key.to_string())).collect();
+        let table = CertTable::load(&paths)?;
+        Ok(Certs { paths, table: RwLock::new(Arc::new(table)) })
+    }
+
+    /// loads the files again, the certificates in use are kept if any pair of them is invalid.
+    pub fn reload(&self) -> io::Result<()> {
+        let table = CertTable::load(&self.paths)?;
+        *self.table.write().unwrap() = Arc::new(table);
+        info!("[TLS] certificates reloaded");
+        Ok(())
+    }
+
+    /// reloads the certificates when their files are modified, checking every interval, or on SIGHUP.
+    pub fn watch(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let mut modified = self.modified();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            #[cfg(unix)]
+            let mut hangup = signal(SignalKind::hangup()).ok();
+            loop {
+                #[cfg(unix)]
+                let hangup = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                #[cfg(not(unix))]
+                let hangup = std::future::pending::<()>();
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        let now = self.modified();
+                        if now == modified {
+                            continue;
+                        }
+                        // a pair that is half written fails now and is loaded when the rest of it is written
+                        modified = now;
+                    }
+                    _ = hangup => {}
+                }
+                if let Err(e) = self.reload() {
+                    error!("[TLS] reloading certificates failed, the old ones are kept: {}", e);
+                }
+            }
+        })
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths.iter()
+            .flat_map(|(cert, key)| [cert, key])
+            .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    fn pick(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let table = self.table.read().unwrap().clone();
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return table.default.clone();
+        };
+        let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{parent}"));
+        table.by_name.get(&name)
+            .or_else(|| table.by_name.get(wildcard.as_deref()?))
+            .unwrap_or(&table.default)
+            .clone()
+    }
+}
+
+impl CertTable {
+    fn load(paths: &[(String, String)]) -> io::Result<CertTable> {
+        let mut by_name = HashMap::new();
+        let mut default = None;
+        for (cert, key) in paths {
+            let certs = load_certs(&PathBuf::from(cert))?;
+            let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&load_key(&PathBuf::from(key))?)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+            let names = certs.first().map(|cert| dns_names(cert)).unwrap_or_default();
+            let certified = CertifiedKey::new(certs, signing_key);
+            certified.keys_match()
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("{cert} and {key}: {err}")))?;
+            let certified = Arc::new(certified);
+            for name in names {
+                by_name.entry(name).or_insert_with(|| certified.clone());
+            }
+            default.get_or_insert(certified);
+        }
+        let default = default.ok_or(io::Error::new(ErrorKind::InvalidInput, "no certificate was given"))?;
+        Ok(CertTable { by_name, default })
+    }
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.pick(client_hello.server_name()))
+    }
+}
+
+/// the dns names of a DER encoded certificate, in lowercase.
+fn dns_names(cert: &[u8]) -> Vec<String> {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return Vec::new();
+    };
+    let Ok(Some(san)) = cert.subject_alternative_name() else {
+        return Vec::new();
+    };
+    san.value.general_names.iter().filter_map(|name| match name {
+        GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+        _ => None,
+    }).collect()
+}
+
+/// the inbound that a tls connection is handed to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Inbound {
+    Tunnel,
+    Trojan,
+    Fallback,
+}
+
+/// a connection whose server name and negotiated alpn protocol match goes to the inbound.
+/// the server name can be a wildcard such as `*.example.com`, and an absent field matches anything.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    sni: Option<String>,
+    alpn: Option<String>,
+    inbound: Inbound,
+}
+
+impl Route {
+    fn matches(&self, server_name: Option<&str>, alpn: Option<&[u8]>) -> bool {
+        let sni = match (&self.sni, server_name) {
+            (None, _) => true,
+            (Some(pattern), Some(name)) => match pattern.strip_prefix("*.") {
+                Some(parent) => name.split_once('.').is_some_and(|(_, rest)| rest.eq_ignore_ascii_case(parent)),
+                None => pattern.eq_ignore_ascii_case(name),
+            },
+            (Some(_), None) => false,
+        };
+        sni && self.alpn.as_ref().is_none_or(|protocol| Some(protocol.as_bytes()) == alpn)
+    }
+}
+
+/// the `tls` section of the config of nexeld, the certificates besides the one of `-c` and `-k`
+/// and the routes of the connections.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsCfg {
+    certs: Vec<CertCfg>,
+    routes: Vec<Route>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct CertCfg {
+    cert: String,
+    key: String,
+}
+
+impl TlsCfg {
+    pub fn load(path: &str) -> crate::Result<TlsCfg> {
+        #[derive(Deserialize)]
+        struct Cfg {
+            tls: Option<TlsCfg>,
+        }
+        let file = File::open(path)?;
+        let cfg: Cfg = serde_yml::from_reader(file)
+            .map_err(|e| Error::Other(format!("bad tls config: {e}")))?;
+        Ok(cfg.tls.unwrap_or_default())
+    }
+
+    pub fn certs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.certs.iter().map(|cfg| (cfg.cert.as_str(), cfg.key.as_str()))
+    }
+
+    /// the alpn protocols that the routes look for, the server has to offer them.
+    pub fn alpn(&self) -> impl Iterator<Item = &[u8]> {
+        self.routes.iter().filter_map(|route| route.alpn.as_deref().map(str::as_bytes))
+    }
+
+    /// the inbound of the first route that matches, none if no route does.
+    pub fn route(&self, server_name: Option<&str>, alpn: Option<&[u8]>) -> Option<Inbound> {
+        self.routes.iter().find(|route| route.matches(server_name, alpn)).map(|route| route.inbound)
+    }
+}
+
+/// the protocols in alpn are offered to the clients, in the order of preference.
+pub fn acceptor(certs: Arc<Certs>, client_auth: Option<&ClientAuth>, alpn: &[&[u8]]) -> io::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(certs, client_auth, alpn)?)))
+}
+
+/// the config of the server side, shared by the tls acceptor and the QUIC endpoint.
+pub fn server_config(certs: Arc<Certs>, client_auth: Option<&ClientAuth>, alpn: &[&[u8]]) -> io::Result<rustls::ServerConfig> {
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_auth {
+        Some(client_auth) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_certs(&PathBuf::from(&client_auth.ca))? {
+                roots.add(cert).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if client_auth.required { verifier } else { verifier.allow_unauthenticated() };
+            let verifier = verifier.build().map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(certs);
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(config)
+}
+
+/// how the client verifies the certificate of the server. it has to chain to one of the roots,
+/// which are the CA files and the ones of the system, and its public key has to match one of the pins if any.
+/// with pins but no roots, the public key alone is verified, which fits a server with a self-signed certificate.
+#[derive(Clone, Debug, Default)]
+pub struct Trust {
+    system_roots: bool,
+    cas: Vec<String>,
+    pins: Vec<String>, // base64 SHA-256 of the SubjectPublicKeyInfo
+}
+
+impl Trust {
+    pub fn new() -> Trust {
+        Trust::default()
+    }
+
+    /// trusts the certificates in the PEM file alone.
+    pub fn ca(path: &str) -> Trust {
+        let mut trust = Trust::new();
+        trust.add_ca(path);
+        trust
+    }
+
+    /// the system roots are read from `SSL_CERT_FILE`, or the certificate store of the platform.
+    pub fn set_system_roots(&mut self, system_roots: bool) {
+        self.system_roots = system_roots;
+    }
+
+    pub fn add_ca(&mut self, path: &str) {
+        self.cas.push(path.to_string());
+    }
+
+    /// the pin is the base64 SHA-256 of the public key of the server, `sha256//` prefixed or not.
+    pub fn add_pin(&mut self, pin: &str) {
+        self.pins.push(pin.to_string());
+    }
+
+    fn roots(&self) -> io::Result<rustls::RootCertStore> {
+        let mut roots = rustls::RootCertStore::empty();
+        for path in &self.cas {
+            for cert in load_certs(&PathBuf::from(path))
+                .map_err(|err| io::Error::new(err.kind(), format!("loading the CA {path} failed: {err}")))? {
+                roots.add(cert)
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("bad CA in {path}: {err}")))?;
+            }
+        }
+        if self.system_roots {
+            let native = rustls_native_certs::load_native_certs();
+            for err in &native.errors {
+                error!("loading the system roots: {err}");
+            }
+            // a store of the system may have certificates that webpki doesn't take, they're skipped
+            let (added, _) = roots.add_parsable_certificates(native.certs);
+            if added == 0 {
+                return Err(io::Error::new(ErrorKind::NotFound, "no CA of the system was found, set SSL_CERT_FILE"));
+            }
+        }
+        Ok(roots)
+    }
+
+    fn pins(&self) -> io::Result<Vec<[u8; 32]>> {
+        self.pins.iter().map(|pin| {
+            let encoded = pin.strip_prefix("sha256//").unwrap_or(pin);
+            base64::engine::general_purpose::STANDARD.decode(encoded).ok()
+                .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                .ok_or(io::Error::new(ErrorKind::InvalidInput, format!("bad pin {pin}, it's the base64 of a SHA-256")))
+        }).collect()
+    }
+}
+
+/// verifies the public key of the server against the pins, after the chain if there are roots.
+#[derive(Debug)]
+struct PinnedVerifier {
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: pki_types::UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        match spki_sha256(end_entity) {
+            Some(hash) if self.pins.contains(&hash) => Ok(ServerCertVerified::assertion()),
+            Some(_) => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure)),
+            None => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)),
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// the SHA-256 of the SubjectPublicKeyInfo of a DER encoded certificate, which is what a pin is.
+fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(Sha256::digest(cert.tbs_certificate.subject_pki.raw).into())
+}
+
+/// the client presents the certificate of the identity, a pair of the cert and key paths, if it's given.
+/// the server is verified with the trust, under the server name, which can differ from the host that's dialed.
+pub async fn connect<S>(stream: S, trust: &Trust, server_name: &str, identity: Option<(&str, &str)>, alpn: &[&[u8]]) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connector = TlsConnector::from(Arc::new(client_config(trust, identity, alpn)?));
+    let domain = self::server_name(server_name)?;
     Ok(TlsStream::from(connector.connect(domain, stream).await?))
-}
\ No newline at end of file
+}
+
+/// the config of the client side, the server is verified with the trust.
+pub fn client_config(trust: &Trust, identity: Option<(&str, &str)>, alpn: &[&[u8]]) -> io::Result<rustls::ClientConfig> {
+    let roots = trust.roots()?;
+    let pins = trust.pins()?;
+    if roots.is_empty() && pins.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "nothing to verify the server with, give a CA, the system roots or a pin"));
+    }
+    let builder = rustls::ClientConfig::builder();
+    let builder = if pins.is_empty() {
+        builder.with_root_certificates(roots)
+    } else {
+        let provider = rustls::crypto::aws_lc_rs::default_provider();
+        let chain = match roots.is_empty() {
+            true => None,
+            false => Some(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::new(provider.clone()))
+                .build()
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?),
+        };
+        let verifier = PinnedVerifier { chain, pins, algorithms: provider.signature_verification_algorithms };
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+    };
+    let mut config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(&PathBuf::from(cert))?, load_key(&PathBuf::from(key))?)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?,
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(config)
+}
+
+/// what gen-cert wrote, the pin is the one of the certificate of the server.
+#[derive(Debug)]
+pub struct Generated {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub pin: String,
+}
+
+/// generates a CA and a certificate of the server for the names, which can be ips as well, signed by it.
+/// they're written into the directory as ca.crt, ca.key, certificate.crt and private.key,
+/// the keys only readable by the owner. existing files are kept unless overwrite is set.
+pub fn generate(dir: &Path, names: &[String], days: u32, overwrite: bool) -> io::Result<Generated> {
+    let invalid = |err: rcgen::Error| io::Error::new(ErrorKind::InvalidInput, err);
+    if names.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "the certificate needs a name at least"));
+    }
+    let (now, until) = (chrono::Utc::now(), chrono::Utc::now() + chrono::Duration::days(days.into()));
+    let validity = |params: &mut CertificateParams| {
+        params.not_before = date_time_ymd(now.year(), now.month() as u8, now.day() as u8);
+        params.not_after = date_time_ymd(until.year(), until.month() as u8, until.day() as u8);
+    };
+
+    let ca_key = KeyPair::generate().map_err(invalid)?;
+    let mut params = CertificateParams::new(Vec::<String>::new()).map_err(invalid)?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.distinguished_name.push(DnType::CommonName, "nexeld ca");
+    validity(&mut params);
+    let ca = params.self_signed(&ca_key).map_err(invalid)?;
+
+    let key = KeyPair::generate().map_err(invalid)?;
+    let mut params = CertificateParams::new(names.to_vec()).map_err(invalid)?;
+    params.distinguished_name.push(DnType::CommonName, names[0].as_str());
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    validity(&mut params);
+    let cert = params.signed_by(&key, &ca, &ca_key).map_err(invalid)?;
+
+    std::fs::create_dir_all(dir)?;
+    let generated = Generated {
+        ca: dir.join("ca.crt"),
+        cert: dir.join("certificate.crt"),
+        key: dir.join("private.key"),
+        pin: base64::engine::general_purpose::STANDARD.encode(spki_sha256(cert.der()).unwrap_or_default()),
+    };
+    let files = [
+        (generated.ca.clone(), ca.pem(), 0o644),
+        (dir.join("ca.key"), ca_key.serialize_pem(), 0o600),
+        (generated.cert.clone(), cert.pem(), 0o644),
+        (generated.key.clone(), key.serialize_pem(), 0o600),
+    ];
+    if !overwrite {
+        if let Some((path, _, _)) = files.iter().find(|(path, _, _)| path.exists()) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} exists already", path.display())));
+        }
+    }
+    for (path, contents, mode) in files {
+        write_private(&path, contents.as_bytes(), mode)?;
+    }
+    Ok(generated)
+}
+
+/// writes the file with the permissions on unix, which are set before anything is written to it.
+fn write_private(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(mode);
+        // the mode is only taken by a file that is created
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)?.write_all(contents)
+}
+
+fn server_name(server_domain: &str) -> io::Result<pki_types::ServerName<'static>> {
+    Ok(pki_types::ServerName::try_from(server_domain)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid dnsname"))?
+        .to_owned())
+}
+
+/// the subject of the certificate that the client presented.
+pub fn client_subject(conn: &rustls::ServerConnection) -> Option<String> {
+    cert_subject(conn.peer_certificates()?.first()?)
+}
+
+/// the subject of a DER encoded certificate, such as "CN=alice".
+pub fn cert_subject(cert: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(cert.subject().to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use tokio_rustls::TlsStream;
+    use base64::Engine;
+    use crate::tls::{acceptor, cert_subject, client_subject, connect, generate, load_certs, spki_sha256, Certs, ClientAuth, Inbound, TlsCfg, Trust};
+
+    /// writes a CA, a server cert for localhost and a client cert of alice, both signed by the CA.
+    pub(crate) fn write_pki(dir: &Path) {
+        std::fs::create_dir_all(dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "nexel ca");
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+        for (name, cn) in [("server", "localhost"), ("client", "alice")] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![cn.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, cn);
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(dir.join(format!("{name}.crt")), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{name}.key")), key.serialize_pem()).unwrap();
+        }
+    }
+
+    pub(crate) fn path(dir: &Path, name: &str) -> String {
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    /// accepts one tls connection and returns the subject of its client certificate.
+    async fn accept_one(required: bool, dir: &Path, identity: Option<(&str, &str)>) -> std::io::Result<Option<String>> {
+        let client_auth = ClientAuth::new(&path(dir, "ca.crt"), required);
+        let certs = Certs::load([(path(dir, "server.crt").as_str(), path(dir, "server.key").as_str())]).unwrap();
+        let tls_acceptor = acceptor(Arc::new(certs), Some(&client_auth), &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let socket = tls_acceptor.accept(socket).await?;
+            Ok(client_subject(socket.get_ref().1))
+        });
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let _client = connect(stream, &Trust::ca(&path(dir, "ca.crt")), "localhost", identity, &[]).await;
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let dir = std::env::temp_dir().join(format!("nexel-mtls-{}", uuid::Uuid::new_v4()));
+        write_pki(&dir);
+        let (cert, key) = (path(&dir, "client.crt"), path(&dir, "client.key"));
+
+        let subject = accept_one(true, &dir, Some((&cert, &key))).await.unwrap();
+        assert_eq!(subject.as_deref(), Some("CN=alice"));
+        assert!(accept_one(true, &dir, None).await.is_err());
+        assert_eq!(accept_one(false, &dir, None).await.unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn certs_by_server_name() {
+        let dir = std::env::temp_dir().join(format!("nexel-sni-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, san) in [("a", "a.test"), ("b", "*.b.test")] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(dir.join(format!("{name}.crt")), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{name}.key")), key.serialize_pem()).unwrap();
+        }
+        let (a, b) = ((path(&dir, "a.crt"), path(&dir, "a.key")), (path(&dir, "b.crt"), path(&dir, "b.key")));
+        let certs = Certs::load([(a.0.as_str(), a.1.as_str()), (b.0.as_str(), b.1.as_str())]).unwrap();
+        // an unknown name or none at all gets the first certificate
+        assert!(Arc::ptr_eq(&certs.pick(Some("c.test")), &certs.pick(Some("A.test"))));
+        assert!(Arc::ptr_eq(&certs.pick(None), &certs.pick(Some("a.test"))));
+        assert!(!Arc::ptr_eq(&certs.pick(Some("b.test")), &certs.pick(Some("x.b.test"))));
+        let tls_acceptor = acceptor(Arc::new(certs), None, &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let _ = tls_acceptor.accept(socket).await;
+            }
+        });
+
+        for (server_name, root, subject) in [("a.test", &a.0, "CN=a"), ("www.b.test", &b.0, "CN=b")] {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let stream = connect(stream, &Trust::ca(root), server_name, None, &[]).await;
+            let presented = match stream {
+                Ok(TlsStream::Client(stream)) => stream.get_ref().1.peer_certificates().unwrap()[0].to_vec(),
+                _ => panic!("{server_name} failed the handshake"),
+            };
+            assert_eq!(cert_subject(&presented).as_deref(), Some(subject));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn routes() {
+        let cfg: TlsCfg = serde_yml::from_str(r#"
+routes:
+  - sni: "*.trojan.test"
+    inbound: trojan
+  - alpn: h2
+    inbound: tunnel
+  - sni: www.test
+    inbound: fallback
+"#).unwrap();
+        assert_eq!(cfg.route(Some("a.trojan.test"), Some(b"h2")), Some(Inbound::Trojan));
+        assert_eq!(cfg.route(Some("trojan.test"), Some(b"h2")), Some(Inbound::Tunnel));
+        assert_eq!(cfg.route(Some("WWW.test"), None), Some(Inbound::Fallback));
+        assert_eq!(cfg.route(None, Some(b"http/1.1")), None);
+        assert_eq!(cfg.alpn().collect::<Vec<_>>(), vec![b"h2".as_slice()]);
+    }
+
+    #[tokio::test]
+    async fn reload_certs() {
+        let dir = std::env::temp_dir().join(format!("nexel-reload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |cn: &str, key: &KeyPair| {
+            let mut params = CertificateParams::new(vec!["a.test".to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, cn);
+            std::fs::write(dir.join("a.crt"), params.self_signed(key).unwrap().pem()).unwrap();
+            std::fs::write(dir.join("a.key"), key.serialize_pem()).unwrap();
+        };
+        let subject = |certs: &Certs| cert_subject(&certs.pick(Some("a.test")).cert[0]);
+        write("old", &KeyPair::generate().unwrap());
+        let (cert, key) = (path(&dir, "a.crt"), path(&dir, "a.key"));
+        let certs = Arc::new(Certs::load([(cert.as_str(), key.as_str())]).unwrap());
+        let watch = certs.clone().watch(std::time::Duration::from_millis(20));
+
+        write("new", &KeyPair::generate().unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(subject(&certs).as_deref(), Some("CN=new"));
+
+        // a key that doesn't match the cert is refused and the one in use is kept
+        std::fs::write(dir.join("a.key"), KeyPair::generate().unwrap().serialize_pem()).unwrap();
+        assert!(certs.reload().is_err());
+        assert_eq!(subject(&certs).as_deref(), Some("CN=new"));
+        watch.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pinned_keys() {
+        let dir = std::env::temp_dir().join(format!("nexel-pin-{}", uuid::Uuid::new_v4()));
+        write_pki(&dir);
+        let certs = Certs::load([(path(&dir, "server.crt").as_str(), path(&dir, "server.key").as_str())]).unwrap();
+        let tls_acceptor = acceptor(Arc::new(certs), None, &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let _ = tls_acceptor.accept(socket).await;
+            }
+        });
+        let server_cert = load_certs(std::path::Path::new(&path(&dir, "server.crt"))).unwrap();
+        let pin = base64::engine::general_purpose::STANDARD.encode(spki_sha256(&server_cert[0]).unwrap());
+        let wrong = base64::engine::general_purpose::STANDARD.encode([0u8; 32]);
+        let dial = |trust: Trust, server_name: &'static str| async move {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            connect(stream, &trust, server_name, None, &[]).await
+        };
+
+        // a pin alone verifies the key whatever the name is, a pin with the CA verifies both
+        let mut trust = Trust::new();
+        trust.add_pin(&format!("sha256//{pin}"));
+        assert!(dial(trust, "anything.test").await.is_ok());
+        let mut trust = Trust::ca(&path(&dir, "ca.crt"));
+        trust.add_pin(&pin);
+        assert!(dial(trust.clone(), "localhost").await.is_ok());
+        assert!(dial(trust, "anything.test").await.is_err());
+        let mut trust = Trust::ca(&path(&dir, "ca.crt"));
+        trust.add_pin(&wrong);
+        assert!(dial(trust, "localhost").await.is_err());
+
+        // bad settings are errors rather than panics
+        assert!(dial(Trust::new(), "localhost").await.is_err());
+        let mut trust = Trust::new();
+        trust.add_pin("not base64");
+        assert!(dial(trust, "localhost").await.is_err());
+        std::fs::write(dir.join("bad.crt"), "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n").unwrap();
+        assert!(dial(Trust::ca(&path(&dir, "bad.crt")), "localhost").await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn generated_certs() {
+        let dir = std::env::temp_dir().join(format!("nexel-gen-{}", uuid::Uuid::new_v4()));
+        let generated = generate(&dir, &["localhost".to_string(), "127.0.0.1".to_string()], 30, false).unwrap();
+        assert!(generate(&dir, &["localhost".to_string()], 30, false).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&generated.key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let (cert, key) = (generated.cert.to_str().unwrap(), generated.key.to_str().unwrap());
+        let tls_acceptor = acceptor(Arc::new(Certs::load([(cert, key)]).unwrap()), None, &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let _ = tls_acceptor.accept(socket).await;
+            }
+        });
+        let mut trust = Trust::ca(generated.ca.to_str().unwrap());
+        trust.add_pin(&generated.pin);
+        for server_name in ["localhost", "127.0.0.1"] {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            assert!(connect(stream, &trust, server_name, None, &[]).await.is_ok());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
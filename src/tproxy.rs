@@ -0,0 +1,255 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use log::{error, info};
+use tokio::io::Interest;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use crate::outbound::Outbounds;
+use crate::redir::socket_addr;
+use crate::udp::{self, Addr, Relay};
+
+const MAX_DATAGRAM: usize = 65535;
+/// how long the datagrams of a client are relayed after its last one, when no idle timeout is configured.
+const SESSION_IDLE: Duration = Duration::from_secs(60);
+
+fn set_opt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const _ as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// lets the socket take the connections and datagrams of other addresses, it takes CAP_NET_ADMIN.
+fn set_transparent(fd: RawFd, addr: SocketAddr) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => set_opt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1),
+        SocketAddr::V6(_) => set_opt(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1),
+    }
+}
+
+fn set_recv_orig_dst(fd: RawFd, addr: SocketAddr) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => set_opt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1),
+        SocketAddr::V6(_) => set_opt(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1),
+    }
+}
+
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sa = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sa.sin_family = libc::AF_INET as libc::sa_family_t;
+            sa.sin_port = addr.port().to_be();
+            sa.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sa = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sa.sin6_port = addr.port().to_be();
+            sa.sin6_addr.s6_addr = addr.ip().octets();
+            sa.sin6_flowinfo = addr.flowinfo();
+            sa.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// a TCP listener that TPROXY hands the connections to, the local address of each one is its original destination.
+pub fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    set_transparent(socket.as_raw_fd(), addr)?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// the destination of a connection that TPROXY handed over to the listener on `port`.
+pub fn original_dst(socket: &TcpStream, port: u16) -> io::Result<SocketAddr> {
+    let dst = socket.local_addr()?;
+    // a connection made to the port itself would be dialed back to nexel forever,
+    // it's told apart by its destination being an address of this host, which the handed over ones aren't
+    if dst.port() == port && is_local(dst.ip()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection wasn't handed over by TPROXY"));
+    }
+    Ok(dst)
+}
+
+/// only the addresses of this host can be bound without IP_TRANSPARENT.
+fn is_local(ip: IpAddr) -> bool {
+    std::net::TcpListener::bind((ip, 0)).is_ok()
+}
+
+/// a transparent UDP socket bound to the address, which can be another host's.
+/// the one that TPROXY hands the datagrams to is told their original destinations.
+fn udp_bind(addr: SocketAddr, recv_orig_dst: bool) -> io::Result<UdpSocket> {
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // closes the fd when any of the following fails
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    set_transparent(fd, addr)?;
+    // the replies of many clients are sent from the same destination
+    set_opt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if recv_orig_dst {
+        set_recv_orig_dst(fd, addr)?;
+    }
+    let (storage, len) = sockaddr(addr);
+    if unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    UdpSocket::from_std(socket)
+}
+
+/// the UDP socket that TPROXY hands the datagrams to.
+pub fn udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    udp_bind(addr, true)
+}
+
+/// receives a datagram with its source and the destination that it had before TPROXY, from IP_ORIGDSTADDR.
+async fn recv_orig(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let fd = socket.as_raw_fd();
+    socket.async_io(Interest::READABLE, || recv_msg(fd, buf)).await
+}
+
+fn recv_msg(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut control = [0u64; 16]; // aligned for the headers
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut src as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let src = socket_addr(&src).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram came from an unknown family"))?;
+    let mut dst = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let orig_dst = (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_ORIGDSTADDR)
+            || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == libc::IPV6_ORIGDSTADDR);
+        if orig_dst {
+            let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+            let len = (header.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize).min(std::mem::size_of_val(&storage));
+            unsafe { std::ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), &mut storage as *mut _ as *mut u8, len) };
+            dst = socket_addr(&storage);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    let dst = dst.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram came without its original destination"))?;
+    Ok((n as usize, src, dst))
+}
+
+/// relays the datagrams that TPROXY hands over, each client gets a relay of its own as a socks5 association would.
+pub async fn serve_udp(socket: UdpSocket, outbounds: Arc<Outbounds>, idle: Option<Duration>) -> io::Result<()> {
+    let idle = idle.unwrap_or(SESSION_IDLE);
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<(SocketAddr, Bytes)>> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (n, client, dst) = match recv_orig(&socket, &mut buf).await {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                error!("[TPROXY-UDP] datagram dropped: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        sessions.retain(|_, session| !session.is_closed());
+        let session = sessions.entry(client).or_insert_with(|| spawn_session(client, outbounds.clone(), idle));
+        let _ = session.try_send((dst, Bytes::copy_from_slice(&buf[..n])));
+    }
+}
+
+/// the replies are sent from the addresses that they come from, so the client takes them for the destination's.
+fn spawn_session(client: SocketAddr, outbounds: Arc<Outbounds>, idle: Duration) -> mpsc::Sender<(SocketAddr, Bytes)> {
+    let (tx, mut rx) = mpsc::channel::<(SocketAddr, Bytes)>(udp::QUEUE);
+    tokio::spawn(async move {
+        let conn_id = uuid::Uuid::new_v4().to_string();
+        info!("[TPROXY-UDP] conn_id = {}, client = {}", conn_id, client);
+        let (mut relay, mut replies) = Relay::new(outbounds, &conn_id);
+        let mut senders: HashMap<SocketAddr, UdpSocket> = HashMap::new(); // bound to the destinations
+        loop {
+            tokio::select! {
+                datagram = rx.recv() => {
+                    let Some((dst, payload)) = datagram else {
+                        break;
+                    };
+                    if let Err(e) = relay.send(&Addr::Ip(dst), &payload).await {
+                        error!("[TPROXY-UDP] conn_id = {}, datagram dropped: {}", conn_id, e);
+                    }
+                }
+                Some((src, payload)) = replies.recv() => {
+                    let Addr::Ip(src) = src else {
+                        continue;
+                    };
+                    let sender = match senders.entry(src) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => match udp_bind(src, false) {
+                            Ok(sender) => entry.insert(sender),
+                            Err(e) => {
+                                error!("[TPROXY-UDP] conn_id = {}, reply from {} dropped: {}", conn_id, src, e);
+                                continue;
+                            }
+                        },
+                    };
+                    let _ = sender.send_to(&payload, client).await;
+                }
+                _ = tokio::time::sleep(idle) => break,
+            }
+        }
+        info!("[TPROXY-UDP] conn_id = {}, session ended", conn_id);
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use crate::tproxy::{original_dst, recv_orig, set_recv_orig_dst};
+
+    #[tokio::test]
+    async fn not_handed_over() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        // a connection to the tproxy port itself is refused, the one to another port of this host is served
+        assert!(original_dst(&socket, addr.port()).is_err());
+        assert_eq!(original_dst(&socket, addr.port() + 1).unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn original_destinations() {
+        // a socket that isn't transparent is told the destinations all the same, which are its own
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        set_recv_orig_dst(socket.as_raw_fd(), addr).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"query", addr).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, src, dst) = recv_orig(&socket, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"query");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(dst, addr);
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use serde::Deserialize;
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::error::Error;
+use crate::udp::Addr;
+use crate::Result;
+
+/// the length of the hex SHA224 of a password that starts every request.
+pub const HASH_LEN: usize = 56;
+const CMD_CONNECT: u8 = 1;
+
+pub fn hash_password(password: &str) -> String {
+    Sha224::digest(password.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// whether the bytes can be the start of a trojan request, which is lowercase hex.
+pub fn looks_like(read: &[u8]) -> bool {
+    read.first().is_some_and(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(b))
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    name: String,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrojanCfg {
+    users: Vec<User>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Accepted {
+    Connect { user: String, dst: Addr },
+    /// not a trojan request of a known user, with the bytes read from it.
+    Other(Vec<u8>),
+}
+
+/// the trojan inbound of nexeld, a request is authenticated by the hash of its user's password.
+/// hash | CRLF | cmd | socks5 addr | CRLF | payload
+#[derive(Default)]
+pub struct Trojan {
+    users: HashMap<String, String>, // password hash -> user
+}
+
+impl Trojan {
+    pub fn new() -> Trojan {
+        Trojan::default()
+    }
+
+    /// loads the `trojan` section of the config file, none if it's absent.
+    pub fn load(path: &str) -> Result<Option<Trojan>> {
+        #[derive(Deserialize)]
+        struct Cfg {
+            trojan: Option<TrojanCfg>,
+        }
+        let file = std::fs::File::open(path)?;
+        let cfg: Cfg = serde_yml::from_reader(file)
+            .map_err(|e| Error::Other(format!("bad trojan config: {e}")))?;
+        Ok(cfg.trojan.map(|cfg| {
+            let mut trojan = Trojan::new();
+            for user in cfg.users {
+                trojan.insert_user(&user.name, &user.password);
+            }
+            trojan
+        }))
+    }
+
+    pub fn insert_user(&mut self, name: &str, password: &str) {
+        self.users.insert(hash_password(password), name.to_string());
+    }
+
+    /// reads the request, it stops at the first byte that a trojan request can't have.
+    pub async fn accept<R: AsyncRead + Unpin + ?Sized>(&self, stream: &mut R) -> Result<Accepted> {
+        let mut read = Vec::with_capacity(HASH_LEN + 2);
+        while read.len() < HASH_LEN + 2 {
+            let Ok(b) = stream.read_u8().await else {
+                return Ok(Accepted::Other(read));
+            };
+            read.push(b);
+            let valid = match read.len() {
+                n if n <= HASH_LEN => looks_like(&[b]),
+                n if n == HASH_LEN + 1 => b == b'\r',
+                _ => b == b'\n',
+            };
+            if !valid {
+                return Ok(Accepted::Other(read));
+            }
+        }
+        let hash = std::str::from_utf8(&read[..HASH_LEN]).unwrap_or_default();
+        let Some(user) = self.users.get(hash).cloned() else {
+            return Ok(Accepted::Other(read));
+        };
+        let cmd = stream.read_u8().await?;
+        if cmd != CMD_CONNECT {
+            return Err(Error::UnknownCmd(cmd));
+        }
+        let dst = read_addr(stream).await?;
+        let mut crlf = [0u8; 2];
+        stream.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(Error::Other("trojan request didn't end with CRLF".to_string()));
+        }
+        Ok(Accepted::Connect { user, dst })
+    }
+}
+
+async fn read_addr<R: AsyncRead + Unpin + ?Sized>(stream: &mut R) -> Result<Addr> {
+    let atyp = stream.read_u8().await?;
+    let addr = match atyp {
+        1 => {
+            let ip = Ipv4Addr::from(stream.read_u32().await?);
+            Addr::Ip(SocketAddr::new(IpAddr::V4(ip), stream.read_u16().await?))
+        }
+        3 => {
+            let mut domain = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain).await?;
+            Addr::Domain(String::from_utf8(domain)?, stream.read_u16().await?)
+        }
+        4 => {
+            let ip = Ipv6Addr::from(stream.read_u128().await?);
+            Addr::Ip(SocketAddr::new(IpAddr::V6(ip), stream.read_u16().await?))
+        }
+        _ => return Err(Error::AddrTypeUnsupported(atyp)),
+    };
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tokio::io::AsyncReadExt;
+    use crate::trojan::{hash_password, Accepted, Trojan};
+    use crate::udp::Addr;
+
+    #[test]
+    fn sha224_of_password() {
+        // SHA224("abc") of FIPS 180-2
+        assert_eq!(hash_password("abc"), "23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7");
+    }
+
+    #[tokio::test]
+    async fn accept_requests() {
+        let mut trojan = Trojan::new();
+        trojan.insert_user("alice", "secret");
+        let mut req = hash_password("secret").into_bytes();
+        req.extend_from_slice(b"\r\n\x01\x03\x08nexel.cc\x01\xbb\r\nGET / HTTP/1.1\r\n");
+        let mut stream = Cursor::new(req);
+        let accepted = trojan.accept(&mut stream).await.unwrap();
+        assert_eq!(accepted, Accepted::Connect { user: "alice".to_string(), dst: Addr::Domain("nexel.cc".to_string(), 443) });
+        let mut payload = String::new();
+        stream.read_to_string(&mut payload).await.unwrap();
+        assert_eq!(payload, "GET / HTTP/1.1\r\n");
+
+        // a wrong password is read in full, anything else stops at the first byte that gives it away
+        let mut req = hash_password("guess").into_bytes();
+        req.extend_from_slice(b"\r\n\x01");
+        let accepted = trojan.accept(&mut Cursor::new(req.clone())).await.unwrap();
+        assert_eq!(accepted, Accepted::Other(req[..58].to_vec()));
+        let accepted = trojan.accept(&mut Cursor::new(b"GET / HTTP/1.1\r\n".to_vec())).await.unwrap();
+        assert_eq!(accepted, Accepted::Other(b"G".to_vec()));
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::acl::Acl;
+use crate::error::Error;
+use crate::outbound::{Outbounds, Target};
+use crate::quic::{Association, Datagrams};
+use crate::{dial, rule, Result};
+
+const MAX_DATAGRAM: usize = 65535;
+/// datagrams that wait for the peer, the later ones are dropped when it's full.
+pub const QUEUE: usize = 256;
+
+/// the destination or source of a datagram, a domain is resolved by the side that sends it out.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Addr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Display for Addr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Addr::Ip(addr) => write!(f, "{addr}"),
+            Addr::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+impl Addr {
+    pub fn host(&self) -> String {
+        match self {
+            Addr::Ip(addr) => addr.ip().to_string(),
+            Addr::Domain(domain, _) => domain.clone(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Addr::Ip(addr) => addr.port(),
+            Addr::Domain(_, port) => *port,
+        }
+    }
+}
+
+/// atyp | addr | port, the same as in socks5. a domain takes 255 bytes at most.
+pub fn encode_addr(buf: &mut BytesMut, addr: &Addr) -> Result<()> {
+    match addr {
+        Addr::Ip(SocketAddr::V4(addr)) => {
+            buf.put_u8(1);
+            buf.put_slice(&addr.ip().octets());
+        }
+        Addr::Domain(domain, _) => {
+            let len = u8::try_from(domain.len()).map_err(|_| Error::Other(format!("the domain {domain} is longer than 255 bytes")))?;
+            buf.put_u8(3);
+            buf.put_u8(len);
+            buf.put_slice(domain.as_bytes());
+        }
+        Addr::Ip(SocketAddr::V6(addr)) => {
+            buf.put_u8(4);
+            buf.put_slice(&addr.ip().octets());
+        }
+    }
+    buf.put_u16(addr.port());
+    Ok(())
+}
+
+pub fn decode_addr(buf: &mut Bytes) -> Result<Addr> {
+    if buf.is_empty() {
+        return Err(Error::Incomplete);
+    }
+    let atyp = buf.get_u8();
+    let len = match atyp {
+        1 => 4,
+        3 => 1 + *buf.first().ok_or(Error::Incomplete)? as usize,
+        4 => 16,
+        _ => return Err(Error::AddrTypeUnsupported(atyp)),
+    };
+    if buf.len() < len + 2 {
+        return Err(Error::Incomplete);
+    }
+    let addr = match atyp {
+        1 => {
+            let ip = Ipv4Addr::from(buf.get_u32());
+            Addr::Ip(SocketAddr::new(IpAddr::V4(ip), buf.get_u16()))
+        }
+        3 => {
+            let domain = buf.split_to(len).slice(1..);
+            let domain = String::from_utf8(domain.to_vec())?;
+            Addr::Domain(domain, buf.get_u16())
+        }
+        _ => {
+            let ip = Ipv6Addr::from(buf.get_u128());
+            Addr::Ip(SocketAddr::new(IpAddr::V6(ip), buf.get_u16()))
+        }
+    };
+    Ok(addr)
+}
+
+/// rsv | frag | addr | data, fragments are not supported.
+pub fn parse_socks5(packet: &[u8]) -> Result<(Addr, Bytes)> {
+    if packet.len() < 3 {
+        return Err(Error::Incomplete);
+    }
+    if packet[2] != 0 {
+        return Err(Error::Other("fragmented socks5 datagrams are not supported".to_string()));
+    }
+    let mut buf = Bytes::copy_from_slice(&packet[3..]);
+    let addr = decode_addr(&mut buf)?;
+    Ok((addr, buf))
+}
+
+pub fn socks5_packet(addr: &Addr, payload: &[u8]) -> Result<BytesMut> {
+    let mut buf = BytesMut::with_capacity(3 + 19 + payload.len());
+    buf.put_slice(&[0, 0, 0]);
+    encode_addr(&mut buf, addr)?;
+    buf.put_slice(payload);
+    Ok(buf)
+}
+
+/// the sockets that datagrams go out directly from, one for each address family.
+pub struct Direct {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>, // hosts without ipv6 have none
+}
+
+impl Direct {
+    pub async fn bind() -> io::Result<Direct> {
+        let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+        Ok(Direct { v4, v6 })
+    }
+
+    pub async fn send_to(&self, payload: &[u8], addr: SocketAddr) -> io::Result<()> {
+        match (addr, &self.v6) {
+            (SocketAddr::V4(_), _) => self.v4.send_to(payload, addr).await?,
+            (SocketAddr::V6(_), Some(v6)) => v6.send_to(payload, addr).await?,
+            (SocketAddr::V6(_), None) => return Err(io::ErrorKind::AddrNotAvailable.into()),
+        };
+        Ok(())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|cx| {
+            let mut read = ReadBuf::new(buf);
+            if let Poll::Ready(ret) = self.v4.poll_recv_from(cx, &mut read) {
+                return Poll::Ready(ret.map(|addr| (read.filled().len(), addr)));
+            }
+            if let Some(v6) = &self.v6 {
+                if let Poll::Ready(ret) = v6.poll_recv_from(cx, &mut read) {
+                    return Poll::Ready(ret.map(|addr| (read.filled().len(), addr)));
+                }
+            }
+            Poll::Pending
+        }).await
+    }
+}
+
+async fn resolve(addr: &Addr) -> Result<SocketAddr> {
+    match addr {
+        Addr::Ip(addr) => Ok(*addr),
+        Addr::Domain(domain, port) => dial::resolve(domain, *port).await?
+            .into_iter()
+            .next()
+            .ok_or(Error::Other(format!("{domain} has no address"))),
+    }
+}
+
+async fn idle_sleep(idle: Option<Duration>) {
+    match idle {
+        Some(idle) => tokio::time::sleep(idle).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// sends the datagrams of a client to where the rules route them, the replies come back through the receiver.
+/// only the proxies that run over QUIC carry datagrams.
+pub struct Relay {
+    outbounds: Arc<Outbounds>,
+    conn_id: String,
+    routes: HashMap<Addr, Target>,
+    direct: Option<Arc<Direct>>,
+    assocs: HashMap<String, Association>, // proxy -> the association tunneled to it
+    replies: mpsc::Sender<(Addr, Bytes)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Relay {
+    pub fn new(outbounds: Arc<Outbounds>, conn_id: &str) -> (Relay, mpsc::Receiver<(Addr, Bytes)>) {
+        let (replies, rx) = mpsc::channel(QUEUE);
+        let relay = Relay {
+            outbounds,
+            conn_id: conn_id.to_string(),
+            routes: HashMap::new(),
+            direct: None,
+            assocs: HashMap::new(),
+            replies,
+            tasks: Vec::new(),
+        };
+        (relay, rx)
+    }
+
+    async fn route(&mut self, dst: &Addr) -> Result<Target> {
+        if let Some(target) = self.routes.get(dst) {
+            return Ok(target.clone());
+        }
+        let routing = match (rule::port(dst.port()), dst) {
+            (Some(routing), _) => routing,
+            (None, Addr::Ip(addr)) => rule::ip(addr.ip()),
+            (None, Addr::Domain(domain, _)) => rule::domain(domain).await?,
+        };
+        let target = self.outbounds.resolve(routing.name(), &dst.host())?;
+        self.routes.insert(dst.clone(), target.clone());
+        Ok(target)
+    }
+
+    async fn direct(&mut self) -> Result<Arc<Direct>> {
+        if let Some(direct) = &self.direct {
+            return Ok(direct.clone());
+        }
+        let direct = Arc::new(Direct::bind().await?);
+        let (socket, replies) = (direct.clone(), self.replies.clone());
+        self.tasks.push(tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            while let Ok((n, src)) = socket.recv_from(&mut buf).await {
+                let _ = replies.try_send((Addr::Ip(src), Bytes::copy_from_slice(&buf[..n])));
+            }
+        }));
+        self.direct = Some(direct.clone());
+        Ok(direct)
+    }
+
+    pub async fn send(&mut self, dst: &Addr, payload: &[u8]) -> Result<()> {
+        match self.route(dst).await? {
+            Target::Direct => {
+                let addr = resolve(dst).await?;
+                self.direct().await?.send_to(payload, addr).await?;
+            }
+            Target::Reject => {
+                info!("[UDP-Reject] conn_id = {}, dst = {}", self.conn_id, dst);
+            }
+            Target::Proxy(proxy) if proxy.quic() => {
+                if !self.assocs.contains_key(proxy.name()) {
+                    let (assoc, mut rx) = self.outbounds.associate(&proxy).await?;
+                    info!("[UDP-Proxy] conn_id = {}, outbound = {}", self.conn_id, proxy.name());
+                    let replies = self.replies.clone();
+                    self.tasks.push(tokio::spawn(async move {
+                        while let Some(reply) = rx.recv().await {
+                            let _ = replies.try_send(reply);
+                        }
+                    }));
+                    self.assocs.insert(proxy.name().to_string(), assoc);
+                }
+                if let Err(e) = self.assocs[proxy.name()].send(dst, payload) {
+                    // associated again with the next datagram
+                    self.assocs.remove(proxy.name());
+                    return Err(e);
+                }
+            }
+            Target::Proxy(proxy) => {
+                warn!("[UDP-Proxy] conn_id = {}, outbound = {} doesn't carry datagrams, dropped", self.conn_id, proxy.name());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(|task| task.abort());
+    }
+}
+
+/// relays the datagrams of a socks5 client until its control connection is closed.
+/// the first address that sends to the socket is taken as the client.
+pub async fn serve_local<R>(socket: UdpSocket, mut relay: Relay, mut replies: mpsc::Receiver<(Addr, Bytes)>, control: &mut R, idle: Option<Duration>) -> Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut client = None;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut ctl = [0u8; 64];
+    loop {
+        tokio::select! {
+            ret = socket.recv_from(&mut buf) => {
+                let (n, from) = ret?;
+                if *client.get_or_insert(from) != from {
+                    continue;
+                }
+                let sent = match parse_socks5(&buf[..n]) {
+                    Ok((dst, payload)) => relay.send(&dst, &payload).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    error!("[UDP-Relay] conn_id = {}, datagram dropped: {}", relay.conn_id, e);
+                }
+            }
+            Some((src, payload)) = replies.recv() => {
+                if let Some(client) = client {
+                    match socks5_packet(&src, &payload) {
+                        Ok(packet) => {
+                            socket.send_to(&packet, client).await?;
+                        }
+                        Err(e) => error!("[UDP-Relay] conn_id = {}, reply dropped: {}", relay.conn_id, e),
+                    }
+                }
+            }
+            ret = control.read(&mut ctl) => {
+                if matches!(ret, Ok(0) | Err(_)) {
+                    return Ok(());
+                }
+            }
+            _ = idle_sleep(idle) => return Err(Error::IdleTimeout),
+        }
+    }
+}
+
+/// sends the datagrams tunneled in an association out directly until its control stream is closed.
+pub async fn serve_remote<R>(datagrams: &Datagrams, id: u64, acl: Option<&Acl>, control: &mut R, idle: Option<Duration>) -> Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut rx = datagrams.register(id);
+    let ret = relay_remote(datagrams, id, &mut rx, acl, control, idle).await;
+    datagrams.unregister(id);
+    ret
+}
+
+async fn relay_remote<R>(datagrams: &Datagrams, id: u64, rx: &mut mpsc::Receiver<(Addr, Bytes)>, acl: Option<&Acl>, control: &mut R, idle: Option<Duration>) -> Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let direct = Direct::bind().await?;
+    let mut resolved: HashMap<Addr, SocketAddr> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut ctl = [0u8; 64];
+    loop {
+        tokio::select! {
+            Some((dst, payload)) = rx.recv() => {
+                let addr = match resolved.get(&dst) {
+                    Some(addr) => Ok(*addr),
+                    None => match acl {
+                        Some(acl) => acl.resolve(&dst.host(), dst.port()).await.map(|addrs| addrs[0]),
+                        None => resolve(&dst).await,
+                    },
+                };
+                match addr {
+                    Ok(addr) => {
+                        resolved.insert(dst, addr);
+                        let _ = direct.send_to(&payload, addr).await;
+                    }
+                    Err(e) => info!("[UDP-Reject] id = {}, dst = {}, error = {}", id, dst, e),
+                }
+            }
+            ret = direct.recv_from(&mut buf) => {
+                let (n, src) = ret?;
+                datagrams.send(id, &Addr::Ip(src), &buf[..n])?;
+            }
+            ret = control.read(&mut ctl) => {
+                if matches!(ret, Ok(0) | Err(_)) {
+                    return Ok(());
+                }
+            }
+            _ = idle_sleep(idle) => return Err(Error::IdleTimeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use bytes::{Bytes, BytesMut};
+    use crate::udp::{decode_addr, encode_addr, parse_socks5, socks5_packet, Addr};
+
+    #[test]
+    fn encode_and_decode() {
+        for addr in [
+            Addr::Ip("1.2.3.4:53".parse::<SocketAddr>().unwrap()),
+            Addr::Ip("[2001:db8::1]:443".parse::<SocketAddr>().unwrap()),
+            Addr::Domain("nexel.cc".to_string(), 8080),
+        ] {
+            let mut buf = BytesMut::new();
+            encode_addr(&mut buf, &addr).unwrap();
+            buf.extend_from_slice(b"payload");
+            let mut buf = buf.freeze();
+            assert_eq!(decode_addr(&mut buf).unwrap(), addr);
+            assert_eq!(&buf[..], b"payload");
+        }
+        assert!(decode_addr(&mut Bytes::from_static(&[3, 9, b'n'])).is_err());
+        // the length of a domain is a byte
+        let long = Addr::Domain(format!("{}.nexel.cc", "a".repeat(247)), 53);
+        assert!(encode_addr(&mut BytesMut::new(), &long).is_err());
+        assert!(socks5_packet(&long, b"query").is_err());
+
+        let packet = socks5_packet(&Addr::Domain("nexel.cc".to_string(), 53), b"query").unwrap();
+        let (addr, payload) = parse_socks5(&packet).unwrap();
+        assert_eq!(addr, Addr::Domain("nexel.cc".to_string(), 53));
+        assert_eq!(&payload[..], b"query");
+        // fragmented
+        assert!(parse_socks5(&[0, 0, 1, 1, 1, 2, 3, 4, 0, 53]).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::net::IpAddr;
+use base64::Engine;
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::error::Error;
+use crate::outbound::{Protocol, ProxyCfg};
+use crate::{shadowsocks, Result};
+
+/// asks the proxy server on the other side of the stream to open a tunnel to host:port.
+pub async fn handshake<RW>(proxy: &ProxyCfg, stream: &mut RW, host: &str, port: u16) -> Result<()>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    match proxy.protocol() {
+        // nexeld understands HTTP CONNECT frames as well
+        Protocol::Nexel => http_connect(stream, host, port, None).await,
+        Protocol::Http => http_connect(stream, host, port, proxy.credentials()).await,
+        Protocol::Socks5 => socks5_connect(stream, host, port, proxy.credentials()).await,
+        // the stream was wrapped by establish, the server doesn't reply
+        Protocol::Shadowsocks => shadowsocks::request(stream, host, port).await,
+    }
+}
+
+pub async fn http_connect<RW>(stream: &mut RW, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<()>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+        _ => format!("{host}:{port}"),
+    };
+    let mut req = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((username, password)) = credentials {
+        let token = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+        req.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+    stream.flush().await?;
+    expect_status(stream, &["200"]).await
+}
+
+/// reads a HTTP response header and checks that its status code starts with one of the prefixes.
+pub async fn expect_status<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, prefixes: &[&str]) -> Result<()> {
+    let mut buffer = BytesMut::with_capacity(256);
+    while !buffer.ends_with(b"\r\n\r\n") {
+        if buffer.len() > 8192 {
+            return Err(Error::Other("response header was too large".to_string()));
+        }
+        // byte by byte so the bytes after the header stay in the stream
+        let b = reader.read_u8().await?;
+        buffer.put_u8(b);
+    }
+    let header = String::from_utf8_lossy(&buffer[..]);
+    let status = header.split(' ').nth(1).unwrap_or("");
+    if prefixes.iter().any(|prefix| status.starts_with(prefix)) {
+        Ok(())
+    } else {
+        Err(Error::Other(format!("unexpected response status {status}")))
+    }
+}
+
+pub async fn socks5_connect<RW>(stream: &mut RW, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<()>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    // greeting, no auth or username/password
+    let methods: &[u8] = if credentials.is_some() { &[0x00, 0x02] } else { &[0x00] };
+    let mut buf = BytesMut::with_capacity(64);
+    buf.put_u8(5);
+    buf.put_u8(methods.len() as u8);
+    buf.put_slice(methods);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let ver = stream.read_u8().await?;
+    if ver != 5 {
+        return Err(Error::VnUnsupported(ver));
+    }
+    match (stream.read_u8().await?, credentials) {
+        (0x00, _) => {}
+        (0x02, Some((username, password))) => {
+            // RFC 1929
+            buf.clear();
+            buf.put_u8(1);
+            buf.put_u8(username.len() as u8);
+            buf.put_slice(username.as_bytes());
+            buf.put_u8(password.len() as u8);
+            buf.put_slice(password.as_bytes());
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+            let _ver = stream.read_u8().await?;
+            if stream.read_u8().await? != 0 {
+                return Err(Error::ServerRefusedAuth);
+            }
+        }
+        _ => return Err(Error::ServerRefusedAuth),
+    }
+
+    buf.clear();
+    buf.put_slice(&[5, 1, 0]);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.put_u8(1);
+            buf.put_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.put_u8(4);
+            buf.put_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(Error::AddrTypeUnsupported(3));
+            }
+            buf.put_u8(3);
+            buf.put_u8(host.len() as u8);
+            buf.put_slice(host.as_bytes());
+        }
+    }
+    buf.put_u16(port);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(Error::Other(format!("socks5 upstream replied error {}", head[1])));
+    }
+    // skip the bound address
+    let addr_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        a_type => return Err(Error::AddrTypeUnsupported(a_type)),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::error::Error;
+    use crate::test_util::{assert_echo, outbounds, spawn_client, spawn_echo, spawn_server, spawn_with};
+    use crate::upstream::{http_connect, socks5_connect};
+
+    #[tokio::test]
+    async fn connect_through_upstreams() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_client(None).await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy)).await.unwrap();
+        socks5_connect(&mut stream, "127.0.0.1", echo, None).await.unwrap();
+        assert_echo(&mut stream).await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy)).await.unwrap();
+        http_connect(&mut stream, "localhost", echo, None).await.unwrap();
+        assert_echo(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn socks5_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 4];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            socket.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 11];
+            socket.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            socket.write_all(&[1, 1]).await.unwrap();
+        });
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let ret = socks5_connect(&mut stream, "nexel.cc", 443, Some(("user", "pass"))).await;
+        assert!(matches!(ret, Err(Error::ServerRefusedAuth)));
+    }
+
+    #[tokio::test]
+    async fn chain_outbounds() {
+        let echo = spawn_echo().await;
+        let server = spawn_server().await;
+
+        // a corporate HTTP proxy that counts its tunnels
+        let corp_proxy = spawn_client(None).await;
+        let (corp, tunnels) = spawn_with(move |mut socket| async move {
+            let mut upstream = TcpStream::connect(("127.0.0.1", corp_proxy)).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut socket, &mut upstream).await;
+        }).await;
+
+        let outbounds = outbounds(&format!(r#"
+proxies:
+  - name: corp
+    server: 127.0.0.1
+    port: {corp}
+    protocol: http
+  - name: PROXY
+    server: 127.0.0.1
+    port: {server}
+    dialer-proxy: corp
+"#)).unwrap();
+        let client = spawn_client(Some(Arc::new(outbounds))).await;
+
+        // nexel -> corp -> nexeld -> echo
+        let mut stream = TcpStream::connect(("127.0.0.1", client)).await.unwrap();
+        socks5_connect(&mut stream, "127.0.0.1", echo, None).await.unwrap();
+        assert_echo(&mut stream).await;
+        assert_eq!(tunnels.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use base64::Engine;
+use bytes::{Buf, BufMut, BytesMut};
+use log::info;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use crate::error::Error;
+use crate::Result;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEAD: usize = 8192;
+const MAX_FRAME: usize = 16 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// the WebSocket that nexel tunnels through to reach nexeld, the host defaults to the sni of the proxy.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct WsCfg {
+    path: String,
+    host: Option<String>,
+    headers: HashMap<String, String>,
+}
+
+impl Default for WsCfg {
+    fn default() -> Self {
+        WsCfg { path: "/".to_string(), host: None, headers: HashMap::new() }
+    }
+}
+
+impl WsCfg {
+    pub fn new(path: &str) -> WsCfg {
+        WsCfg { path: path.to_string(), ..WsCfg::default() }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn set_host(&mut self, host: &str) {
+        self.host = Some(host.to_string());
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    pub fn insert_header(&mut self, name: &str, value: &str) {
+        self.headers.insert(name.to_string(), value.to_string());
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.finalize())
+}
+
+/// reads a HTTP header byte by byte, so the frames after it stay in the stream.
+async fn read_head<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(Error::Other("http header was too large".to_string()));
+        }
+        head.push(reader.read_u8().await?);
+    }
+    Ok(head)
+}
+
+/// the value of the header, the name is case-insensitive.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// upgrades a connection to nexeld to a WebSocket.
+pub async fn connect<RW: AsyncRead + AsyncWrite + Unpin>(mut stream: RW, host: &str, cfg: &WsCfg) -> Result<WsStream<RW>> {
+    let key = base64::engine::general_purpose::STANDARD.encode(uuid::Uuid::new_v4().as_bytes());
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        cfg.path, cfg.host().unwrap_or(host), key
+    );
+    for (name, value) in &cfg.headers {
+        req.push_str(&format!("{name}: {value}\r\n"));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = read_head(&mut stream).await?;
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split(' ').nth(1).unwrap_or("");
+    if status != "101" {
+        return Err(Error::Other(format!("unexpected response status {status}")));
+    }
+    if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(Error::Other("bad websocket accept key".to_string()));
+    }
+    Ok(WsStream::new(stream, true))
+}
+
+/// what the first request on a WebSocket port turned out to be.
+pub enum Accepted<RW> {
+    Tunnel(WsStream<RW>),
+    /// any other HTTP request along with its header that was read.
+    Other(RW, Vec<u8>),
+}
+
+/// reads the first request, only an upgrade to the path is a tunnel.
+pub async fn accept<RW: AsyncRead + AsyncWrite + Unpin>(mut stream: RW, path: &str) -> Result<Accepted<RW>> {
+    let head = read_head(&mut stream).await?;
+    let text = String::from_utf8_lossy(&head);
+    let mut first = text.lines().next().unwrap_or("").split(' ');
+    let (method, target) = (first.next().unwrap_or(""), first.next().unwrap_or(""));
+    let upgrade = header(&text, "Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let key = match header(&text, "Sec-WebSocket-Key") {
+        Some(key) if method == "GET" && target == path && upgrade => key.to_string(),
+        _ => {
+            info!("[WS] not a tunnel: {} {}", method, target);
+            return Ok(Accepted::Other(stream, head));
+        }
+    };
+    let resp = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(Accepted::Tunnel(WsStream::new(stream, false)))
+}
+
+/// answers a request that isn't a tunnel the way an ordinary web server does.
+pub async fn not_found<W: AsyncWrite + Unpin + ?Sized>(stream: &mut W) -> Result<()> {
+    let body = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n<center><h1>404 Not Found</h1></center>\r\n</body>\r\n</html>\r\n";
+    let resp = format!(
+        "HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(), body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// the data frame being read.
+struct Frame {
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    pos: usize,
+}
+
+/// the bytes of the tunnel carried in binary frames, the ones that the client sends are masked.
+pub struct WsStream<RW> {
+    inner: RW,
+    client: bool,
+    rbuf: BytesMut,
+    frame: Option<Frame>,
+    wbuf: BytesMut, // frames not written yet
+    closed: bool,
+    close_sent: bool,
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> WsStream<RW> {
+    fn new(inner: RW, client: bool) -> WsStream<RW> {
+        WsStream {
+            inner,
+            client,
+            rbuf: BytesMut::new(),
+            frame: None,
+            wbuf: BytesMut::new(),
+            closed: false,
+            close_sent: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &RW {
+        &self.inner
+    }
+
+    fn encode(&mut self, opcode: u8, payload: &[u8]) {
+        self.wbuf.put_u8(0x80 | opcode);
+        let mask_bit = if self.client { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => self.wbuf.put_u8(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                self.wbuf.put_u8(mask_bit | 126);
+                self.wbuf.put_u16(len as u16);
+            }
+            len => {
+                self.wbuf.put_u8(mask_bit | 127);
+                self.wbuf.put_u64(len as u64);
+            }
+        }
+        if self.client {
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..4]);
+            self.wbuf.put_slice(&mask);
+            self.wbuf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            self.wbuf.put_slice(payload);
+        }
+    }
+
+    /// parses the next frame header, false if it's incomplete. control frames are handled as a whole.
+    fn parse_frame(&mut self) -> io::Result<bool> {
+        let buf = &self.rbuf[..];
+        if buf.len() < 2 {
+            return Ok(false);
+        }
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        let (len, mut offset) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(false),
+            len => (len as u64, 2),
+        };
+        let mask = if masked {
+            if buf.len() < offset + 4 {
+                return Ok(false);
+            }
+            offset += 4;
+            Some(<[u8; 4]>::try_from(&buf[offset - 4..offset]).unwrap())
+        } else {
+            None
+        };
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                self.rbuf.advance(offset);
+                self.frame = Some(Frame { remaining: len, mask, pos: 0 });
+            }
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if len > 125 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "websocket control frame was too large"));
+                }
+                if buf.len() < offset + len as usize {
+                    return Ok(false);
+                }
+                self.rbuf.advance(offset);
+                let mut payload = self.rbuf.split_to(len as usize);
+                if let Some(mask) = mask {
+                    payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+                }
+                match opcode {
+                    OP_PING => self.encode(OP_PONG, &payload),
+                    OP_CLOSE => {
+                        self.closed = true;
+                        if !self.close_sent {
+                            self.close_sent = true;
+                            self.encode(OP_CLOSE, &payload[..payload.len().min(2)]);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown websocket opcode {opcode}"))),
+        }
+        Ok(true)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<RW> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+            match &mut this.frame {
+                Some(frame) if frame.remaining == 0 => {
+                    this.frame = None;
+                    continue;
+                }
+                Some(frame) if !this.rbuf.is_empty() => {
+                    let n = (frame.remaining as usize).min(this.rbuf.len()).min(buf.remaining());
+                    let mut data = this.rbuf.split_to(n);
+                    if let Some(mask) = frame.mask {
+                        data.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[(frame.pos + i) % 4]);
+                    }
+                    frame.pos += n;
+                    frame.remaining -= n as u64;
+                    buf.put_slice(&data);
+                    return Poll::Ready(Ok(()));
+                }
+                Some(_) => {}
+                None => {
+                    if this.parse_frame()? {
+                        continue;
+                    }
+                }
+            }
+            // pongs and the close reply go out while waiting for data
+            if !this.wbuf.is_empty() {
+                let _ = this.poll_drain(cx)?;
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return if this.frame.is_some() || !this.rbuf.is_empty() {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                } else {
+                    Poll::Ready(Ok(()))
+                };
+            }
+            this.rbuf.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_FRAME);
+        this.encode(OP_BINARY, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.close_sent = true;
+            this.encode(OP_CLOSE, &1000u16.to_be_bytes());
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::connection::Connection;
+    use crate::outbound::{Outbounds, ProxyCfg};
+    use crate::test_util::{spawn_echo, spawn_with};
+    use crate::upstream::{expect_status, http_connect};
+    use crate::ws::{accept, accept_key, not_found, Accepted, WsCfg, WsStream};
+
+    #[test]
+    fn rfc_accept_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn read_masked_frames() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = WsStream::new(a, false);
+        // a masked "Hello" from rfc 6455 split into a fragment and a continuation, with a ping between them
+        b.write_all(&[0x01, 0x83, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d]).await.unwrap();
+        b.write_all(&[0x89, 0x00]).await.unwrap();
+        b.write_all(&[0x80, 0x82, 0x37, 0xfa, 0x21, 0x3d, 0x5b, 0x95]).await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello");
+        server.flush().await.unwrap();
+        let mut pong = [0u8; 2];
+        b.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x8a, 0x00]);
+        // closed by the peer
+        b.write_all(&[0x88, 0x02, 0x03, 0xe8]).await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    async fn spawn_server(path: &'static str) -> u16 {
+        let (port, _) = spawn_with(move |socket| async move {
+            match accept(socket, path).await.unwrap() {
+                Accepted::Tunnel(stream) => Connection::new(stream, None).run_on_server().await,
+                Accepted::Other(mut stream, _) => not_found(&mut stream).await,
+            }
+        }).await;
+        port
+    }
+
+    #[tokio::test]
+    async fn tunnel_over_websocket() {
+        let echo_port = spawn_echo().await;
+        let server = spawn_server("/tunnel").await;
+
+        let mut proxy = ProxyCfg::new("127.0.0.1", server, "");
+        let mut ws = WsCfg::new("/tunnel");
+        ws.set_host("cdn.nexel.cc");
+        ws.insert_header("User-Agent", "Mozilla/5.0");
+        proxy.set_ws(ws);
+        let mut remote = Outbounds::new().dial(&proxy).await.unwrap();
+        http_connect(&mut remote, "127.0.0.1", echo_port, None).await.unwrap();
+        // larger than a frame
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        remote.write_all(&data).await.unwrap();
+        remote.flush().await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn answer_other_requests() {
+        let server = spawn_server("/tunnel").await;
+        let mut stream = TcpStream::connect(("127.0.0.1", server)).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: nexel.cc\r\n\r\n").await.unwrap();
+        assert_eq!(expect_status(&mut stream, &["404"]).await.ok(), Some(()));
+
+        // a plain CONNECT isn't served on a WebSocket port
+        let mut stream = TcpStream::connect(("127.0.0.1", server)).await.unwrap();
+        let ret = http_connect(&mut stream, "127.0.0.1", server, None).await;
+        assert_eq!(ret.unwrap_err().to_string(), "unexpected response status 404");
+    }
+}